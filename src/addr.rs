@@ -0,0 +1,86 @@
+use core::ops::Add;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use mork_hal::config::HAL_PAGE_LEVEL;
+use crate::kaslr::kernel_offset;
+
+// the lower half of the canonical address space belongs to user
+pub const USER_SPACE_END: usize = 1 << (12 + 9 * HAL_PAGE_LEVEL - 1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PhysAddr(usize);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct VirtAddr(usize);
+
+// end of the kernel window, 0 before init
+static WINDOW_END: AtomicUsize = AtomicUsize::new(0);
+
+pub fn init(memory_end: usize) {
+    WINDOW_END.store(memory_end, Ordering::Release);
+}
+
+// ram hotplugged above the boot ram, the window never shrinks again
+pub(crate) fn extend_window(memory_end: usize) {
+    WINDOW_END.fetch_max(memory_end, Ordering::AcqRel);
+}
+
+impl PhysAddr {
+    pub const fn new(paddr: usize) -> Self {
+        Self(paddr)
+    }
+
+    pub const fn from_ppn(ppn: usize) -> Self {
+        Self(ppn << 12)
+    }
+
+    pub const fn as_usize(self) -> usize {
+        self.0
+    }
+
+    pub const fn ppn(self) -> usize {
+        self.0 >> 12
+    }
+}
+
+impl VirtAddr {
+    pub const fn new(vaddr: usize) -> Self {
+        Self(vaddr)
+    }
+
+    pub const fn as_usize(self) -> usize {
+        self.0
+    }
+}
+
+impl Add<usize> for PhysAddr {
+    type Output = Self;
+
+    fn add(self, rhs: usize) -> Self {
+        Self(self.0 + rhs)
+    }
+}
+
+impl Add<usize> for VirtAddr {
+    type Output = Self;
+
+    fn add(self, rhs: usize) -> Self {
+        Self(self.0 + rhs)
+    }
+}
+
+pub fn phys_to_virt(paddr: PhysAddr) -> VirtAddr {
+    VirtAddr(paddr.0 + kernel_offset())
+}
+
+// only addresses inside the kernel window have a physical counterpart
+pub fn virt_to_phys(vaddr: VirtAddr) -> Option<PhysAddr> {
+    let (offset, end) = (kernel_offset(), WINDOW_END.load(Ordering::Acquire));
+    if vaddr.0 < offset || (end != 0 && vaddr.0 >= end) {
+        return None;
+    }
+    Some(PhysAddr(vaddr.0 - offset))
+}
+
+pub fn is_user_range(vaddr: usize, len: usize) -> bool {
+    vaddr.checked_add(len).is_some_and(|end| end <= USER_SPACE_END)
+}
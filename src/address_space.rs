@@ -0,0 +1,643 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use mork_common::mork_kernel_log;
+use mork_common::types::ResultWithErr;
+use mork_common::utils::alignas::is_aligned;
+use mork_hal::config::HAL_PAGE_LEVEL;
+use mork_hal::mm::PageTableImpl;
+use crate::addr::{virt_to_phys, PhysAddr, VirtAddr, USER_SPACE_END};
+use crate::attributes::MapAttributes;
+use crate::backend::MappingBackend;
+use crate::error::MmError;
+use crate::eviction::PageLru;
+use crate::fault::{self, AccessType};
+use crate::memory::{self, PhysSegment};
+use crate::shootdown::{shootdown, FlushRequest};
+use crate::stats::{MemoryLimits, MemoryStats};
+use crate::frame::FRAME_SIZE;
+#[cfg(feature = "ksm")]
+use crate::ksm;
+use crate::{asid, coloring, frame, frame_info, hart, kernel_template, policy, swap, tlb};
+use crate::page_table::{check_user_range, MutPageTableWrapper, PageTable, PageTableWrapper};
+use crate::vm_area::{VmArea, VmAreaSet, VmBacking, VmKind};
+
+#[derive(Clone, Copy)]
+struct ProgramBreak {
+    start: usize,
+    end: usize,
+    attrs: MapAttributes,
+}
+
+pub struct AddressSpace {
+    root: &'static mut PageTable,
+    owned: bool,
+    asid: usize,
+    active_harts: AtomicUsize,
+    areas: VmAreaSet,
+    stats: MemoryStats,
+    brk: Option<ProgramBreak>,
+    lru: PageLru,
+    // page -> pin count, pinned pages stay resident on the same frame
+    pinned: BTreeMap<usize, usize>,
+    // cache colors the frames of this space come from, 0 for any
+    color_mask: u64,
+    // the page merge_pages continues at
+    #[cfg(feature = "ksm")]
+    merge_cursor: usize,
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<Self, MmError> {
+        let Some(root) = PageTable::new_user() else {
+            mork_kernel_log!(warn, "fail to allocate root page table");
+            return Err(MmError::OutOfMemory);
+        };
+        Ok(Self::with_root(root, true))
+    }
+
+    pub fn from_root(root: &'static mut PageTable) -> Self {
+        kernel_template::register_user_root(root);
+        Self::with_root(root, false)
+    }
+
+    fn with_root(root: &'static mut PageTable, owned: bool) -> Self {
+        Self {
+            root,
+            owned,
+            asid: 0,
+            active_harts: AtomicUsize::new(0),
+            areas: VmAreaSet::new(),
+            // the root table
+            stats: MemoryStats { page_table_pages: 1, ..MemoryStats::default() },
+            brk: None,
+            lru: PageLru::new(),
+            pinned: BTreeMap::new(),
+            color_mask: 0,
+            #[cfg(feature = "ksm")]
+            merge_cursor: 0,
+        }
+    }
+
+    pub fn root(&mut self) -> &mut PageTable {
+        self.root
+    }
+
+    pub fn asid(&self) -> usize {
+        asid::hw_asid(self.asid)
+    }
+
+    pub fn mapped_bytes(&self) -> usize {
+        self.stats.virtual_bytes
+    }
+
+    pub fn stats(&self) -> MemoryStats {
+        self.stats
+    }
+
+    pub fn set_limits(&mut self, limits: MemoryLimits) {
+        self.stats.limits = limits;
+    }
+
+    pub fn areas(&self) -> &VmAreaSet {
+        &self.areas
+    }
+
+    pub fn find_region(&self, vaddr: usize) -> Option<&VmArea> {
+        self.areas.find_region(vaddr)
+    }
+
+    // the null page is never handed out
+    pub fn find_free_region(&self, len: usize, align: usize, hint: usize) -> Result<usize, MmError> {
+        if len == 0 || !is_aligned(len, FRAME_SIZE) || !align.is_power_of_two() {
+            mork_kernel_log!(warn, "invalid len/align, {:#x}, {:#x}", len, align);
+            return Err(MmError::InvalidParam);
+        }
+        let align = align.max(FRAME_SIZE);
+        self.areas.find_free(len, align, hint, FRAME_SIZE, USER_SPACE_END).ok_or_else(|| {
+            mork_kernel_log!(warn, "no free region for len {:#x}", len);
+            MmError::OutOfMemory
+        })
+    }
+
+    // bit n allows frames of cache color n, see coloring::set_colors. keeps real-time tasks out of
+    // each other's cache sets, frames already mapped keep their color
+    pub fn set_color_mask(&mut self, mask: u64) {
+        self.color_mask = mask;
+    }
+
+    pub fn color_mask(&self) -> u64 {
+        self.color_mask
+    }
+
+    pub fn wrapper(&mut self) -> MutPageTableWrapper<'_> {
+        let asid = self.asid();
+        let harts = self.active_harts.load(Ordering::Acquire);
+        MutPageTableWrapper::new(self.root).with_asid(asid).with_harts(harts).with_stats(&mut self.stats)
+    }
+
+    pub fn activate(&mut self) {
+        let hw_asid = asid::refresh(&mut self.asid);
+        self.active_harts.fetch_or(1 << hart::hart_id(), Ordering::AcqRel);
+        self.root.page_table_impl.active();
+        asid::set_hw_asid(hw_asid);
+        // asid 0 is shared, whatever ran with it before may still sit in the tlb
+        if hw_asid == 0 {
+            tlb::flush_asid(0);
+        }
+    }
+
+    // must be called on the current hart before switching to another address space
+    pub fn deactivate(&self) {
+        self.active_harts.fetch_and(!(1 << hart::hart_id()), Ordering::AcqRel);
+    }
+
+    // whether the current hart runs on this address space
+    pub fn is_active(&self) -> bool {
+        self.active_harts.load(Ordering::Acquire) & (1 << hart::hart_id()) != 0
+    }
+
+    pub fn map(&mut self, vaddr: usize, paddr: usize, len: usize, attrs: MapAttributes)
+        -> ResultWithErr<MmError> {
+        self.areas.check_free(vaddr, len)?;
+        self.wrapper().map_range(vaddr, paddr, len, attrs)?;
+        self.areas.insert(VmArea::new(vaddr, len, attrs, VmBacking::Fixed { paddr }))?;
+        self.stats.virtual_bytes += len;
+        Ok(())
+    }
+
+    // only records the area, frames are allocated by the fault path on first touch
+    pub fn map_anonymous(&mut self, vaddr: usize, len: usize, attrs: MapAttributes)
+        -> ResultWithErr<MmError> {
+        policy::check_user_attrs(attrs)?;
+        check_user_range(vaddr, len)?;
+        if len == 0 || !is_aligned(vaddr, 4096) || !is_aligned(len, 4096) {
+            mork_kernel_log!(warn, "vaddr/len must be aligned, {:#x}, {:#x}", vaddr, len);
+            return Err(MmError::AlignmentError);
+        }
+        self.areas.insert(VmArea::new(vaddr, len, attrs, VmBacking::Anonymous))?;
+        self.stats.virtual_bytes += len;
+        Ok(())
+    }
+
+    // pages are read from the backend on first touch, starting at offset
+    pub fn map_file(&mut self, vaddr: usize, len: usize, backend: Arc<dyn MappingBackend>, offset: usize,
+                    attrs: MapAttributes) -> ResultWithErr<MmError> {
+        policy::check_user_attrs(attrs)?;
+        check_user_range(vaddr, len)?;
+        if len == 0 || !is_aligned(vaddr, FRAME_SIZE) || !is_aligned(len, FRAME_SIZE)
+            || !is_aligned(offset, FRAME_SIZE) {
+            mork_kernel_log!(warn, "vaddr/len/offset must be aligned, {:#x}, {:#x}, {:#x}", vaddr, len, offset);
+            return Err(MmError::AlignmentError);
+        }
+        self.areas.insert(VmArea::new(vaddr, len, attrs, VmBacking::File { backend, offset }))?;
+        self.stats.virtual_bytes += len;
+        Ok(())
+    }
+
+    // [vaddr, vaddr + len) is the initial stack, faults up to gap below it grow it down to limit
+    pub fn map_stack(&mut self, vaddr: usize, len: usize, limit: usize, gap: usize, attrs: MapAttributes)
+        -> ResultWithErr<MmError> {
+        policy::check_user_attrs(attrs)?;
+        check_user_range(vaddr, len)?;
+        if len == 0 || !is_aligned(vaddr, FRAME_SIZE) || !is_aligned(len, FRAME_SIZE)
+            || !is_aligned(limit, FRAME_SIZE) {
+            mork_kernel_log!(warn, "vaddr/len/limit must be aligned, {:#x}, {:#x}, {:#x}", vaddr, len, limit);
+            return Err(MmError::AlignmentError);
+        }
+        if limit < FRAME_SIZE || limit > vaddr {
+            mork_kernel_log!(warn, "stack limit {:#x} leaves no room for the guard page", limit);
+            return Err(MmError::InvalidParam);
+        }
+        let kind = VmKind::StackGrowsDown { limit, gap };
+        self.areas.insert(VmArea::new(vaddr, len, attrs, VmBacking::Anonymous).with_kind(kind))?;
+        self.stats.virtual_bytes += len;
+        Ok(())
+    }
+
+    // extends the stack area below vaddr so that it covers the page of vaddr
+    pub fn grow_stack(&mut self, vaddr: usize) -> Option<VmArea> {
+        let area = self.areas.find_growable(vaddr)?.clone();
+        let new_start = vaddr & !(FRAME_SIZE - 1);
+        self.areas.grow_down(area.start, new_start).ok()?;
+        self.stats.virtual_bytes += area.start - new_start;
+        self.areas.find_region(vaddr).cloned()
+    }
+
+    pub fn map_frame(&mut self, vaddr: usize, paddr: usize, frame_level: usize, attrs: MapAttributes)
+        -> ResultWithErr<MmError> {
+        let size = PageTableImpl::get_align(frame_level).ok_or(MmError::InvalidLevel)?;
+        self.areas.check_free(vaddr, size)?;
+        self.wrapper().map_frame(vaddr, paddr, frame_level, attrs)?;
+        self.areas.insert(VmArea::new(vaddr, size, attrs, VmBacking::Fixed { paddr }))?;
+        self.stats.virtual_bytes += size;
+        Ok(())
+    }
+
+    pub fn reserve_guard(&mut self, vaddr: usize, len: usize) -> ResultWithErr<MmError> {
+        check_user_range(vaddr, len)?;
+        if len == 0 || !is_aligned(vaddr, FRAME_SIZE) || !is_aligned(len, FRAME_SIZE) {
+            mork_kernel_log!(warn, "vaddr/len must be aligned, {:#x}, {:#x}", vaddr, len);
+            return Err(MmError::AlignmentError);
+        }
+        let wrapper = self.wrapper();
+        let backed = (vaddr..vaddr + len).step_by(FRAME_SIZE).find_map(|page| wrapper.translate(page));
+        if let Some((paddr, _, _)) = backed {
+            mork_kernel_log!(warn, "guard region {:#x} is backed by {:#x}", vaddr, paddr);
+            return Err(MmError::AlreadyMapped);
+        }
+        let area = VmArea::new(vaddr, len, MapAttributes::empty(), VmBacking::Anonymous)
+            .with_kind(VmKind::Guard);
+        self.areas.insert(area)
+    }
+
+    pub fn init_brk(&mut self, start: usize, attrs: MapAttributes) -> ResultWithErr<MmError> {
+        policy::check_user_attrs(attrs)?;
+        check_user_range(start, 0)?;
+        if self.brk.is_some() {
+            mork_kernel_log!(warn, "program break has been initialized");
+            return Err(MmError::AlreadyMapped);
+        }
+        if !is_aligned(start, FRAME_SIZE) {
+            mork_kernel_log!(warn, "program break must start aligned, {:#x}", start);
+            return Err(MmError::AlignmentError);
+        }
+        self.brk = Some(ProgramBreak { start, end: start, attrs });
+        Ok(())
+    }
+
+    pub fn brk(&self) -> Option<usize> {
+        self.brk.map(|brk| brk.end)
+    }
+
+    // moves the break to new_end, mapping or unmapping whole pages as needed
+    pub fn set_brk(&mut self, new_end: usize) -> Result<usize, MmError> {
+        let Some(brk) = self.brk else {
+            mork_kernel_log!(warn, "program break has not been initialized");
+            return Err(MmError::NotMapped);
+        };
+        if new_end < brk.start {
+            mork_kernel_log!(warn, "program break {:#x} below its start {:#x}", new_end, brk.start);
+            return Err(MmError::InvalidParam);
+        }
+        check_user_range(brk.start, new_end - brk.start)?;
+        let old_top = page_round_up(brk.end);
+        let new_top = page_round_up(new_end);
+        if new_top > old_top {
+            self.grow_brk(brk, old_top, new_top)?;
+        } else if new_top < old_top {
+            self.shrink_brk(old_top, new_top)?;
+        }
+        self.brk = Some(ProgramBreak { end: new_end, ..brk });
+        Ok(new_end)
+    }
+
+    fn grow_brk(&mut self, brk: ProgramBreak, old_top: usize, new_top: usize) -> ResultWithErr<MmError> {
+        self.areas.check_free(old_top, new_top - old_top)?;
+        let color_mask = self.color_mask;
+        let mut wrapper = self.wrapper();
+        wrapper.begin_batch();
+        let mut current = old_top;
+        let mut result = Ok(());
+        while current < new_top {
+            let Some(paddr) = coloring::alloc_user_frame(color_mask, current) else {
+                mork_kernel_log!(warn, "fail to allocate frame for program break at {:#x}", current);
+                result = Err(MmError::OutOfMemory);
+                break;
+            };
+            if let Err(err) = wrapper.map_frame_populate(current, paddr, HAL_PAGE_LEVEL, brk.attrs) {
+                unsafe { frame::dealloc_frame(paddr); }
+                result = Err(err);
+                break;
+            }
+            current += FRAME_SIZE;
+        }
+        // roll back the pages mapped before the failure
+        if result.is_err() && current > old_top {
+            for paddr in wrapper.unmap_range(old_top, current - old_top).unwrap_or_default() {
+                unsafe { frame::dealloc_frame(paddr); }
+            }
+        }
+        wrapper.finish_batch();
+        result?;
+
+        if old_top == brk.start {
+            let area = VmArea::new(brk.start, new_top - brk.start, brk.attrs, VmBacking::Anonymous)
+                .with_kind(VmKind::Heap);
+            self.areas.insert(area)?;
+        } else {
+            self.areas.resize(brk.start, new_top - brk.start)?;
+        }
+        self.stats.virtual_bytes += new_top - old_top;
+        Ok(())
+    }
+
+    fn shrink_brk(&mut self, old_top: usize, new_top: usize) -> ResultWithErr<MmError> {
+        for paddr in self.unmap(new_top, old_top - new_top)? {
+            unsafe { frame::dealloc_frame(paddr); }
+        }
+        Ok(())
+    }
+
+    pub fn unmap(&mut self, vaddr: usize, len: usize) -> Result<Vec<usize>, MmError> {
+        if self.pinned.range(vaddr..vaddr.saturating_add(len)).next().is_some() {
+            mork_kernel_log!(warn, "range {:#x}, len: {:#x} has pinned pages", vaddr, len);
+            return Err(MmError::InvalidParam);
+        }
+        self.write_back(vaddr, len)?;
+        let frames = self.wrapper().unmap_range(vaddr, len)?;
+        self.lru.remove_range(vaddr, len);
+        #[cfg(feature = "ksm")]
+        ksm::forget_range(self.root.get_ptr(), vaddr, len);
+        for area in self.areas.remove_range(vaddr, len) {
+            if area.kind != VmKind::Guard {
+                self.stats.virtual_bytes -= area.len;
+            }
+        }
+        Ok(frames)
+    }
+
+    pub(crate) fn track_page(&mut self, vaddr: usize) {
+        if !self.pinned.contains_key(&vaddr) {
+            self.lru.insert(vaddr);
+        }
+    }
+
+    // faults in every page of the range and keeps it on its frame until unpinned,
+    // cow is broken up front so a later write can't move the page
+    pub fn pin_pages(&mut self, vaddr: usize, len: usize) -> Result<Vec<PhysSegment>, MmError> {
+        check_user_range(vaddr, len)?;
+        let start = vaddr & !(FRAME_SIZE - 1);
+        let end = page_round_up(vaddr + len);
+        for page in (start..end).step_by(FRAME_SIZE) {
+            if let Err(err) = self.pin_page(page) {
+                self.unpin_pages(start, page - start);
+                return Err(err);
+            }
+        }
+        self.lookup_segments(vaddr, len, AccessType::Read)
+    }
+
+    pub fn unpin_pages(&mut self, vaddr: usize, len: usize) {
+        let start = vaddr & !(FRAME_SIZE - 1);
+        for page in (start..page_round_up(vaddr + len)).step_by(FRAME_SIZE) {
+            let frame = self.frame_of(page);
+            let Some(count) = self.pinned.get_mut(&page) else {
+                mork_kernel_log!(warn, "unpin of page {:#x} that is not pinned", page);
+                continue;
+            };
+            *count -= 1;
+            if let Some(paddr) = frame {
+                frame_info::unpin(paddr);
+            }
+            if *count == 0 {
+                self.pinned.remove(&page);
+                let anonymous = self.find_region(page)
+                    .is_some_and(|area| matches!(area.backing, VmBacking::Anonymous));
+                if anonymous {
+                    self.lru.insert(page);
+                }
+            }
+        }
+    }
+
+    fn pin_page(&mut self, page: usize) -> ResultWithErr<MmError> {
+        let writable = self.find_region(page).ok_or(MmError::NotMapped)?.attrs.is_w();
+        let mut wrapper = self.wrapper();
+        match wrapper.translate(page) {
+            Some((_, _, attrs)) if attrs.is_cow() && writable => wrapper.resolve_cow_fault(page)?,
+            Some(_) => {}
+            None => {
+                drop(wrapper);
+                let access = if writable { AccessType::Write } else { AccessType::Read };
+                fault::handle_page_fault(self, page, access).into_result()?;
+            }
+        }
+        *self.pinned.entry(page).or_insert(0) += 1;
+        // migration, compaction, promotion and merging look at the frame, not at the address space
+        if let Some(paddr) = self.frame_of(page) {
+            frame_info::pin(paddr);
+        }
+        self.lru.remove_range(page, FRAME_SIZE);
+        Ok(())
+    }
+
+    fn frame_of(&self, page: usize) -> Option<PhysAddr> {
+        let (paddr, _, _) = PageTableWrapper::new(self.root).translate(page)?;
+        virt_to_phys(VirtAddr::new(paddr))
+    }
+
+    // swaps out up to n cold private pages, returns how many frames were freed
+    pub fn reclaim_pages(&mut self, n: usize) -> usize {
+        if !swap::is_enabled() {
+            mork_kernel_log!(warn, "no swap backend, anonymous pages can not be reclaimed");
+            return 0;
+        }
+        let asid = self.asid();
+        let harts = self.active_harts.load(Ordering::Acquire);
+        let mut wrapper = MutPageTableWrapper::new(self.root).with_asid(asid).with_harts(harts)
+            .with_stats(&mut self.stats);
+
+        let mut victims = Vec::new();
+        let mut budget = self.lru.len();
+        wrapper.begin_batch();
+        while victims.len() < n && budget > 0 {
+            budget -= 1;
+            let Some(vaddr) = self.lru.next_victim(&mut wrapper) else {
+                break;
+            };
+            let Some((paddr, level, attrs)) = wrapper.translate(vaddr) else {
+                continue;
+            };
+            // cow pages are still shared with another address space
+            if attrs.is_cow() || level != HAL_PAGE_LEVEL - 1 || wrapper.unmap_frame(vaddr).is_err() {
+                self.lru.insert(vaddr);
+                continue;
+            }
+            victims.push((vaddr, paddr, attrs));
+        }
+        // nobody may write to the victims while they are copied out
+        wrapper.finish_batch();
+
+        let mut reclaimed = 0;
+        for (vaddr, paddr, attrs) in victims {
+            let result = swap::swap_out(paddr).and_then(|slot| {
+                wrapper.set_swap_entry(vaddr, slot).inspect_err(|_| swap::free_slot(slot))
+            });
+            match result {
+                Ok(()) => {
+                    unsafe { frame::dealloc_frame(paddr); }
+                    reclaimed += 1;
+                }
+                Err(err) => {
+                    mork_kernel_log!(warn, "fail to swap out {:#x}, {}", vaddr, err);
+                    if wrapper.map_frame_populate(vaddr, paddr, HAL_PAGE_LEVEL, attrs).is_ok() {
+                        self.lru.insert(vaddr);
+                    }
+                }
+            }
+        }
+        reclaimed
+    }
+
+    // for a housekeeping task, collapses anonymous extents whose pages happen to sit on contiguous
+    // frames into huge leaves, returns how many it collapsed
+    pub fn promote_huge_pages(&mut self) -> usize {
+        let size = PageTableImpl::get_size(HAL_PAGE_LEVEL - 2).unwrap();
+        let extents: Vec<(usize, usize)> = self.areas.iter()
+            .filter(|area| matches!(area.backing, VmBacking::Anonymous) && area.kind != VmKind::Guard)
+            .map(|area| (area.start.next_multiple_of(size), area.end() & !(size - 1)))
+            .filter(|(start, end)| start < end)
+            .collect();
+        let mut wrapper = self.wrapper();
+        let mut promoted = 0;
+        for (start, end) in extents {
+            for vaddr in (start..end).step_by(size) {
+                if wrapper.promote(vaddr).unwrap_or(false) {
+                    promoted += 1;
+                }
+            }
+        }
+        promoted
+    }
+
+    // for a background scanner, merges up to budget of the private anonymous pages with identical
+    // pages of any address space, returns how many frames it freed
+    #[cfg(feature = "ksm")]
+    pub fn merge_pages(&mut self, budget: usize) -> usize {
+        let root = self.root.get_ptr();
+        let mut pages: Vec<usize> = self.lru.pages().collect();
+        pages.sort_unstable();
+        let start = pages.partition_point(|page| *page < self.merge_cursor);
+        let mut cursor = self.merge_cursor;
+        let mut merged = 0;
+        let mut wrapper = self.wrapper();
+        for page in pages[start..].iter().chain(&pages[..start]).take(budget) {
+            if ksm::merge_page(&mut wrapper, root, *page) {
+                merged += 1;
+            }
+            cursor = page + FRAME_SIZE;
+        }
+        self.merge_cursor = cursor;
+        merged
+    }
+
+    // physical segments behind a user buffer, every page must already be mapped with the access
+    // allowed, so cow and not yet faulted pages have to be touched first
+    pub fn lookup_segments(&self, vaddr: usize, len: usize, access: AccessType)
+        -> Result<Vec<PhysSegment>, MmError> {
+        check_user_range(vaddr, len)?;
+        let end = vaddr + len;
+        let wrapper = PageTableWrapper::new(self.root);
+        let mut segments: Vec<PhysSegment> = Vec::new();
+        let mut current = vaddr;
+        while current < end {
+            let Some((paddr, _, attrs)) = wrapper.translate(current) else {
+                mork_kernel_log!(warn, "user buffer is not mapped at {:#x}", current);
+                return Err(MmError::NotMapped);
+            };
+            let allowed = match access {
+                AccessType::Read => attrs.is_r(),
+                AccessType::Write => attrs.is_w(),
+                AccessType::Execute => attrs.is_x(),
+            };
+            if !allowed || !attrs.is_user() {
+                mork_kernel_log!(warn, "{:?} access to user buffer at {:#x} is not allowed", access, current);
+                return Err(MmError::PermissionDenied);
+            }
+            let paddr = virt_to_phys(VirtAddr::new(paddr)).ok_or(MmError::InvalidAddress)?;
+            let chunk = (FRAME_SIZE - (current & (FRAME_SIZE - 1))).min(end - current);
+            memory::push_segment(&mut segments, paddr, chunk);
+            current += chunk;
+        }
+        Ok(segments)
+    }
+
+    // flushes dirty pages of file backed areas inside [vaddr, vaddr + len) to their backend
+    pub fn write_back(&mut self, vaddr: usize, len: usize) -> ResultWithErr<MmError> {
+        let end = vaddr + len;
+        let files: Vec<VmArea> = self.areas.iter()
+            .filter(|area| area.overlaps(vaddr, len) && matches!(area.backing, VmBacking::File { .. }))
+            .cloned()
+            .collect();
+        let wrapper = self.wrapper();
+        for area in files {
+            let VmBacking::File { backend, offset } = &area.backing else {
+                continue;
+            };
+            for page in (area.start.max(vaddr)..area.end().min(end)).step_by(FRAME_SIZE) {
+                let Some((paddr, _, attrs)) = wrapper.translate(page) else {
+                    continue;
+                };
+                if attrs.contains(MapAttributes::DIRTY) && !frame::is_zero_page(paddr) {
+                    backend.write_back(paddr, offset + (page - area.start))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn clone(&mut self, cow: bool) -> Result<Self, MmError> {
+        let Some(root) = PageTable::new_user() else {
+            mork_kernel_log!(warn, "fail to allocate root page table for clone");
+            return Err(MmError::OutOfMemory);
+        };
+
+        let mut stats = MemoryStats { page_table_pages: 1, limits: self.stats.limits, ..MemoryStats::default() };
+        let mut wrapper = MutPageTableWrapper::new(root).with_stats(&mut stats);
+        let result = if cow {
+            let result = wrapper.clone_cow(self.root);
+            // the source lost its write permissions, other harts running it must see that
+            shootdown(self.active_harts.load(Ordering::Acquire), FlushRequest::Asid(self.asid()));
+            result
+        } else {
+            wrapper.clone_copy(self.root)
+        };
+        let result = result.and_then(|()| if cow { self.keep_pinned_frames(&mut wrapper) } else { Ok(()) });
+        if let Err(err) = result {
+            // frames shared with the source only lose the child's reference, copies go right away
+            wrapper.teardown(Some(&mut |paddr, size| unsafe { frame::dealloc_frames(paddr, size) }));
+            kernel_template::unregister_user_root(root);
+            root.free();
+            return Err(err);
+        }
+        let mut address_space = Self::with_root(root, true);
+        address_space.areas = self.areas.clone();
+        address_space.stats = MemoryStats { virtual_bytes: self.stats.virtual_bytes, ..stats };
+        address_space.brk = self.brk;
+        address_space.lru = self.lru.clone();
+        address_space.color_mask = self.color_mask;
+        Ok(address_space)
+    }
+
+    // pinned pages keep their frame, the child of a cow clone gets the copy instead
+    fn keep_pinned_frames(&mut self, child: &mut MutPageTableWrapper) -> ResultWithErr<MmError> {
+        let pinned: Vec<usize> = self.pinned.keys().copied().collect();
+        for page in pinned {
+            let Some((_, _, attrs)) = PageTableWrapper::new(self.root).translate(page) else {
+                continue;
+            };
+            // the child copies first, so the parent is the only holder left and keeps the frame in place
+            if attrs.is_cow() {
+                child.resolve_cow_fault(page)?;
+                self.wrapper().resolve_cow_fault(page)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn destroy(mut self, release_frame: Option<&mut dyn FnMut(usize, usize)>) {
+        self.wrapper().teardown(release_frame);
+        kernel_template::unregister_user_root(self.root);
+        #[cfg(feature = "ksm")]
+        ksm::forget_space(self.root.get_ptr());
+        if self.owned {
+            self.root.free();
+        }
+    }
+}
+
+fn page_round_up(vaddr: usize) -> usize {
+    (vaddr + FRAME_SIZE - 1) & !(FRAME_SIZE - 1)
+}
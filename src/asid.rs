@@ -0,0 +1,196 @@
+#[cfg(target_arch = "riscv64")]
+use core::arch::asm;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::mutex::{Mutex, MutexGuard};
+use crate::hart;
+use crate::shootdown::{self, shootdown, FlushRequest};
+
+// riscv allows up to 16 asid bits, a hart may implement fewer, see init
+const ASID_LIMIT_BITS: usize = 16;
+const ASID_MASK: usize = (1 << ASID_LIMIT_BITS) - 1;
+const GENERATION_STEP: usize = 1 << ASID_LIMIT_BITS;
+const MAX_HARTS: usize = usize::BITS as usize;
+#[cfg(target_arch = "riscv64")]
+const SATP_ASID_SHIFT: usize = 44;
+
+static ASID_BITS: AtomicUsize = AtomicUsize::new(ASID_LIMIT_BITS);
+
+struct AsidAllocator {
+    generation: usize,
+    // asids handed out in the current generation
+    used: [u64; (ASID_MASK + 1) / 64],
+    next: usize,
+    // context each hart runs with
+    active: [usize; MAX_HARTS],
+    // contexts that were running at the last rollover, they keep their asid in the new generation
+    reserved: [usize; MAX_HARTS],
+}
+
+static ASID_ALLOCATOR: Mutex<AsidAllocator> = Mutex::new(AsidAllocator::new());
+
+impl AsidAllocator {
+    const fn new() -> Self {
+        Self {
+            generation: GENERATION_STEP,
+            // asid 0 is kept for the kernel page table
+            used: {
+                let mut used = [0; (ASID_MASK + 1) / 64];
+                used[0] = 1;
+                used
+            },
+            next: 1,
+            active: [0; MAX_HARTS],
+            reserved: [0; MAX_HARTS],
+        }
+    }
+
+    fn alloc(&mut self) -> usize {
+        if max_asid() == 0 {
+            return self.generation;
+        }
+        let asid = match self.find_free() {
+            Some(asid) => asid,
+            None => {
+                self.rollover();
+                // all asids reserved by running address spaces, the caller flushes on every switch
+                self.find_free().unwrap_or(0)
+            }
+        };
+        if asid != 0 {
+            self.used[asid / 64] |= 1 << (asid % 64);
+            self.next = asid + 1;
+        }
+        self.generation | asid
+    }
+
+    fn find_free(&self) -> Option<usize> {
+        (self.next..=max_asid()).find(|asid| self.used[asid / 64] & (1 << (asid % 64)) == 0)
+    }
+
+    // the asids of the old generation may still sit in any tlb, so every hart flushes
+    fn rollover(&mut self) {
+        self.generation += GENERATION_STEP;
+        self.used.fill(0);
+        self.used[0] = 1;
+        self.next = 1;
+        self.reserved = self.active;
+        // only harts that ever ran a user context can hold old asids
+        let mut harts = 0;
+        for (hart, context) in self.active.iter().enumerate().filter(|(_, context)| **context != 0) {
+            let asid = hw_asid(*context);
+            self.used[asid / 64] |= 1 << (asid % 64);
+            harts |= 1 << hart;
+        }
+        shootdown(harts, FlushRequest::All);
+    }
+}
+
+// probes the asid bits the hart implements by writing all ones and reading back what stuck,
+// must run on the kernel page table
+pub(crate) fn init() {
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        let satp: usize;
+        let probed: usize;
+        asm!("csrr {}, satp", out(reg) satp);
+        asm!("csrw satp, {}", in(reg) satp | (ASID_MASK << SATP_ASID_SHIFT));
+        asm!("csrr {}, satp", out(reg) probed);
+        asm!("csrw satp, {}", in(reg) satp);
+        asm!("sfence.vma");
+        let bits = ((probed >> SATP_ASID_SHIFT) & ASID_MASK).count_ones() as usize;
+        ASID_BITS.store(bits, Ordering::Release);
+    }
+}
+
+// context holds the generation in the upper bits and the hardware asid in the lower bits,
+// 0 means no asid has been assigned yet. a hardware asid of 0 is shared and must be flushed on every switch
+pub fn refresh(context: &mut usize) -> usize {
+    let mut allocator = lock();
+    if *context & !ASID_MASK != allocator.generation {
+        *context = if *context != 0 && allocator.reserved.contains(context) {
+            allocator.generation | hw_asid(*context)
+        } else {
+            allocator.alloc()
+        };
+    }
+    allocator.active[hart::hart_id()] = *context;
+    hw_asid(*context)
+}
+
+// the hal activates a page table without an asid, this tags the current satp with the space's asid afterwards.
+// entries the walker cached under asid 0 in between are dropped by the flush on the next asid 0 switch
+pub(crate) fn set_hw_asid(asid: usize) {
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        let satp: usize;
+        asm!("csrr {}, satp", out(reg) satp);
+        asm!("csrw satp, {}", in(reg) (satp & !(ASID_MASK << SATP_ASID_SHIFT)) | (asid << SATP_ASID_SHIFT));
+    }
+    #[cfg(not(target_arch = "riscv64"))]
+    let _ = asid;
+}
+
+pub fn hw_asid(context: usize) -> usize {
+    context & ASID_MASK
+}
+
+fn max_asid() -> usize {
+    (1 << ASID_BITS.load(Ordering::Acquire)) - 1
+}
+
+// a rollover holds the lock while it waits for the other harts to flush, so waiters serve it
+fn lock() -> MutexGuard<'static, AsidAllocator> {
+    loop {
+        if let Some(allocator) = ASID_ALLOCATOR.try_lock() {
+            return allocator;
+        }
+        shootdown::handle_ipi();
+        spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use super::*;
+
+    // the asid bits are global, every test runs with the same 4
+
+    fn allocator() -> AsidAllocator {
+        ASID_BITS.store(4, Ordering::Release);
+        AsidAllocator::new()
+    }
+
+    #[test]
+    fn kernel_asid_is_never_handed_out() {
+        let mut allocator = allocator();
+        let contexts: Vec<usize> = (0..max_asid()).map(|_| allocator.alloc()).collect();
+        let asids: Vec<usize> = contexts.iter().map(|context| hw_asid(*context)).collect();
+        assert_eq!(asids, (1..=max_asid()).collect::<Vec<_>>());
+        assert!(contexts.iter().all(|context| context & !ASID_MASK == GENERATION_STEP));
+    }
+
+    #[test]
+    fn rollover_starts_a_new_generation() {
+        let mut allocator = allocator();
+        for _ in 0..max_asid() {
+            allocator.alloc();
+        }
+        let context = allocator.alloc();
+        assert_eq!(context & !ASID_MASK, 2 * GENERATION_STEP);
+        assert_eq!(hw_asid(context), 1);
+    }
+
+    #[test]
+    fn running_contexts_keep_their_asid_across_rollover() {
+        let mut allocator = allocator();
+        let contexts: Vec<usize> = (0..max_asid()).map(|_| allocator.alloc()).collect();
+        // the hart running it is the local one, so the rollover flush does not wait for an ipi
+        allocator.active[0] = contexts[0];
+        let context = allocator.alloc();
+        assert!(allocator.reserved.contains(&contexts[0]));
+        assert_eq!(hw_asid(context), 2);
+        assert!((3..=max_asid()).all(|asid| hw_asid(allocator.alloc()) == asid));
+    }
+}
@@ -0,0 +1,169 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use bitflags::bitflags;
+use mork_common::mork_kernel_log;
+use mork_common::types::ResultWithErr;
+use crate::error::MmError;
+
+bitflags! {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct MapAttributes: usize {
+        const READ = 1 << 1;
+        const WRITE = 1 << 2;
+        const EXECUTE = 1 << 3;
+        const USER = 1 << 4;
+        const GLOBAL = 1 << 5;
+        const ACCESSED = 1 << 6;
+        const DIRTY = 1 << 7;
+        // software only, marks a read-only copy-on-write leaf
+        const COW = 1 << 8;
+        // software only, a huge leaf collapsed from 4K leaves whose frames were allocated one by one
+        const PROMOTED = 1 << 9;
+        // Svpbmt memory types, PMA when both are clear
+        const NON_CACHEABLE = 1 << 61;
+        const DEVICE = 1 << 62;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryType {
+    Pma,
+    NonCacheable,
+    Io,
+}
+
+// set by the boot code once every hart reports Svpbmt in its isa string
+static SVPBMT: AtomicBool = AtomicBool::new(false);
+
+// the hal has no way to put a memory type into a leaf, so svpbmt stays unused until it has
+pub fn set_svpbmt_supported(supported: bool) {
+    if supported {
+        mork_kernel_log!(warn, "svpbmt is present but the hal can not set memory types, it stays unused");
+    }
+    SVPBMT.store(false, Ordering::Release);
+}
+
+pub fn svpbmt_supported() -> bool {
+    SVPBMT.load(Ordering::Acquire)
+}
+
+impl MapAttributes {
+    const MEMORY_TYPE: Self = Self::NON_CACHEABLE.union(Self::DEVICE);
+
+    pub fn from_perms(is_x: bool, is_w: bool, is_r: bool) -> Self {
+        let mut attrs = Self::empty();
+        attrs.set(Self::EXECUTE, is_x);
+        attrs.set(Self::WRITE, is_w);
+        attrs.set(Self::READ, is_r);
+        attrs
+    }
+
+    pub fn is_x(&self) -> bool {
+        self.contains(Self::EXECUTE)
+    }
+
+    pub fn is_w(&self) -> bool {
+        self.contains(Self::WRITE)
+    }
+
+    pub fn is_r(&self) -> bool {
+        self.contains(Self::READ)
+    }
+
+    // sv39 allows execute without read, the code can run but not be read back
+    pub fn is_exec_only(&self) -> bool {
+        self.is_x() && !self.is_r() && !self.is_w()
+    }
+
+    // rejects encodings the hardware reserves or that contradict themselves
+    pub fn validate(&self) -> ResultWithErr<MmError> {
+        let reason = if !self.intersects(Self::READ | Self::WRITE | Self::EXECUTE) {
+            // would turn the leaf into a pointer to the next level
+            "no permission"
+        } else if self.is_w() && !self.is_r() {
+            "write without read"
+        } else if self.is_cow() && self.is_w() {
+            "writable cow"
+        } else if self.contains(Self::MEMORY_TYPE) {
+            "reserved memory type"
+        } else if self.contains(Self::PROMOTED) {
+            "promoted is only set by the kernel"
+        } else {
+            return Ok(());
+        };
+        mork_kernel_log!(warn, "invalid mapping attributes, {}: {:?}", reason, self);
+        Err(MmError::InvalidParam)
+    }
+
+    pub fn is_user(&self) -> bool {
+        self.contains(Self::USER)
+    }
+
+    pub fn is_global(&self) -> bool {
+        self.contains(Self::GLOBAL)
+    }
+
+    pub fn is_cow(&self) -> bool {
+        self.contains(Self::COW)
+    }
+
+    pub fn memory_type(&self) -> MemoryType {
+        if self.contains(Self::DEVICE) {
+            MemoryType::Io
+        } else if self.contains(Self::NON_CACHEABLE) {
+            MemoryType::NonCacheable
+        } else {
+            MemoryType::Pma
+        }
+    }
+
+    pub fn with_memory_type(self, memory_type: MemoryType) -> Self {
+        let attrs = self - Self::MEMORY_TYPE;
+        match memory_type {
+            MemoryType::Pma => attrs,
+            MemoryType::NonCacheable => attrs | Self::NON_CACHEABLE,
+            MemoryType::Io => attrs | Self::DEVICE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn perms_round_trip() {
+        for bits in 0..8 {
+            let (is_x, is_w, is_r) = (bits & 4 != 0, bits & 2 != 0, bits & 1 != 0);
+            let attrs = MapAttributes::from_perms(is_x, is_w, is_r);
+            assert_eq!((attrs.is_x(), attrs.is_w(), attrs.is_r()), (is_x, is_w, is_r));
+            assert!(!attrs.intersects(!(MapAttributes::READ | MapAttributes::WRITE | MapAttributes::EXECUTE)));
+        }
+    }
+
+    #[test]
+    fn exec_only_is_execute_alone() {
+        assert!(MapAttributes::from_perms(true, false, false).is_exec_only());
+        assert!(!MapAttributes::from_perms(true, false, true).is_exec_only());
+        assert!(!MapAttributes::empty().is_exec_only());
+    }
+
+    #[test]
+    fn validate_accepts_usual_mappings() {
+        let r = MapAttributes::READ;
+        let device = (r | MapAttributes::WRITE).with_memory_type(MemoryType::Io);
+        for attrs in [r, r | MapAttributes::WRITE, MapAttributes::EXECUTE, r | MapAttributes::EXECUTE,
+                      r | MapAttributes::COW | MapAttributes::USER, device] {
+            assert!(attrs.validate().is_ok(), "{:?}", attrs);
+        }
+    }
+
+    #[test]
+    fn validate_rejects_reserved_encodings() {
+        let rw = MapAttributes::READ | MapAttributes::WRITE;
+        for attrs in [MapAttributes::USER, MapAttributes::WRITE, rw | MapAttributes::COW,
+                      MapAttributes::READ | MapAttributes::NON_CACHEABLE | MapAttributes::DEVICE,
+                      MapAttributes::READ | MapAttributes::PROMOTED] {
+            assert!(attrs.validate().is_err(), "{:?}", attrs);
+        }
+    }
+}
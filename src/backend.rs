@@ -0,0 +1,11 @@
+use mork_common::types::ResultWithErr;
+use crate::error::MmError;
+
+// provides the contents of file backed areas, offsets are in bytes and page aligned
+pub trait MappingBackend: Send + Sync {
+    // returns a frame (kernel window address) filled with the page at offset,
+    // the frame is owned by the mapping afterwards
+    fn read_page(&self, offset: usize) -> Result<usize, MmError>;
+
+    fn write_back(&self, frame: usize, offset: usize) -> ResultWithErr<MmError>;
+}
@@ -0,0 +1,98 @@
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+use mork_hal::config::HAL_PAGE_LEVEL;
+use spin::mutex::Mutex;
+use mork_common::mork_kernel_log;
+use crate::addr::{phys_to_virt, PhysAddr};
+use crate::attributes::MapAttributes;
+use crate::error::MmError;
+use crate::frame::{self, FRAME_SIZE};
+use crate::frame_info::{self, FrameFlags};
+use crate::{coloring, heap};
+use crate::page_table::PTE_COUNT;
+use crate::rmap::{self, Mapping};
+
+// pfns reported as bad ram since boot
+static BLACKLIST: Mutex<BTreeSet<usize>> = Mutex::new(BTreeSet::new());
+
+// keeps the frame from ever being handed out again and unmaps it from every address space,
+// returns the mappings that lost it so the caller can tell the tasks their data is gone.
+// fails if the frame was free but still sits in the heap, e.g. as part of a kernel object
+pub(crate) fn poison(pfn: usize) -> Result<Vec<Mapping>, MmError> {
+    let Some(info) = frame_info::frame_info(pfn) else {
+        mork_kernel_log!(warn, "poisoned frame {:#x} is not ram", pfn);
+        return Err(MmError::InvalidAddress);
+    };
+    if !BLACKLIST.lock().insert(pfn) {
+        return Ok(Vec::new());
+    }
+    info.insert_flags(FrameFlags::POISONED);
+    mork_kernel_log!(warn, "frame {:#x} is poisoned", pfn);
+    let flags = info.flags();
+    if flags.contains(FrameFlags::RESERVED) {
+        return Ok(Vec::new());
+    }
+    if !flags.contains(FrameFlags::ALLOCATED) {
+        let paddr = phys_to_virt(PhysAddr::from_ppn(pfn)).as_usize();
+        // frames queued zeroed or for their color are taken from the heap already, they just never come back
+        if frame::unlink_zeroed(paddr) || coloring::unlink(paddr) {
+            return Ok(Vec::new());
+        }
+        if heap::remove_region(paddr, paddr + FRAME_SIZE) {
+            return Ok(Vec::new());
+        }
+        // allocated meanwhile, the holder frees it into nothing like any other
+        if !info.flags().contains(FrameFlags::ALLOCATED) {
+            mork_kernel_log!(warn, "poisoned frame {:#x} stays in the heap", pfn);
+            return Err(MmError::InUse);
+        }
+    }
+    if !info.flags().contains(FrameFlags::USER) {
+        // kernel frames are leaked once their holder frees them, see frame::dealloc_layout
+        return Ok(Vec::new());
+    }
+    let unmapped = unmap_user(pfn);
+    // nothing maps it anymore, compaction must not move it and ksm must not merge into it
+    info.remove_flags(FrameFlags::USER | FrameFlags::MERGED);
+    Ok(unmapped)
+}
+
+pub(crate) fn poisoned_frames() -> Vec<usize> {
+    BLACKLIST.lock().iter().copied().collect()
+}
+
+// a huge leaf is recorded at its first frame, so the frames a covering leaf could start at are looked at too
+fn unmap_user(pfn: usize) -> Vec<Mapping> {
+    let mut unmapped = Vec::new();
+    let mut firsts: Vec<usize> = (0..HAL_PAGE_LEVEL)
+        .map(|order| pfn & !(PTE_COUNT.pow(order as u32) - 1))
+        .collect();
+    firsts.dedup();
+    for first in firsts {
+        for mapping in rmap::mappings(first) {
+            let mut wrapper = mapping.wrapper();
+            let Some((paddr, level, attrs)) = wrapper.translate(mapping.vaddr) else {
+                continue;
+            };
+            let frames = PTE_COUNT.pow((HAL_PAGE_LEVEL - 1 - level) as u32);
+            let base = mapping.vaddr & !(frames * FRAME_SIZE - 1);
+            let offset = (mapping.vaddr - base) / FRAME_SIZE;
+            let Some(leaf_first) = frame_info::pfn(paddr).map(|start| start - offset) else {
+                continue;
+            };
+            if !(leaf_first..leaf_first + frames).contains(&pfn) {
+                continue;
+            }
+            // a promoted leaf is split and loses only the bad frame, any other leaf goes as a whole
+            let vaddr = if attrs.contains(MapAttributes::PROMOTED) {
+                base + (pfn - leaf_first) * FRAME_SIZE
+            } else {
+                base
+            };
+            if wrapper.unmap_frame(vaddr).is_ok() {
+                unmapped.push(Mapping { root: mapping.root, vaddr });
+            }
+        }
+    }
+    unmapped
+}
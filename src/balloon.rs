@@ -0,0 +1,63 @@
+use core::alloc::Layout;
+use core::ptr::NonNull;
+use alloc::vec::Vec;
+use spin::mutex::Mutex;
+use mork_common::mork_kernel_log;
+use crate::frame::{self, FRAME_SIZE};
+use crate::frame_info::{self, FrameFlags};
+use crate::{heap, watermark};
+
+// kernel window addresses of the frames the host has, their contents are gone so nothing is
+// linked through them
+static BALLOON: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+// takes up to frames free frames out of the allocator and returns their pfns. stops early instead
+// of pushing the system below the low watermark, the host gets less than it asked for
+pub(crate) fn inflate(frames: usize) -> Vec<usize> {
+    let mut balloon = BALLOON.lock();
+    let mut pfns = Vec::new();
+    if balloon.try_reserve(frames).is_err() || pfns.try_reserve(frames).is_err() {
+        mork_kernel_log!(warn, "no memory to track {} ballooned frames", frames);
+        return pfns;
+    }
+    let (low, _) = watermark::watermarks();
+    while pfns.len() < frames && frame::free_frames() > low {
+        // never compacts or wakes the oom handler, reclaiming for the host is not worth it
+        let Some(block) = heap::try_alloc_contiguous(frame_layout()) else {
+            break;
+        };
+        let paddr = block.as_ptr() as usize;
+        frame_info::mark_allocated(paddr, FRAME_SIZE, FrameFlags::BALLOONED);
+        balloon.push(paddr);
+        pfns.push(frame_info::pfn(paddr).unwrap());
+    }
+    drop(balloon);
+    watermark::check();
+    pfns
+}
+
+// gives up to frames ballooned frames back to the allocator and returns their pfns.
+// the host faults them in again on first touch
+pub(crate) fn deflate(frames: usize) -> Vec<usize> {
+    let mut balloon = BALLOON.lock();
+    let mut pfns = Vec::with_capacity(frames.min(balloon.len()));
+    while pfns.len() < frames {
+        let Some(paddr) = balloon.pop() else {
+            break;
+        };
+        pfns.push(frame_info::pfn(paddr).unwrap());
+        frame_info::mark_free(paddr, FRAME_SIZE);
+        heap::dealloc_contiguous(NonNull::new(paddr as *mut u8).unwrap(), frame_layout());
+    }
+    drop(balloon);
+    watermark::check();
+    pfns
+}
+
+pub(crate) fn frames() -> usize {
+    BALLOON.lock().len()
+}
+
+fn frame_layout() -> Layout {
+    Layout::from_size_align(FRAME_SIZE, FRAME_SIZE).unwrap()
+}
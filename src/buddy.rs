@@ -0,0 +1,86 @@
+use core::alloc::Layout;
+use core::ptr::NonNull;
+use buddy_system_allocator::Heap;
+use crate::heap::HeapBackend;
+
+// the buddy_system_allocator heap. its free lists are private, so a region can not be taken out again,
+// blocks do not grow in place and only the byte counters are known
+pub struct BuddyHeap<const ORDER: usize>(Heap<ORDER>);
+
+impl<const ORDER: usize> BuddyHeap<ORDER> {
+    pub const fn empty() -> Self {
+        Self(Heap::empty())
+    }
+}
+
+impl<const ORDER: usize> HeapBackend for BuddyHeap<ORDER> {
+    unsafe fn add_to_heap(&mut self, start: usize, end: usize) {
+        unsafe {
+            self.0.add_to_heap(start, end);
+        }
+    }
+
+    unsafe fn remove_region(&mut self, _start: usize, _end: usize) -> bool {
+        false
+    }
+
+    fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, ()> {
+        self.0.alloc(layout)
+    }
+
+    fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        self.0.dealloc(ptr, layout);
+    }
+
+    fn total_bytes(&self) -> usize {
+        self.0.stats_total_bytes()
+    }
+
+    // bytes handed out, including the rounding up to whole blocks
+    fn allocated_bytes(&self) -> usize {
+        self.0.stats_alloc_actual()
+    }
+
+    // bytes the callers asked for
+    fn requested_bytes(&self) -> usize {
+        self.0.stats_alloc_user()
+    }
+
+    // not known, callers that need a free run try the allocation instead
+    fn largest_free_block(&self) -> usize {
+        0
+    }
+
+    fn count_free_blocks(&self, _counts: &mut [usize]) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use super::*;
+
+    #[test]
+    fn counters_follow_the_allocations() {
+        let mut memory = vec![0u64; 512];
+        let start = memory.as_mut_ptr() as usize;
+        let mut heap = BuddyHeap::<32>::empty();
+        unsafe { heap.add_to_heap(start, start + 4096); }
+        assert_eq!(heap.total_bytes(), 4096);
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let ptr = heap.alloc(layout).unwrap();
+        assert_eq!(heap.requested_bytes(), 24);
+        assert_eq!(heap.allocated_bytes(), 32);
+        heap.dealloc(ptr, layout);
+        assert_eq!((heap.requested_bytes(), heap.allocated_bytes()), (0, 0));
+    }
+
+    #[test]
+    fn exhausted_heap_fails() {
+        let mut memory = vec![0u64; 512];
+        let start = memory.as_mut_ptr() as usize;
+        let mut heap = BuddyHeap::<32>::empty();
+        unsafe { heap.add_to_heap(start, start + 4096); }
+        assert!(heap.alloc(Layout::from_size_align(8192, 8).unwrap()).is_err());
+        assert!(!unsafe { heap.remove_region(start, start + 4096) });
+    }
+}
@@ -0,0 +1,72 @@
+#[cfg(target_arch = "riscv64")]
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// Zicbom block size from the device tree, 0 when the harts have no cbo instructions
+// and dma is coherent with the caches
+static BLOCK_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_block_size(size: usize) {
+    debug_assert!(size == 0 || size.is_power_of_two());
+    BLOCK_SIZE.store(size, Ordering::Release);
+}
+
+pub fn block_size() -> usize {
+    BLOCK_SIZE.load(Ordering::Acquire)
+}
+
+// write dirty lines back to memory
+pub fn clean_range(vaddr: usize, len: usize) {
+    for_each_block(vaddr, len, |block| {
+        #[cfg(target_arch = "riscv64")]
+        unsafe {
+            // cbo.clean
+            asm!(".insn i 0x0f, 2, x0, {}, 1", in(reg) block);
+        }
+        #[cfg(not(target_arch = "riscv64"))]
+        let _ = block;
+    });
+}
+
+// drop lines without writing them back
+pub fn invalidate_range(vaddr: usize, len: usize) {
+    for_each_block(vaddr, len, |block| {
+        #[cfg(target_arch = "riscv64")]
+        unsafe {
+            // cbo.inval
+            asm!(".insn i 0x0f, 2, x0, {}, 0", in(reg) block);
+        }
+        #[cfg(not(target_arch = "riscv64"))]
+        let _ = block;
+    });
+}
+
+// clean and invalidate
+pub fn flush_range(vaddr: usize, len: usize) {
+    for_each_block(vaddr, len, |block| {
+        #[cfg(target_arch = "riscv64")]
+        unsafe {
+            // cbo.flush
+            asm!(".insn i 0x0f, 2, x0, {}, 2", in(reg) block);
+        }
+        #[cfg(not(target_arch = "riscv64"))]
+        let _ = block;
+    });
+}
+
+fn for_each_block(vaddr: usize, len: usize, op: impl Fn(usize)) {
+    let size = block_size();
+    if size == 0 || len == 0 {
+        return;
+    }
+    let mut block = vaddr & !(size - 1);
+    while block < vaddr + len {
+        op(block);
+        block += size;
+    }
+    // order the cache operations before the device is told about the buffer
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        asm!("fence rw, rw");
+    }
+}
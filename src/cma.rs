@@ -0,0 +1,251 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+use mork_common::mork_kernel_log;
+use mork_common::types::ResultWithErr;
+use crate::addr::{phys_to_virt, virt_to_phys, PhysAddr, VirtAddr};
+use crate::error::MmError;
+use crate::frame::{self, FRAME_SIZE};
+use crate::frame_info::{self, FrameFlags};
+use crate::memory::{self, MemoryRegion, ReservedRegion};
+use crate::migrate;
+use crate::sync::IrqMutex;
+
+// a physically contiguous region kept away from the heap, carved into frame runs on demand.
+// until a run is claimed its free frames are lent to user pages, which are migrated out by the claim
+struct Cma {
+    start: PhysAddr,
+    frames: usize,
+    // one bit per frame each, sized at init since the region is declared before the heap exists
+    used: Vec<u64>,
+    lent: Vec<u64>,
+    used_frames: usize,
+    lent_frames: usize,
+}
+
+// lent frames come back from inside the frame allocator, which the global allocator reaches through vfree
+static CMA: IrqMutex<Cma> = IrqMutex::new(Cma {
+    start: PhysAddr::new(0),
+    frames: 0,
+    used: Vec::new(),
+    lent: Vec::new(),
+    used_frames: 0,
+    lent_frames: 0,
+});
+
+// only before init, one region
+pub fn declare(paddr: PhysAddr, len: usize) -> ResultWithErr<MmError> {
+    if !paddr.as_usize().is_multiple_of(FRAME_SIZE) || len < FRAME_SIZE {
+        mork_kernel_log!(warn, "invalid cma region {:#x}, len: {:#x}", paddr.as_usize(), len);
+        return Err(MmError::InvalidParam);
+    }
+    let mut cma = CMA.lock();
+    if cma.frames != 0 {
+        mork_kernel_log!(warn, "cma region already declared at {:#x}", cma.start.as_usize());
+        return Err(MmError::InvalidParam);
+    }
+    let frames = len / FRAME_SIZE;
+    let region = MemoryRegion { start: paddr, end: PhysAddr::new(paddr.as_usize() + frames * FRAME_SIZE) };
+    memory::reserve_early(ReservedRegion { region, name: "cma", no_map: false })?;
+    cma.start = paddr;
+    cma.frames = frames;
+    Ok(())
+}
+
+pub(crate) fn init() {
+    let words = CMA.lock().frames.div_ceil(64);
+    let bitmap = vec![0; words];
+    let mut cma = CMA.lock();
+    cma.used = bitmap.clone();
+    cma.lent = bitmap;
+}
+
+impl Cma {
+    // frame index of a kernel window address inside the region
+    fn index(&self, vaddr: usize) -> Option<usize> {
+        virt_to_phys(VirtAddr::new(vaddr))
+            .and_then(|paddr| paddr.as_usize().checked_sub(self.start.as_usize()))
+            .map(|offset| offset / FRAME_SIZE)
+            .filter(|frame| *frame < self.frames)
+    }
+
+    fn vaddr(&self, frame: usize) -> usize {
+        phys_to_virt(self.start + frame * FRAME_SIZE).as_usize()
+    }
+
+    // first run of count frames from frame on that no claim holds, starting on a physically aligned frame
+    fn find_run(&self, from: usize, count: usize, step: usize) -> Option<usize> {
+        if self.used.is_empty() {
+            return None;
+        }
+        let base = self.start.ppn();
+        let mut first = (base + from).next_multiple_of(step) - base;
+        while first + count <= self.frames {
+            match (first..first + count).rev().find(|frame| is_set(&self.used, *frame)) {
+                Some(used) => first = (used + 1 + base).next_multiple_of(step) - base,
+                None => return Some(first),
+            }
+        }
+        None
+    }
+}
+
+fn is_set(bits: &[u64], frame: usize) -> bool {
+    bits[frame / 64] & (1 << (frame % 64)) != 0
+}
+
+fn set(bits: &mut [u64], frames: Range<usize>, value: bool) {
+    for frame in frames {
+        if value {
+            bits[frame / 64] |= 1 << (frame % 64);
+        } else {
+            bits[frame / 64] &= !(1 << (frame % 64));
+        }
+    }
+}
+
+// a free frame for a user page, which can move out again when a claim needs the frame
+pub(crate) fn lend_frame() -> Option<usize> {
+    let mut cma = CMA.lock();
+    let frame = (0..cma.lent.len() * 64).take(cma.frames)
+        .find(|frame| !is_set(&cma.used, *frame) && !is_set(&cma.lent, *frame))?;
+    set(&mut cma.lent, frame..frame + 1, true);
+    cma.lent_frames += 1;
+    let vaddr = cma.vaddr(frame);
+    drop(cma);
+    if let Some(info) = frame_info::pfn(vaddr).and_then(frame_info::frame_info) {
+        info.remove_flags(FrameFlags::RESERVED);
+    }
+    Some(vaddr)
+}
+
+// for the frame allocator, false if the freed frame is not a lent one and goes to the heap
+pub(crate) fn take_back(vaddr: usize) -> bool {
+    let mut cma = CMA.lock();
+    let Some(frame) = cma.index(vaddr).filter(|frame| is_set(&cma.lent, *frame)) else {
+        return false;
+    };
+    set(&mut cma.lent, frame..frame + 1, false);
+    cma.lent_frames -= 1;
+    drop(cma);
+    // like the rest of the region, compaction and the heap keep away from it
+    if let Some(info) = frame_info::pfn(vaddr).and_then(frame_info::frame_info) {
+        info.insert_flags(FrameFlags::RESERVED);
+    }
+    true
+}
+
+fn is_lent(frame: usize) -> bool {
+    is_set(&CMA.lock().lent, frame)
+}
+
+// the caller serializes migration with map and unmap, like for compaction
+fn migrate_out(pfn: usize) -> ResultWithErr<MmError> {
+    let dest = frame::alloc_frame().ok_or(MmError::OutOfMemory)?;
+    let result = migrate::migrate_frame(pfn, frame_info::pfn(dest).unwrap());
+    if result.is_err() {
+        unsafe { frame::dealloc_frame(dest); }
+    }
+    result
+}
+
+// zeroed, returns the kernel window address like the frame allocator, align is in bytes.
+// user pages borrowing frames of the run are migrated to the heap first, a run with a pinned one is skipped
+pub fn cma_alloc(count: usize, align: usize) -> Option<usize> {
+    if count == 0 || !align.is_power_of_two() {
+        return None;
+    }
+    let step = (align / FRAME_SIZE).max(1);
+    let mut from = 0;
+    loop {
+        let (first, base) = {
+            let mut cma = CMA.lock();
+            let Some(first) = cma.find_run(from, count, step) else {
+                break;
+            };
+            // nothing new is lent out of the run from here on
+            set(&mut cma.used, first..first + count, true);
+            cma.used_frames += count;
+            (first, cma.start.ppn())
+        };
+        // a page freed meanwhile gives its frame back by itself
+        let stuck = (first..first + count)
+            .find(|frame| is_lent(*frame) && migrate_out(base + frame).is_err() && is_lent(*frame));
+        let Some(stuck) = stuck else {
+            let vaddr = CMA.lock().vaddr(first);
+            unsafe {
+                core::ptr::write_bytes(vaddr as *mut u8, 0, count * FRAME_SIZE);
+            }
+            return Some(vaddr);
+        };
+        let mut cma = CMA.lock();
+        set(&mut cma.used, first..first + count, false);
+        cma.used_frames -= count;
+        from = stuck + 1;
+    }
+    mork_kernel_log!(warn, "no {} free cma frames aligned to {:#x}", count, align);
+    None
+}
+
+/// # Safety
+///
+/// `vaddr` must come from `cma_alloc` with the same `count`, and nothing may use the frames afterwards.
+pub unsafe fn cma_free(vaddr: usize, count: usize) {
+    let mut cma = CMA.lock();
+    let Some(first) = cma.index(vaddr).filter(|first| first + count <= cma.frames && !cma.used.is_empty()) else {
+        mork_kernel_log!(warn, "cma free of {:#x} outside the region", vaddr);
+        return;
+    };
+    set(&mut cma.used, first..first + count, false);
+    cma.used_frames -= count;
+}
+
+// (total, used) frames, lent frames count as free
+pub fn usage() -> (usize, usize) {
+    let cma = CMA.lock();
+    (cma.frames, cma.used_frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(start: usize, frames: usize) -> Cma {
+        let words = frames.div_ceil(64);
+        Cma { start: PhysAddr::new(start), frames, used: vec![0; words], lent: vec![0; words], used_frames: 0,
+              lent_frames: 0 }
+    }
+
+    #[test]
+    fn run_skips_claimed_frames() {
+        let mut cma = region(0x8000_0000, 16);
+        set(&mut cma.used, 2..3, true);
+        assert_eq!(cma.find_run(0, 4, 1), Some(3));
+        assert_eq!(cma.find_run(0, 2, 1), Some(0));
+        set(&mut cma.used, 3..16, true);
+        assert_eq!(cma.find_run(0, 3, 1), None);
+    }
+
+    #[test]
+    fn run_starts_physically_aligned() {
+        // the region starts one frame past a 4 frame boundary
+        let cma = region(0x8000_1000, 16);
+        assert_eq!(cma.find_run(0, 4, 4), Some(3));
+        assert_eq!(cma.find_run(4, 4, 4), Some(7));
+        assert_eq!(cma.find_run(0, 16, 4), None);
+    }
+
+    #[test]
+    fn lent_frames_do_not_block_a_run() {
+        let mut cma = region(0x8000_0000, 8);
+        set(&mut cma.lent, 0..8, true);
+        assert_eq!(cma.find_run(0, 8, 1), Some(0));
+    }
+
+    #[test]
+    fn nothing_before_init() {
+        let cma = Cma { start: PhysAddr::new(0x8000_0000), frames: 8, used: Vec::new(), lent: Vec::new(),
+                        used_frames: 0, lent_frames: 0 };
+        assert_eq!(cma.find_run(0, 1, 1), None);
+    }
+}
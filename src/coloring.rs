@@ -0,0 +1,156 @@
+use core::alloc::Layout;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use mork_common::mork_kernel_log;
+use mork_common::types::ResultWithErr;
+use crate::error::MmError;
+use crate::frame::{self, FRAME_SIZE};
+use crate::frame_info;
+use crate::heap;
+use crate::sync::IrqMutex;
+
+pub const MAX_COLORS: usize = 64;
+
+// heap allocations tried for a color before settling for any frame
+const MAX_TRIES: usize = 2 * MAX_COLORS;
+// frames held for other colors at most
+const MAX_POOLED: usize = 1024;
+
+// frames the heap gave out for a color nobody asked for yet, zeroed except for the link in the first word
+struct ColorPools {
+    heads: [usize; MAX_COLORS],
+    len: usize,
+}
+
+static POOLS: IrqMutex<ColorPools> = IrqMutex::new(ColorPools { heads: [0; MAX_COLORS], len: 0 });
+// 1 turns coloring off
+static COLORS: AtomicUsize = AtomicUsize::new(1);
+
+// cache size / (ways * FRAME_SIZE) of the physically indexed cache to color for
+pub fn set_colors(colors: usize) -> ResultWithErr<MmError> {
+    if colors == 0 || colors > MAX_COLORS {
+        mork_kernel_log!(warn, "cache colors must be within 1..={}, {}", MAX_COLORS, colors);
+        return Err(MmError::InvalidParam);
+    }
+    COLORS.store(colors, Ordering::Relaxed);
+    // pooled frames were sorted for the old count
+    drain();
+    Ok(())
+}
+
+pub fn colors() -> usize {
+    COLORS.load(Ordering::Relaxed)
+}
+
+pub fn color_of(paddr: usize) -> usize {
+    frame_info::pfn(paddr).map_or(0, |pfn| pfn % colors())
+}
+
+// a frame for the page at vaddr of an address space limited to the colors in mask, consecutive pages
+// cycle through them. an empty mask or coloring turned off takes any frame
+pub(crate) fn alloc_user_frame(mask: u64, vaddr: usize) -> Option<usize> {
+    let colors = colors();
+    let mask = mask & (u64::MAX >> (u64::BITS as usize - colors));
+    if colors == 1 || mask == 0 {
+        return frame::alloc_user_frame();
+    }
+    let nth = (vaddr / FRAME_SIZE) % mask.count_ones() as usize;
+    let color = (0..MAX_COLORS).filter(|color| mask & (1 << color) != 0).nth(nth).unwrap();
+    if let Some(paddr) = pop(color).and_then(frame::claim_user_frame) {
+        return Some(paddr);
+    }
+    for _ in 0..MAX_TRIES {
+        let Some(block) = heap::alloc_contiguous(frame_layout()) else {
+            break;
+        };
+        let paddr = block.as_ptr() as usize;
+        unsafe {
+            core::ptr::write_bytes(block.as_ptr(), 0, FRAME_SIZE);
+        }
+        if color_of(paddr) == color {
+            match frame::claim_user_frame(paddr) {
+                Some(paddr) => return Some(paddr),
+                None => continue,
+            }
+        }
+        if !push(paddr) {
+            heap::dealloc_contiguous(block, frame_layout());
+            break;
+        }
+    }
+    // any color is better than no frame
+    frame::alloc_user_frame()
+}
+
+pub(crate) fn pooled() -> usize {
+    POOLS.lock().len
+}
+
+// gives the pooled frames back to the heap
+pub(crate) fn drain() {
+    for color in 0..MAX_COLORS {
+        let mut paddr = {
+            let mut pools = POOLS.lock();
+            core::mem::replace(&mut pools.heads[color], 0)
+        };
+        while paddr != 0 {
+            let next = unsafe { *(paddr as *const usize) };
+            POOLS.lock().len -= 1;
+            heap::dealloc_contiguous(NonNull::new(paddr as *mut u8).unwrap(), frame_layout());
+            paddr = next;
+        }
+    }
+}
+
+// takes the frame out of its pool, true if it was pooled. it stays taken from the heap
+pub(crate) fn unlink(paddr: usize) -> bool {
+    let mut pools = POOLS.lock();
+    let color = color_of(paddr);
+    let (mut prev, mut frame) = (0, pools.heads[color]);
+    while frame != 0 && frame != paddr {
+        prev = frame;
+        frame = unsafe { *(frame as *const usize) };
+    }
+    if frame == 0 {
+        return false;
+    }
+    let next = unsafe { *(frame as *const usize) };
+    if prev == 0 {
+        pools.heads[color] = next;
+    } else {
+        unsafe {
+            *(prev as *mut usize) = next;
+        }
+    }
+    pools.len -= 1;
+    true
+}
+
+fn pop(color: usize) -> Option<usize> {
+    let mut pools = POOLS.lock();
+    let paddr = pools.heads[color];
+    if paddr == 0 {
+        return None;
+    }
+    pools.heads[color] = unsafe { *(paddr as *const usize) };
+    pools.len -= 1;
+    Some(paddr)
+}
+
+fn push(paddr: usize) -> bool {
+    let mut pools = POOLS.lock();
+    if pools.len >= MAX_POOLED {
+        return false;
+    }
+    let color = color_of(paddr);
+    unsafe {
+        *(paddr as *mut usize) = pools.heads[color];
+    }
+    pools.heads[color] = paddr;
+    pools.len += 1;
+    true
+}
+
+fn frame_layout() -> Layout {
+    Layout::from_size_align(FRAME_SIZE, FRAME_SIZE).unwrap()
+}
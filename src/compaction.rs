@@ -0,0 +1,120 @@
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, Ordering};
+use mork_common::mork_kernel_log;
+use crate::addr::PhysAddr;
+use crate::frame::{self, Zone, FRAME_SIZE};
+use crate::frame_info::{self, FrameFlags};
+use crate::zoned::DMA32_LIMIT;
+use crate::{heap, migrate};
+
+// windows emptied per pass at most, each costs a migration per user frame in it
+const MAX_WINDOWS: usize = 8;
+
+// a second pass at the same time would only fight over the same windows
+static COMPACTING: AtomicBool = AtomicBool::new(false);
+
+// moves user frames out of the way until the heap has a free block of size bytes, returns whether it
+// got there. never run by the allocator, migration takes page table locks and allocates frames itself.
+// a caller whose contiguous allocation failed, or a kernel thread doing it for them, calls it and retries
+pub fn compact(zone: Zone, size: usize) -> bool {
+    let span = size.next_power_of_two().max(FRAME_SIZE) / FRAME_SIZE;
+    if span == 1 || COMPACTING.swap(true, Ordering::Acquire) {
+        return false;
+    }
+    let compacted = compact_windows(zone, span);
+    COMPACTING.store(false, Ordering::Release);
+    compacted
+}
+
+fn compact_windows(zone: Zone, span: usize) -> bool {
+    let range = frame_info::pfn_range();
+    let end = match zone {
+        Zone::Dma32 => range.end.min(PhysAddr::new(DMA32_LIMIT).ppn()),
+        Zone::Normal => range.end,
+    };
+    // (user frames, first pfn) of the cheapest windows, a fixed array as memory is short already
+    let mut candidates = [(usize::MAX, 0); MAX_WINDOWS];
+    let mut first = range.start.next_multiple_of(span);
+    while first + span <= end {
+        // a window without user frames is free already or pinned by the kernel
+        if let Some(movable) = movable_frames(first..first + span).filter(|movable| *movable != 0) {
+            let worst = candidates.iter_mut().max_by_key(|(movable, _)| *movable).unwrap();
+            if movable < worst.0 {
+                *worst = (movable, first);
+            }
+        }
+        first += span;
+    }
+    candidates.sort_unstable();
+    for (movable, first) in candidates.into_iter().filter(|(movable, _)| *movable != usize::MAX) {
+        if !evacuate(first..first + span) {
+            continue;
+        }
+        // freed frames sit in the hart caches until they are flushed back to merge
+        heap::flush_caches();
+        // kernel objects carry no frame flags, a window that looked free may still be in use.
+        // a backend that can not tell its largest block reports 0, the caller finds out by allocating
+        let largest = heap::largest_free_block();
+        if largest == 0 || largest >= span * FRAME_SIZE {
+            mork_kernel_log!(debug, "compaction moved {} frames out of pfn {:#x}", movable, first);
+            return true;
+        }
+    }
+    false
+}
+
+// None if the window holds frames that can not move
+pub(crate) fn movable_frames(window: Range<usize>) -> Option<usize> {
+    let mut movable = 0;
+    for pfn in window {
+        let flags = frame_info::frame_info(pfn)?.flags();
+        if flags.contains(FrameFlags::USER) && !frame_info::is_pinned(pfn) {
+            movable += 1;
+        } else if flags.intersects(FrameFlags::ALLOCATED | FrameFlags::RESERVED) {
+            return None;
+        }
+    }
+    Some(movable)
+}
+
+// returns whether every user frame left the window
+pub(crate) fn evacuate(window: Range<usize>) -> bool {
+    // destinations the allocator picked inside the window, linked through their first word
+    if window.clone().any(frame_info::is_pinned) {
+        return false;
+    }
+    let mut parked = 0;
+    let mut evacuated = true;
+    for pfn in window.clone() {
+        if !frame_info::frame_info(pfn).is_some_and(|info| info.flags().contains(FrameFlags::USER)) {
+            continue;
+        }
+        let dest = loop {
+            let Some(paddr) = frame::alloc_frame() else {
+                break None;
+            };
+            if !frame_info::pfn(paddr).is_some_and(|pfn| window.contains(&pfn)) {
+                break Some(paddr);
+            }
+            unsafe {
+                *(paddr as *mut usize) = parked;
+            }
+            parked = paddr;
+        };
+        let Some(dest) = dest else {
+            evacuated = false;
+            break;
+        };
+        if migrate::migrate_frame(pfn, frame_info::pfn(dest).unwrap()).is_err() {
+            unsafe { frame::dealloc_frame(dest); }
+            evacuated = false;
+            break;
+        }
+    }
+    while parked != 0 {
+        let next = unsafe { *(parked as *const usize) };
+        unsafe { frame::dealloc_frame(parked); }
+        parked = next;
+    }
+    evacuated
+}
@@ -0,0 +1,146 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use spin::mutex::Mutex;
+use mork_common::mork_kernel_log;
+use crate::addr::{phys_to_virt, virt_to_phys, PhysAddr, VirtAddr};
+use crate::attributes::{self, MapAttributes, MemoryType};
+use crate::cache;
+use crate::error::MmError;
+use crate::frame::{self, FRAME_SIZE};
+use crate::kernel_layout::{RegionAllocator, DMA_START, REGION_SIZE};
+use crate::kernel_template;
+use crate::memory::{self, PhysSegment};
+use crate::page_table::{MutPageTableWrapper, PageTableWrapper};
+use crate::shootdown::online_harts;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DmaDirection {
+    ToDevice,
+    FromDevice,
+    Bidirectional,
+}
+
+struct DmaAllocation {
+    // kernel window address of the frames
+    frames: usize,
+    size: usize,
+    // mapped again as non-cacheable in the dma region
+    remapped: bool,
+}
+
+struct DmaPool {
+    space: RegionAllocator,
+    allocations: BTreeMap<usize, DmaAllocation>,
+}
+
+static DMA_POOL: Mutex<DmaPool> = Mutex::new(DmaPool {
+    space: RegionAllocator::new(DMA_START, DMA_START + REGION_SIZE),
+    allocations: BTreeMap::new(),
+});
+
+// physically contiguous and zeroed, the physical address is aligned to align bytes.
+// with svpbmt the buffer is used through a non-cacheable alias, otherwise dma is assumed coherent
+pub fn dma_alloc_coherent(len: usize, align: usize) -> Option<(usize, PhysAddr)> {
+    if len == 0 || !align.is_power_of_two() {
+        mork_kernel_log!(warn, "invalid dma allocation, len: {:#x}, align: {:#x}", len, align);
+        return None;
+    }
+    let size = len.max(align).max(FRAME_SIZE).checked_next_power_of_two()?;
+    let frames = frame::alloc_frames(size)?;
+    let paddr = virt_to_phys(VirtAddr::new(frames)).unwrap();
+    if !attributes::svpbmt_supported() {
+        DMA_POOL.lock().allocations.insert(frames, DmaAllocation { frames, size, remapped: false });
+        return Some((frames, paddr));
+    }
+
+    let mut pool = DMA_POOL.lock();
+    let Some(vaddr) = pool.space.alloc(size) else {
+        mork_kernel_log!(warn, "dma region exhausted, {:#x} bytes requested", size);
+        unsafe { frame::dealloc_frames(frames, size); }
+        return None;
+    };
+    let attrs = (MapAttributes::READ | MapAttributes::WRITE | MapAttributes::ACCESSED | MapAttributes::DIRTY)
+        .with_memory_type(MemoryType::NonCacheable);
+    let mut wrapper = MutPageTableWrapper::new(kernel_template::kernel_root()).with_harts(online_harts());
+    for page in (0..size).step_by(FRAME_SIZE) {
+        if let Err(err) = wrapper.map_kernel_phys(vaddr + page, paddr + page, attrs) {
+            mork_kernel_log!(warn, "fail to map dma buffer at {:#x}, {}", vaddr + page, err);
+            unmap(&mut wrapper, vaddr, page);
+            pool.space.free(vaddr);
+            unsafe { frame::dealloc_frames(frames, size); }
+            return None;
+        }
+    }
+    pool.allocations.insert(vaddr, DmaAllocation { frames, size, remapped: true });
+    Some((vaddr, paddr))
+}
+
+/// # Safety
+///
+/// `vaddr` must be the address `dma_alloc_coherent` returned, and neither the cpu nor a device
+/// may access the buffer afterwards.
+pub unsafe fn dma_free_coherent(vaddr: usize) {
+    let mut pool = DMA_POOL.lock();
+    let Some(allocation) = pool.allocations.remove(&vaddr) else {
+        mork_kernel_log!(warn, "dma free of unknown buffer {:#x}", vaddr);
+        return;
+    };
+    if allocation.remapped {
+        let mut wrapper = MutPageTableWrapper::new(kernel_template::kernel_root()).with_harts(online_harts());
+        unmap(&mut wrapper, vaddr, allocation.size);
+        pool.space.free(vaddr);
+    }
+    unsafe {
+        frame::dealloc_frames(allocation.frames, allocation.size);
+    }
+}
+
+fn unmap(wrapper: &mut MutPageTableWrapper, vaddr: usize, len: usize) {
+    wrapper.begin_batch();
+    for page in (0..len).step_by(FRAME_SIZE) {
+        let _ = wrapper.unmap_kernel_page(vaddr + page);
+    }
+    wrapper.finish_batch();
+}
+
+// translates a kernel buffer into physical segments and hands it to the device
+pub fn dma_map_range(vaddr: usize, len: usize, direction: DmaDirection) -> Result<Vec<PhysSegment>, MmError> {
+    let end = vaddr.checked_add(len).filter(|_| len != 0).ok_or(MmError::InvalidParam)?;
+    let wrapper = PageTableWrapper::new(kernel_template::kernel_root());
+    let mut segments: Vec<PhysSegment> = Vec::new();
+    let mut current = vaddr;
+    while current < end {
+        let Some((paddr, _, _)) = wrapper.translate(current) else {
+            mork_kernel_log!(warn, "dma buffer is not mapped at {:#x}", current);
+            return Err(MmError::NotMapped);
+        };
+        let paddr = virt_to_phys(VirtAddr::new(paddr)).ok_or(MmError::InvalidAddress)?;
+        let chunk = (FRAME_SIZE - (current & (FRAME_SIZE - 1))).min(end - current);
+        memory::push_segment(&mut segments, paddr, chunk);
+        current += chunk;
+    }
+    dma_sync_for_device(&segments, direction);
+    Ok(segments)
+}
+
+// before the device accesses the segments
+pub fn dma_sync_for_device(segments: &[PhysSegment], direction: DmaDirection) {
+    for segment in segments {
+        let vaddr = phys_to_virt(segment.paddr).as_usize();
+        match direction {
+            DmaDirection::ToDevice => cache::clean_range(vaddr, segment.len),
+            // dirty lines must not be written back over what the device stores
+            DmaDirection::FromDevice | DmaDirection::Bidirectional => cache::flush_range(vaddr, segment.len),
+        }
+    }
+}
+
+// before the cpu reads what the device wrote
+pub fn dma_sync_for_cpu(segments: &[PhysSegment], direction: DmaDirection) {
+    if direction == DmaDirection::ToDevice {
+        return;
+    }
+    for segment in segments {
+        cache::invalidate_range(phys_to_virt(segment.paddr).as_usize(), segment.len);
+    }
+}
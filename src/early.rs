@@ -0,0 +1,48 @@
+use core::alloc::Layout;
+use core::cell::UnsafeCell;
+use core::ptr::NonNull;
+use spin::mutex::Mutex;
+use mork_common::mork_kernel_log;
+use crate::frame::FRAME_SIZE;
+
+const EARLY_HEAP_SIZE: usize = 64 * 1024;
+
+#[repr(C, align(4096))]
+struct Arena(UnsafeCell<[u8; EARLY_HEAP_SIZE]>);
+
+unsafe impl Sync for Arena {}
+
+// lives in bss, so every allocation starts zeroed
+static ARENA: Arena = Arena(UnsafeCell::new([0; EARLY_HEAP_SIZE]));
+
+// offset of the first unused byte, None once the rest went to the heap
+static NEXT: Mutex<Option<usize>> = Mutex::new(Some(0));
+
+// memory handed out here is never freed
+pub fn alloc(layout: Layout) -> Option<NonNull<u8>> {
+    let mut next = NEXT.lock();
+    let Some(offset) = *next else {
+        mork_kernel_log!(warn, "early allocator is retired, use the heap");
+        return None;
+    };
+    let base = ARENA.0.get() as usize;
+    let start = (base + offset).checked_next_multiple_of(layout.align())?;
+    let end = start.checked_add(layout.size())?;
+    if end > base + EARLY_HEAP_SIZE {
+        mork_kernel_log!(warn, "early allocator exhausted, {:#x} bytes requested", layout.size());
+        return None;
+    }
+    *next = Some(end - base);
+    NonNull::new(start as *mut u8)
+}
+
+pub fn alloc_frame() -> Option<usize> {
+    alloc(Layout::from_size_align(FRAME_SIZE, FRAME_SIZE).unwrap()).map(|ptr| ptr.as_ptr() as usize)
+}
+
+// retires the allocator and returns what is left of the arena
+pub(crate) fn take_rest() -> Option<(usize, usize)> {
+    let offset = NEXT.lock().take()?;
+    let base = ARENA.0.get() as usize;
+    (offset < EARLY_HEAP_SIZE).then_some((base + offset, base + EARLY_HEAP_SIZE))
+}
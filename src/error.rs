@@ -0,0 +1,59 @@
+use alloc::format;
+use alloc::string::String;
+use core::fmt;
+use mork_common::syscall::message_info::ResponseLabel;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MmError {
+    AlignmentError,
+    AlreadyMapped,
+    PageTableMiss,
+    OutOfMemory,
+    InvalidLevel,
+    NotMapped,
+    InvalidAddress,
+    InvalidParam,
+    WxViolation,
+    GuardRegion,
+    QuotaExceeded,
+    PermissionDenied,
+    InUse,
+}
+
+impl fmt::Display for MmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            MmError::AlignmentError => "address is not aligned",
+            MmError::AlreadyMapped => "address has been mapped",
+            MmError::PageTableMiss => "page table need to been mapped first",
+            MmError::OutOfMemory => "out of memory",
+            MmError::InvalidLevel => "invalid page table level",
+            MmError::NotMapped => "address is not mapped",
+            MmError::InvalidAddress => "address out of range",
+            MmError::InvalidParam => "invalid param",
+            MmError::WxViolation => "writable and executable mapping is not allowed",
+            MmError::GuardRegion => "address is reserved as a guard region",
+            MmError::QuotaExceeded => "memory quota exceeded",
+            MmError::PermissionDenied => "access is not allowed by the mapping",
+            MmError::InUse => "memory is still in use",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl From<MmError> for ResponseLabel {
+    fn from(err: MmError) -> Self {
+        match err {
+            MmError::AlreadyMapped => ResponseLabel::MappedAlready,
+            MmError::PageTableMiss => ResponseLabel::PageTableMiss,
+            // mork-common has no dedicated labels for the rest
+            _ => ResponseLabel::InvalidParam,
+        }
+    }
+}
+
+impl From<MmError> for String {
+    fn from(err: MmError) -> Self {
+        format!("{}", err)
+    }
+}
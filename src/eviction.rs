@@ -0,0 +1,72 @@
+use alloc::collections::VecDeque;
+use crate::page_table::MutPageTableWrapper;
+
+// second chance lists of private anonymous pages, by user vaddr
+#[derive(Clone, Default)]
+pub struct PageLru {
+    active: VecDeque<usize>,
+    inactive: VecDeque<usize>,
+}
+
+impl PageLru {
+    pub fn new() -> Self {
+        Self { active: VecDeque::new(), inactive: VecDeque::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.active.len() + self.inactive.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn pages(&self) -> impl Iterator<Item = usize> + '_ {
+        self.active.iter().chain(self.inactive.iter()).copied()
+    }
+
+    // freshly touched pages start out active
+    pub fn insert(&mut self, vaddr: usize) {
+        self.active.push_back(vaddr);
+    }
+
+    pub fn remove_range(&mut self, start: usize, len: usize) {
+        let outside = |vaddr: &usize| *vaddr < start || *vaddr >= start + len;
+        self.active.retain(outside);
+        self.inactive.retain(outside);
+    }
+
+    // the next page not accessed since it was last scanned, pages seen accessed get another round
+    pub fn next_victim(&mut self, wrapper: &mut MutPageTableWrapper) -> Option<usize> {
+        let mut budget = 2 * self.len();
+        while budget > 0 {
+            budget -= 1;
+            if self.inactive.is_empty() {
+                self.refill_inactive(wrapper);
+            }
+            let vaddr = self.inactive.pop_front()?;
+            match wrapper.get_and_clear_accessed(vaddr) {
+                Ok(false) => return Some(vaddr),
+                Ok(true) => self.active.push_back(vaddr),
+                // unmapped behind our back, forget it
+                Err(_) => {}
+            }
+        }
+        None
+    }
+
+    // ages the older half of the active list, clearing accessed bits on the way
+    fn refill_inactive(&mut self, wrapper: &mut MutPageTableWrapper) {
+        let count = self.active.len().div_ceil(2);
+        for _ in 0..count {
+            let Some(vaddr) = self.active.pop_front() else {
+                break;
+            };
+            match wrapper.get_and_clear_accessed(vaddr) {
+                Ok(true) => self.active.push_back(vaddr),
+                Ok(false) => self.inactive.push_back(vaddr),
+                Err(_) => {}
+            }
+        }
+    }
+}
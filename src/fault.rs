@@ -0,0 +1,178 @@
+use mork_common::mork_kernel_log;
+use mork_common::types::ResultWithErr;
+use mork_hal::config::HAL_PAGE_LEVEL;
+use crate::address_space::AddressSpace;
+use crate::attributes::MapAttributes;
+use crate::error::MmError;
+use crate::frame::{self, FRAME_SIZE};
+use crate::{coloring, swap};
+use crate::vm_area::VmBacking;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessType {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultResolution {
+    Resolved,
+    // no area covers the faulting address
+    Unmapped,
+    // the area exists but does not allow the access
+    AccessViolation,
+    OutOfMemory,
+    QuotaExceeded,
+}
+
+impl FaultResolution {
+    // for kernel paths that fault pages in on behalf of the user
+    pub fn into_result(self) -> ResultWithErr<MmError> {
+        match self {
+            FaultResolution::Resolved => Ok(()),
+            FaultResolution::Unmapped => Err(MmError::NotMapped),
+            FaultResolution::AccessViolation => Err(MmError::PermissionDenied),
+            FaultResolution::OutOfMemory => Err(MmError::OutOfMemory),
+            FaultResolution::QuotaExceeded => Err(MmError::QuotaExceeded),
+        }
+    }
+}
+
+pub fn handle_page_fault(address_space: &mut AddressSpace, fault_vaddr: usize, access_type: AccessType)
+    -> FaultResolution {
+    let Some(area) = address_space.find_region(fault_vaddr).cloned()
+        .or_else(|| address_space.grow_stack(fault_vaddr)) else {
+        return FaultResolution::Unmapped;
+    };
+    let allowed = match access_type {
+        AccessType::Read => area.attrs.is_r(),
+        AccessType::Write => area.attrs.is_w(),
+        AccessType::Execute => area.attrs.is_x(),
+    };
+    if !allowed {
+        mork_kernel_log!(warn, "{:?} access to {:#x} violates the area permissions", access_type, fault_vaddr);
+        return FaultResolution::AccessViolation;
+    }
+
+    let page = fault_vaddr & !(FRAME_SIZE - 1);
+    let color_mask = address_space.color_mask();
+    let mut wrapper = address_space.wrapper();
+    if let Some(slot) = wrapper.swap_entry(page) {
+        drop(wrapper);
+        return swap_in(address_space, page, slot, area.attrs);
+    }
+    if let Some((paddr, _, attrs)) = wrapper.translate(page) {
+        if access_type == AccessType::Write && attrs.is_cow() {
+            let result = wrapper.resolve_cow_fault(page);
+            drop(wrapper);
+            return match result {
+                Ok(()) => {
+                    // pages copied out of the zero page are private from now on
+                    if frame::is_zero_page(paddr) && matches!(area.backing, VmBacking::Anonymous) {
+                        address_space.track_page(page);
+                    }
+                    FaultResolution::Resolved
+                }
+                Err(err) => map_error_resolution(err),
+            };
+        }
+        let permitted = match access_type {
+            AccessType::Read => attrs.is_r(),
+            AccessType::Write => attrs.is_w(),
+            AccessType::Execute => attrs.is_x(),
+        };
+        if !permitted {
+            mork_kernel_log!(warn, "{:?} access to {:#x} violates the leaf permissions", access_type, fault_vaddr);
+            return FaultResolution::AccessViolation;
+        }
+        // raced with another hart or a stale tlb entry, the access can simply be retried
+        return FaultResolution::Resolved;
+    }
+
+    match &area.backing {
+        VmBacking::Anonymous if access_type == AccessType::Read => {
+            // share the zero page until the first write, which goes through the cow path
+            let attrs = if area.attrs.is_w() {
+                (area.attrs - MapAttributes::WRITE) | MapAttributes::COW
+            } else {
+                area.attrs
+            };
+            match wrapper.map_frame_populate(page, frame::zero_page(), HAL_PAGE_LEVEL, attrs) {
+                Ok(()) => FaultResolution::Resolved,
+                Err(err) => {
+                    mork_kernel_log!(warn, "fail to map zero page for fault at {:#x}, {}", fault_vaddr, err);
+                    map_error_resolution(err)
+                }
+            }
+        }
+        VmBacking::Anonymous => {
+            let Some(paddr) = coloring::alloc_user_frame(color_mask, page) else {
+                mork_kernel_log!(warn, "fail to allocate frame for fault at {:#x}", fault_vaddr);
+                return FaultResolution::OutOfMemory;
+            };
+            let result = wrapper.map_frame_populate(page, paddr, HAL_PAGE_LEVEL, area.attrs);
+            drop(wrapper);
+            match result {
+                Ok(()) => {
+                    address_space.track_page(page);
+                    FaultResolution::Resolved
+                }
+                Err(err) => {
+                    unsafe { frame::dealloc_frame(paddr); }
+                    mork_kernel_log!(warn, "fail to map frame for fault at {:#x}, {}", fault_vaddr, err);
+                    map_error_resolution(err)
+                }
+            }
+        }
+        VmBacking::File { backend, offset } => {
+            let paddr = match backend.read_page(offset + (page - area.start)) {
+                Ok(paddr) => paddr,
+                Err(err) => {
+                    mork_kernel_log!(warn, "fail to read backing page for fault at {:#x}, {}", fault_vaddr, err);
+                    return map_error_resolution(err);
+                }
+            };
+            match wrapper.map_frame_populate(page, paddr, HAL_PAGE_LEVEL, area.attrs) {
+                Ok(()) => FaultResolution::Resolved,
+                Err(err) => {
+                    unsafe { frame::dealloc_frame(paddr); }
+                    mork_kernel_log!(warn, "fail to map backing page for fault at {:#x}, {}", fault_vaddr, err);
+                    map_error_resolution(err)
+                }
+            }
+        }
+        // fixed areas are mapped eagerly, a hole means the frames were taken away
+        VmBacking::Fixed { .. } => FaultResolution::Unmapped,
+    }
+}
+
+fn swap_in(address_space: &mut AddressSpace, page: usize, slot: usize, attrs: MapAttributes) -> FaultResolution {
+    let Some(paddr) = coloring::alloc_user_frame(address_space.color_mask(), page) else {
+        mork_kernel_log!(warn, "fail to allocate frame for swap in at {:#x}", page);
+        return FaultResolution::OutOfMemory;
+    };
+    // the swap entry stays in place until the page is mapped over it
+    let result = swap::swap_in(slot, paddr)
+        .and_then(|_| address_space.wrapper().map_frame_populate(page, paddr, HAL_PAGE_LEVEL, attrs));
+    match result {
+        Ok(()) => {
+            swap::free_slot(slot);
+            address_space.track_page(page);
+            FaultResolution::Resolved
+        }
+        Err(err) => {
+            unsafe { frame::dealloc_frame(paddr); }
+            mork_kernel_log!(warn, "fail to swap in {:#x}, {}", page, err);
+            map_error_resolution(err)
+        }
+    }
+}
+
+fn map_error_resolution(err: MmError) -> FaultResolution {
+    match err {
+        MmError::OutOfMemory => FaultResolution::OutOfMemory,
+        MmError::QuotaExceeded => FaultResolution::QuotaExceeded,
+        _ => FaultResolution::AccessViolation,
+    }
+}
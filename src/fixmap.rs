@@ -0,0 +1,52 @@
+use mork_common::mork_kernel_log;
+use mork_common::types::ResultWithErr;
+use crate::addr::PhysAddr;
+use crate::attributes::MapAttributes;
+use crate::error::MmError;
+use crate::frame::FRAME_SIZE;
+use crate::kernel_layout::FIXMAP_START;
+use crate::{hart, kernel_template};
+use crate::page_table::{MutPageTableWrapper, PageTable};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(usize)]
+pub enum FixmapSlot {
+    Early,
+    Atomic0,
+    Atomic1,
+    Trampoline,
+}
+
+const SLOTS_PER_HART: usize = 4;
+// hart masks are a usize
+const MAX_HARTS: usize = usize::BITS as usize;
+// behind the kmap window, in a last level table of its own that is created at init
+pub const FIXMAP_HART_START: usize = FIXMAP_START + (2 << 20);
+
+pub(crate) fn init(kernel_page_table: &mut PageTable) -> ResultWithErr<MmError> {
+    MutPageTableWrapper::new(kernel_page_table).with_boot_pool().populate(FIXMAP_HART_START, 2)
+}
+
+pub fn fixmap_addr(hart_id: usize, slot: FixmapSlot) -> usize {
+    debug_assert!(hart_id < MAX_HARTS);
+    FIXMAP_HART_START + (hart_id * SLOTS_PER_HART + slot as usize) * FRAME_SIZE
+}
+
+// replaces whatever the slot of the current hart mapped, never allocates or takes a lock,
+// only the current hart uses its slots so a local flush is enough
+pub fn set_fixmap(slot: FixmapSlot, paddr: PhysAddr, attrs: MapAttributes) -> Result<usize, MmError> {
+    let vaddr = fixmap_addr(hart::hart_id(), slot);
+    let frame = PhysAddr::new(paddr.as_usize() & !(FRAME_SIZE - 1));
+    let mut wrapper = MutPageTableWrapper::new(kernel_template::kernel_root());
+    if wrapper.translate(vaddr).is_some() {
+        wrapper.unmap_kernel_page(vaddr)?;
+    }
+    wrapper.map_kernel_phys(vaddr, frame, attrs | MapAttributes::ACCESSED | MapAttributes::DIRTY)
+        .inspect_err(|err| mork_kernel_log!(warn, "fail to set fixmap {:?} to {:#x}, {}", slot, paddr.as_usize(), err))?;
+    Ok(vaddr + (paddr.as_usize() & (FRAME_SIZE - 1)))
+}
+
+pub fn clear_fixmap(slot: FixmapSlot) {
+    let vaddr = fixmap_addr(hart::hart_id(), slot);
+    let _ = MutPageTableWrapper::new(kernel_template::kernel_root()).unmap_kernel_page(vaddr);
+}
@@ -0,0 +1,319 @@
+use core::alloc::Layout;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use core::ptr::NonNull;
+use mork_common::mork_kernel_log;
+use mork_common::types::ResultWithErr;
+use crate::addr::PhysAddr;
+use crate::error::MmError;
+use crate::frame_info::{self, FrameFlags};
+use crate::{cma, coloring, heap};
+use crate::sync::IrqMutex;
+use crate::watermark::{self, MemoryPressure};
+
+pub const FRAME_SIZE: usize = 4096;
+
+// which physical memory a frame may come from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Zone {
+    // below 4 GiB, for devices with 32 bit dma masks
+    Dma32,
+    // anywhere, dma32 memory is only used once the rest is gone
+    Normal,
+}
+
+// what happens to the contents of a frame when it is freed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FreeScrub {
+    Off,
+    Zero,
+    // a recognizable pattern, stale reads show up in dumps instead of looking like valid zeroes
+    Poison,
+}
+
+const FREE_POISON: u8 = 0x5a;
+
+// shared by every read-only anonymous page, never freed
+static ZERO_PAGE: AtomicUsize = AtomicUsize::new(0);
+
+// bytes currently handed out as frames
+static FRAME_BYTES: AtomicUsize = AtomicUsize::new(0);
+static FREE_SCRUB: AtomicU8 = AtomicU8::new(FreeScrub::Off as u8);
+
+// frames zeroed ahead of time by the idle loop, linked through their first word
+struct ZeroedFrames {
+    head: usize,
+    len: usize,
+}
+
+static ZEROED: IrqMutex<ZeroedFrames> = IrqMutex::new(ZeroedFrames { head: 0, len: 0 });
+static ZEROED_TARGET: AtomicUsize = AtomicUsize::new(0);
+
+pub fn frames_in_use() -> usize {
+    FRAME_BYTES.load(Ordering::Relaxed) / FRAME_SIZE
+}
+
+// upper bound, fragmentation may keep large blocks from being allocated
+// the heap reserve counts as free, the heap grows into it on demand
+pub fn free_frames() -> usize {
+    let (total, used) = heap::usage();
+    // frames waiting zeroed or for their cache color are taken from the heap, but are as good as free
+    (total - used + heap::reserve_bytes()) / FRAME_SIZE + ZEROED.lock().len + coloring::pooled()
+}
+
+// freed frames merge back into the heap, without scrubbing a task's data can reach the next owner
+// of the memory through a kernel allocation that is later copied out, not only through a frame
+pub fn set_free_scrub(scrub: FreeScrub) {
+    FREE_SCRUB.store(scrub as u8, Ordering::Relaxed);
+}
+
+pub fn free_scrub() -> FreeScrub {
+    match FREE_SCRUB.load(Ordering::Relaxed) {
+        0 => FreeScrub::Off,
+        1 => FreeScrub::Zero,
+        _ => FreeScrub::Poison,
+    }
+}
+
+pub(crate) fn init_zero_page() -> ResultWithErr<MmError> {
+    let paddr = alloc_frame().ok_or(MmError::OutOfMemory)?;
+    ZERO_PAGE.store(paddr, Ordering::Release);
+    Ok(())
+}
+
+pub fn zero_page() -> usize {
+    ZERO_PAGE.load(Ordering::Acquire)
+}
+
+pub fn is_zero_page(paddr: usize) -> bool {
+    paddr != 0 && paddr == zero_page()
+}
+
+pub fn alloc_frame() -> Option<usize> {
+    alloc_frames(FRAME_SIZE)
+}
+
+// for frames that end up in user space, zeroed like every frame but cheap when the idle loop kept up
+pub fn alloc_user_frame() -> Option<usize> {
+    loop {
+        let paddr = {
+            let mut zeroed = ZEROED.lock();
+            let paddr = zeroed.head;
+            if paddr != 0 {
+                zeroed.head = unsafe { *(paddr as *const usize) };
+                zeroed.len -= 1;
+            }
+            paddr
+        };
+        if paddr == 0 {
+            return alloc_movable_frame();
+        }
+        if let Some(paddr) = claim_user_frame(paddr) {
+            return Some(paddr);
+        }
+    }
+}
+
+// user frames can move, so once normal memory runs short they may borrow free cma frames
+fn alloc_movable_frame() -> Option<usize> {
+    let lent = (watermark::pressure() != MemoryPressure::Normal).then(cma::lend_frame).flatten();
+    let Some(paddr) = lent else {
+        return alloc_frame().inspect(|paddr| frame_info::mark_allocated(*paddr, FRAME_SIZE, FrameFlags::USER));
+    };
+    unsafe {
+        core::ptr::write_bytes(paddr as *mut u8, 0, FRAME_SIZE);
+    }
+    frame_info::mark_allocated(paddr, FRAME_SIZE, FrameFlags::USER);
+    FRAME_BYTES.fetch_add(FRAME_SIZE, Ordering::Relaxed);
+    watermark::check();
+    Some(paddr)
+}
+
+// hands out a frame taken from the heap and zeroed except for a list link in its first word.
+// a frame reported as bad ram while it waited is kept out of use, None then
+pub(crate) fn claim_user_frame(paddr: usize) -> Option<usize> {
+    if frame_info::is_poisoned(paddr, FRAME_SIZE) {
+        mork_kernel_log!(warn, "frame {:#x} is poisoned, kept out of use", paddr);
+        return None;
+    }
+    unsafe {
+        *(paddr as *mut usize) = 0;
+    }
+    frame_info::mark_allocated(paddr, FRAME_SIZE, FrameFlags::USER);
+    FRAME_BYTES.fetch_add(FRAME_SIZE, Ordering::Relaxed);
+    watermark::check();
+    Some(paddr)
+}
+
+// takes the frame off the zeroed frames, true if it was one of them. it stays taken from the heap
+pub(crate) fn unlink_zeroed(paddr: usize) -> bool {
+    let mut zeroed = ZEROED.lock();
+    let (mut prev, mut frame) = (0, zeroed.head);
+    while frame != 0 && frame != paddr {
+        prev = frame;
+        frame = unsafe { *(frame as *const usize) };
+    }
+    if frame == 0 {
+        return false;
+    }
+    let next = unsafe { *(frame as *const usize) };
+    if prev == 0 {
+        zeroed.head = next;
+    } else {
+        unsafe {
+            *(prev as *mut usize) = next;
+        }
+    }
+    zeroed.len -= 1;
+    true
+}
+
+// frames the idle loop keeps zeroed ahead of time, 0 turns background zeroing off
+pub fn set_zeroed_target(frames: usize) {
+    ZEROED_TARGET.store(frames, Ordering::Relaxed);
+    if frames == 0 {
+        drain_zeroed();
+    }
+}
+
+// for the idle loop, zeroes at most budget frames and returns how many it zeroed
+pub fn zero_idle_frames(budget: usize) -> usize {
+    let mut count = 0;
+    while count < budget && ZEROED.lock().len < ZEROED_TARGET.load(Ordering::Relaxed) {
+        // background work must not be what pushes the system under pressure
+        if watermark::pressure() != MemoryPressure::Normal {
+            break;
+        }
+        let Some(frame) = heap::try_alloc_contiguous(frames_layout(FRAME_SIZE)) else {
+            break;
+        };
+        let paddr = frame.as_ptr() as usize;
+        unsafe {
+            core::ptr::write_bytes(frame.as_ptr(), 0, FRAME_SIZE);
+        }
+        let mut zeroed = ZEROED.lock();
+        unsafe {
+            *(paddr as *mut usize) = zeroed.head;
+        }
+        zeroed.head = paddr;
+        zeroed.len += 1;
+        count += 1;
+    }
+    count
+}
+
+// gives the zeroed frames back to the heap, e.g. when it runs short
+pub(crate) fn drain_zeroed() {
+    let mut paddr = {
+        let mut zeroed = ZEROED.lock();
+        zeroed.len = 0;
+        core::mem::replace(&mut zeroed.head, 0)
+    };
+    while paddr != 0 {
+        let next = unsafe { *(paddr as *const usize) };
+        heap::dealloc_contiguous(NonNull::new(paddr as *mut u8).unwrap(), frames_layout(FRAME_SIZE));
+        paddr = next;
+    }
+}
+
+/// # Safety
+///
+/// `paddr` must come from `alloc_frame`, and nothing may use the frame afterwards.
+pub unsafe fn dealloc_frame(paddr: usize) {
+    unsafe {
+        dealloc_frames(paddr, FRAME_SIZE);
+    }
+}
+
+// size must be a power of two multiple of FRAME_SIZE, the block is aligned to its size
+// frames bypass the global allocator, which may hand large blocks out of the vmalloc area
+pub fn alloc_frames(size: usize) -> Option<usize> {
+    alloc_frames_in(Zone::Normal, size)
+}
+
+// same as alloc_frames, restricted to memory the zone covers
+pub fn alloc_frames_in(zone: Zone, size: usize) -> Option<usize> {
+    alloc_layout(zone, frames_layout(size))
+}
+
+// count frames in one run aligned to 2^align_order frames, e.g. for device queues,
+// freed with the same count and order
+pub fn alloc_frames_contiguous(count: usize, align_order: usize) -> Option<usize> {
+    alloc_layout(Zone::Normal, contiguous_layout(count, align_order)?)
+}
+
+/// # Safety
+///
+/// `paddr` must come from `alloc_frames_contiguous` with the same `count` and `align_order`,
+/// and nothing may use the frames afterwards.
+pub unsafe fn dealloc_frames_contiguous(paddr: usize, count: usize, align_order: usize) {
+    unsafe {
+        dealloc_layout(paddr, contiguous_layout(count, align_order).unwrap());
+    }
+}
+
+/// # Safety
+///
+/// `paddr` must come from `alloc_frames` with the same `size`, and nothing may use the frames afterwards.
+pub unsafe fn dealloc_frames(paddr: usize, size: usize) {
+    unsafe {
+        dealloc_layout(paddr, frames_layout(size));
+    }
+}
+
+fn alloc_layout(zone: Zone, layout: Layout) -> Option<usize> {
+    let ptr = loop {
+        let ptr = heap::alloc_contiguous_in(zone, layout)?.as_ptr();
+        // bad ram that could not be taken out of the heap when it was reported stays allocated instead
+        if !frame_info::is_poisoned(ptr as usize, layout.size()) {
+            break ptr;
+        }
+        mork_kernel_log!(warn, "block {:#x} holds a poisoned frame, kept out of use", ptr as usize);
+    };
+    unsafe {
+        core::ptr::write_bytes(ptr, 0, layout.size());
+    }
+    frame_info::mark_allocated(ptr as usize, layout.size(), FrameFlags::empty());
+    FRAME_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+    watermark::check();
+    Some(ptr as usize)
+}
+
+unsafe fn dealloc_layout(paddr: usize, layout: Layout) {
+    // a shared frame goes away with its last holder
+    if frame_info::pfn(paddr).is_some_and(|pfn| frame_info::put_shared(PhysAddr::from_ppn(pfn))) {
+        return;
+    }
+    let size = layout.size();
+    if frame_info::is_poisoned(paddr, size) {
+        mork_kernel_log!(warn, "block {:#x} holds a poisoned frame, kept out of the heap", paddr);
+        return;
+    }
+    let fill = match free_scrub() {
+        FreeScrub::Off => None,
+        FreeScrub::Zero => Some(0),
+        FreeScrub::Poison => Some(FREE_POISON),
+    };
+    if let Some(fill) = fill {
+        unsafe {
+            core::ptr::write_bytes(paddr as *mut u8, fill, size);
+        }
+    }
+    frame_info::mark_free(paddr, size);
+    // a frame the cma region lent goes back there
+    if size != FRAME_SIZE || !cma::take_back(paddr) {
+        heap::dealloc_contiguous(NonNull::new(paddr as *mut u8).unwrap(), layout);
+    }
+    FRAME_BYTES.fetch_sub(size, Ordering::Relaxed);
+    watermark::check();
+}
+
+fn frames_layout(size: usize) -> Layout {
+    Layout::from_size_align(size, size).unwrap()
+}
+
+fn contiguous_layout(count: usize, align_order: usize) -> Option<Layout> {
+    let size = count.checked_mul(FRAME_SIZE).filter(|size| *size != 0)?;
+    let align = FRAME_SIZE.checked_shl(align_order as u32)?;
+    Layout::from_size_align(size, align).ok()
+}
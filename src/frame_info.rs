@@ -0,0 +1,248 @@
+use core::ops::Range;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use bitflags::bitflags;
+use spin::mutex::Mutex;
+use mork_common::mork_kernel_log;
+use mork_common::types::ResultWithErr;
+use crate::addr::{virt_to_phys, PhysAddr, VirtAddr};
+use crate::error::MmError;
+use crate::frame::{self, FRAME_SIZE};
+use crate::{memory, vmalloc};
+
+bitflags! {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct FrameFlags: u32 {
+        // firmware, the kernel image and other reserved regions, never allocated
+        const RESERVED = 1 << 0;
+        const ALLOCATED = 1 << 1;
+        const USER = 1 << 2;
+        const PAGE_TABLE = 1 << 3;
+        const ZERO_PAGE = 1 << 4;
+        // identical pages of several mappings share it
+        const MERGED = 1 << 5;
+        // handed to the host by the balloon, the contents are gone
+        const BALLOONED = 1 << 6;
+        // bad ram, never handed out again
+        const POISONED = 1 << 7;
+    }
+}
+
+// one per frame of ram, all zero is a free frame nobody knows anything about
+pub struct FrameInfo {
+    // holders of the frame, every mapping sharing it through cow or merging holds one
+    pub refcount: AtomicUsize,
+    // leaf entries mapping the frame
+    pub mapcount: AtomicUsize,
+    flags: AtomicU32,
+    // asid of the address space the frame was allocated for, 0 for kernel frames
+    pub owner: AtomicUsize,
+    // pfns of the neighbours on an lru list, only touched under the lock of that list
+    pub lru_prev: AtomicUsize,
+    pub lru_next: AtomicUsize,
+    // pins on the pages mapping the frame, a pinned frame stays where it is
+    pub pincount: AtomicUsize,
+}
+
+impl FrameInfo {
+    pub fn flags(&self) -> FrameFlags {
+        FrameFlags::from_bits_truncate(self.flags.load(Ordering::Acquire))
+    }
+
+    pub fn insert_flags(&self, flags: FrameFlags) {
+        self.flags.fetch_or(flags.bits(), Ordering::AcqRel);
+    }
+
+    pub fn remove_flags(&self, flags: FrameFlags) {
+        self.flags.fetch_and(!flags.bits(), Ordering::AcqRel);
+    }
+
+    fn reset(&self) {
+        self.refcount.store(0, Ordering::Relaxed);
+        self.mapcount.store(0, Ordering::Relaxed);
+        self.owner.store(0, Ordering::Relaxed);
+        self.lru_prev.store(0, Ordering::Relaxed);
+        self.lru_next.store(0, Ordering::Relaxed);
+        self.pincount.store(0, Ordering::Relaxed);
+        // bad ram stays bad for the boot
+        self.flags.fetch_and(FrameFlags::POISONED.bits(), Ordering::Release);
+    }
+}
+
+// a vmalloc'd array indexed by pfn - first, empty while frames is 0
+struct Section {
+    frames: AtomicUsize,
+    first: AtomicUsize,
+    count: AtomicUsize,
+}
+
+impl Section {
+    const fn new() -> Self {
+        Self { frames: AtomicUsize::new(0), first: AtomicUsize::new(0), count: AtomicUsize::new(0) }
+    }
+
+    // readers go by frames, first and count are stored before it
+    fn publish(&self, frames: usize, first: usize, count: usize) {
+        self.first.store(first, Ordering::Relaxed);
+        self.count.store(count, Ordering::Relaxed);
+        self.frames.store(frames, Ordering::Release);
+    }
+
+    fn range(&self) -> Option<Range<usize>> {
+        if self.frames.load(Ordering::Acquire) == 0 {
+            return None;
+        }
+        let first = self.first.load(Ordering::Relaxed);
+        Some(first..first + self.count.load(Ordering::Relaxed))
+    }
+
+    fn get(&self, pfn: usize) -> Option<&'static FrameInfo> {
+        let frames = self.frames.load(Ordering::Acquire);
+        let index = pfn.checked_sub(self.first.load(Ordering::Relaxed))?;
+        if frames == 0 || index >= self.count.load(Ordering::Relaxed) {
+            return None;
+        }
+        Some(unsafe { &*(frames as *const FrameInfo).add(index) })
+    }
+}
+
+const MAX_SECTIONS: usize = 16;
+
+// the boot ram, zero until init
+static BOOT: Section = Section::new();
+// ram added at runtime. a section stays when its ram is removed, references into it remain valid
+// and the frames are reserved until the same range comes back
+static SECTIONS: [Section; MAX_SECTIONS] = [const { Section::new() }; MAX_SECTIONS];
+static SECTIONS_LOCK: Mutex<()> = Mutex::new(());
+
+// frames allocated before this point are known as neither allocated nor free
+pub(crate) fn init(ram_start: PhysAddr, ram_end: PhysAddr) -> ResultWithErr<MmError> {
+    let first = ram_start.ppn();
+    let count = ram_end.as_usize().div_ceil(FRAME_SIZE) - first;
+    // zeroed frames are valid, empty entries
+    let frames = vmalloc::vmalloc(count * size_of::<FrameInfo>()).ok_or(MmError::OutOfMemory)?;
+    BOOT.publish(frames, first, count);
+    for reserved in memory::reserved_regions() {
+        let region = reserved.region;
+        for pfn in region.start.ppn()..region.end.as_usize().div_ceil(FRAME_SIZE) {
+            if let Some(info) = frame_info(pfn) {
+                info.insert_flags(FrameFlags::RESERVED);
+            }
+        }
+    }
+    if let Some(info) = pfn(frame::zero_page()).and_then(frame_info) {
+        info.refcount.store(1, Ordering::Relaxed);
+        info.insert_flags(FrameFlags::ALLOCATED | FrameFlags::ZERO_PAGE);
+    }
+    mork_kernel_log!(info, "frame info for {} frames at {:#x}", count, frames);
+    Ok(())
+}
+
+// for ram hotplugged at [start, end), every frame starts out free
+pub(crate) fn add_section(start: PhysAddr, end: PhysAddr) -> ResultWithErr<MmError> {
+    let (first, count) = (start.ppn(), end.as_usize().div_ceil(FRAME_SIZE) - start.ppn());
+    let _lock = SECTIONS_LOCK.lock();
+    if let Some(section) = SECTIONS.iter().find(|section| section.range() == Some(first..first + count)) {
+        for pfn in first..first + count {
+            section.get(pfn).unwrap().reset();
+        }
+        return Ok(());
+    }
+    let overlaps = |section: &Section| section.range()
+        .is_some_and(|range| range.start < first + count && first < range.end);
+    if overlaps(&BOOT) || SECTIONS.iter().any(overlaps) {
+        mork_kernel_log!(warn, "frame info for pfn {:#x}..{:#x} overlaps another section", first, first + count);
+        return Err(MmError::AlreadyMapped);
+    }
+    let Some(section) = SECTIONS.iter().find(|section| section.range().is_none()) else {
+        mork_kernel_log!(warn, "no frame info section left for pfn {:#x}..{:#x}", first, first + count);
+        return Err(MmError::OutOfMemory);
+    };
+    let frames = vmalloc::vmalloc(count * size_of::<FrameInfo>()).ok_or(MmError::OutOfMemory)?;
+    section.publish(frames, first, count);
+    mork_kernel_log!(info, "frame info for {} hotplugged frames at {:#x}", count, frames);
+    Ok(())
+}
+
+pub fn frame_info(pfn: usize) -> Option<&'static FrameInfo> {
+    BOOT.get(pfn).or_else(|| SECTIONS.iter().find_map(|section| section.get(pfn)))
+}
+
+// pfns the arrays cover, the ones in between have no frame info. empty before init
+pub fn pfn_range() -> Range<usize> {
+    BOOT.range().into_iter().chain(SECTIONS.iter().filter_map(Section::range))
+        .reduce(|a, b| a.start.min(b.start)..a.end.max(b.end))
+        .unwrap_or(0..0)
+}
+
+// pfn of a kernel window address, which is what the frame allocator hands out
+pub fn pfn(paddr: usize) -> Option<usize> {
+    virt_to_phys(VirtAddr::new(paddr)).map(|paddr| paddr.ppn())
+}
+
+pub(crate) fn mark_allocated(paddr: usize, size: usize, flags: FrameFlags) {
+    for page in (paddr..paddr + size).step_by(FRAME_SIZE) {
+        if let Some(info) = pfn(page).and_then(frame_info) {
+            info.refcount.store(1, Ordering::Relaxed);
+            info.insert_flags(FrameFlags::ALLOCATED | flags);
+        }
+    }
+}
+
+pub(crate) fn mark_free(paddr: usize, size: usize) {
+    for page in (paddr..paddr + size).step_by(FRAME_SIZE) {
+        if let Some(info) = pfn(page).and_then(frame_info) {
+            info.reset();
+        }
+    }
+}
+
+// another mapping shares the frame, through cow or merging
+pub(crate) fn get_shared(paddr: PhysAddr) {
+    if let Some(info) = shared_info(paddr) {
+        info.refcount.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+// drops the reference of a mapping that goes away, false if it was the last one and the caller
+// frees the frame as usual
+pub(crate) fn put_shared(paddr: PhysAddr) -> bool {
+    shared_info(paddr).is_some_and(|info| {
+        info.refcount.fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| (count > 1).then(|| count - 1))
+            .is_ok()
+    })
+}
+
+// whether the mapping at hand is the only holder of the frame
+pub(crate) fn is_exclusive(paddr: PhysAddr) -> bool {
+    shared_info(paddr).is_some_and(|info| {
+        info.refcount.load(Ordering::Acquire) == 1 && info.mapcount.load(Ordering::Acquire) <= 1
+    })
+}
+
+// the zero page and frames outside of ram are never freed, so nobody counts their holders
+fn shared_info(paddr: PhysAddr) -> Option<&'static FrameInfo> {
+    frame_info(paddr.ppn()).filter(|info| !info.flags().contains(FrameFlags::ZERO_PAGE))
+}
+
+pub(crate) fn pin(paddr: PhysAddr) {
+    if let Some(info) = frame_info(paddr.ppn()) {
+        info.pincount.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+pub(crate) fn unpin(paddr: PhysAddr) {
+    if let Some(info) = frame_info(paddr.ppn()) {
+        let _ = info.pincount.fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| count.checked_sub(1));
+    }
+}
+
+pub(crate) fn is_pinned(pfn: usize) -> bool {
+    frame_info(pfn).is_some_and(|info| info.pincount.load(Ordering::Acquire) != 0)
+}
+
+// any frame of [paddr, paddr + size) reported as bad ram
+pub(crate) fn is_poisoned(paddr: usize, size: usize) -> bool {
+    (paddr..paddr + size).step_by(FRAME_SIZE)
+        .filter_map(|page| pfn(page).and_then(frame_info))
+        .any(|info| info.flags().contains(FrameFlags::POISONED))
+}
@@ -0,0 +1,36 @@
+use lazy_init::LazyInit;
+use mork_common::mork_kernel_log;
+
+// the hal has no way to name the current hart or to interrupt another one, the kernel does both
+pub trait HartOps: Send + Sync {
+    fn hart_id(&self) -> usize;
+
+    // raises the ipi on every hart in hart_mask, whose handler calls shootdown::handle_ipi
+    fn send_ipi(&self, hart_mask: usize);
+}
+
+static HART_OPS: LazyInit<&'static dyn HartOps> = LazyInit::new();
+
+// must be called once before init
+pub fn set_hart_ops(ops: &'static dyn HartOps) {
+    if HART_OPS.is_init() {
+        mork_kernel_log!(warn, "hart ops are set already");
+        return;
+    }
+    HART_OPS.init_by(ops);
+}
+
+pub(crate) fn is_registered() -> bool {
+    HART_OPS.is_init()
+}
+
+// only the boot hart runs before the kernel registers its ops
+pub(crate) fn hart_id() -> usize {
+    if HART_OPS.is_init() { HART_OPS.hart_id() } else { 0 }
+}
+
+pub(crate) fn send_ipi(hart_mask: usize) {
+    if HART_OPS.is_init() {
+        HART_OPS.send_ipi(hart_mask);
+    }
+}
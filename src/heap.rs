@@ -1,35 +1,489 @@
 
 
+use alloc::vec::Vec;
 use core::alloc::{GlobalAlloc, Layout};
-use core::ptr::NonNull;
-use buddy_system_allocator::Heap;
-use spin::mutex::Mutex;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use mork_common::mork_kernel_log;
+use mork_common::types::ResultWithErr;
+#[cfg(not(any(feature = "heap-linked-list", feature = "heap-tlsf")))]
+use crate::buddy::BuddyHeap;
+use crate::error::MmError;
+#[cfg(feature = "heap-linked-list")]
+use crate::linked_list::LinkedListHeap;
+#[cfg(feature = "heap-tlsf")]
+use crate::tlsf::TlsfHeap;
+use crate::frame::{self, Zone, FRAME_SIZE};
+#[cfg(feature = "heap-poison")]
+use crate::heap_poison;
+#[cfg(feature = "kasan")]
+use crate::kasan;
+#[cfg(feature = "heap-redzone")]
+use crate::heap_redzone;
+#[cfg(feature = "heap-track")]
+use crate::heap_track;
+#[cfg(feature = "heap-track")]
+pub use crate::heap_track::AllocTagGuard;
+use crate::{coloring, heap_cache, kernel_template, oom, vmalloc};
+use crate::sync::IrqMutex;
+use crate::zoned::ZonedHeap;
 
-const ORDER: usize = 32;
+// capacity of the buddy free lists
+pub const ORDER: usize = 32;
 
-static HEAP: Mutex<Heap<ORDER>> = Mutex::new(Heap::empty());
+// what the global allocator needs from a heap implementation
+pub(crate) trait HeapBackend: Send {
+    // [start, end) must be unused memory owned by the heap from now on
+    unsafe fn add_to_heap(&mut self, start: usize, end: usize);
+    // takes [start, end) out of the heap again if all of it is free, rounded like add_to_heap rounded it
+    unsafe fn remove_region(&mut self, start: usize, end: usize) -> bool;
+    // only before the first add_to_heap, backends without block orders ignore it
+    fn set_max_block(&mut self, _size: usize) {}
+    fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, ()>;
+    fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout);
+    // resizes the block without moving it, on false nothing changed and the caller has to copy
+    fn grow_in_place(&mut self, _ptr: NonNull<u8>, _layout: Layout, _new_size: usize) -> bool {
+        false
+    }
+    fn total_bytes(&self) -> usize;
+    // bytes handed out, including the rounding of the backend
+    fn allocated_bytes(&self) -> usize;
+    // bytes the callers asked for
+    fn requested_bytes(&self) -> usize;
+    fn largest_free_block(&self) -> usize;
+    // counts[i] grows by the free blocks of [2^i, 2^(i+1)) bytes, walks everything, not for hot paths
+    fn count_free_blocks(&self, counts: &mut [usize]);
+}
+
+#[cfg(not(any(feature = "heap-linked-list", feature = "heap-tlsf")))]
+type Backend = BuddyHeap<ORDER>;
+#[cfg(feature = "heap-linked-list")]
+type Backend = LinkedListHeap;
+#[cfg(feature = "heap-tlsf")]
+type Backend = TlsfHeap;
+
+// interrupt handlers allocate too
+static HEAP: IrqMutex<ZonedHeap<Backend>> = IrqMutex::new(ZonedHeap::new(Backend::empty(), Backend::empty()));
+
+// filled in by the kernel from its configuration, only before mm init
+#[derive(Clone, Copy, Debug)]
+pub struct HeapConfig {
+    // largest block is 2^max_order bytes for backends that can limit their blocks, the buddy crate can not
+    pub max_order: usize,
+    // free memory the heap gets at boot, None hands it all to the heap
+    pub initial_size: Option<usize>,
+    // the rest is held back in a reserve, the heap grows into it by at least this many bytes at a time
+    pub grow_step: usize,
+}
+
+impl Default for HeapConfig {
+    fn default() -> Self {
+        HeapConfig { max_order: ORDER - 1, initial_size: None, grow_step: 4 << 20 }
+    }
+}
+
+static CONFIG: IrqMutex<Option<HeapConfig>> = IrqMutex::new(None);
+static SEALED: AtomicBool = AtomicBool::new(false);
+// boot memory the heap may still take before the rest goes to the reserve
+static BOOT_BUDGET: AtomicUsize = AtomicUsize::new(usize::MAX);
+static GROW_STEP: AtomicUsize = AtomicUsize::new(0);
+// free ranges inside the kernel window that were held back from the heap
+static RESERVE: IrqMutex<Vec<(usize, usize)>> = IrqMutex::new(Vec::new());
+static RESERVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+// allocations of at least this size are backed by vmalloc instead of one contiguous buddy block
+static LARGE_THRESHOLD: AtomicUsize = AtomicUsize::new(FRAME_SIZE);
+
+#[derive(Clone, Debug)]
+pub struct HeapStats {
+    pub total: usize,
+    // block bytes handed out, rounding included
+    pub allocated: usize,
+    // bytes the callers asked for, small allocations count at their cache class size
+    pub requested: usize,
+    pub free: usize,
+    // allocated bytes parked in the per-hart caches, free for the owning hart
+    pub cached: usize,
+    pub largest_free_block: usize,
+    // held back free memory the heap has not grown into yet
+    pub reserve: usize,
+    // free_blocks[i] counts the free blocks of [2^i, 2^(i+1)) bytes
+    pub free_blocks: [usize; ORDER],
+}
+
+pub fn configure(config: HeapConfig) -> ResultWithErr<MmError> {
+    if SEALED.load(Ordering::Acquire) {
+        mork_kernel_log!(warn, "heap configuration can not change after mm init");
+        return Err(MmError::InvalidParam);
+    }
+    if config.max_order < FRAME_SIZE.trailing_zeros() as usize || config.max_order >= ORDER
+        || config.initial_size.is_some_and(|size| size < FRAME_SIZE) || config.grow_step < FRAME_SIZE {
+        mork_kernel_log!(warn, "invalid heap configuration {:?}", config);
+        return Err(MmError::InvalidParam);
+    }
+    *CONFIG.lock() = Some(config);
+    Ok(())
+}
+
+pub(crate) fn seal_config() {
+    SEALED.store(true, Ordering::Release);
+    let config = CONFIG.lock().unwrap_or_default();
+    HEAP.lock().set_max_block(1 << config.max_order);
+    BOOT_BUDGET.store(config.initial_size.unwrap_or(usize::MAX), Ordering::Relaxed);
+    GROW_STEP.store(config.grow_step, Ordering::Relaxed);
+    mork_kernel_log!(info, "heap config: {:?}", config);
+}
+
+// boot memory fills the heap up to the configured initial size, the rest goes to the reserve
+pub(crate) fn add_boot_region(start: usize, end: usize) -> ResultWithErr<MmError> {
+    let budget = BOOT_BUDGET.load(Ordering::Relaxed);
+    let split = start.saturating_add(budget).min(end);
+    if split > start {
+        add_region(start, split)?;
+        BOOT_BUDGET.store(budget - (split - start), Ordering::Relaxed);
+    }
+    if split < end {
+        // the initial size is at least a frame, so the heap can already hold the reserve list
+        RESERVE.lock().push((split, end));
+        RESERVE_BYTES.fetch_add(end - split, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+pub(crate) fn reserve_bytes() -> usize {
+    RESERVE_BYTES.load(Ordering::Relaxed)
+}
+
+// moves the next piece of the reserve into the heap, false once there is nothing left to take
+fn grow(layout: Layout) -> bool {
+    // an aligned block of the rounded size fits in any range of twice that size
+    let want = GROW_STEP.load(Ordering::Relaxed).max(2 * layout.size().max(layout.align()).next_power_of_two());
+    // the reserve list itself allocates while locked, that allocation must not wait on it
+    let Some(mut reserve) = RESERVE.try_lock() else {
+        return false;
+    };
+    let Some(range) = reserve.last_mut() else {
+        return false;
+    };
+    let start = range.0;
+    let end = range.1.min(start.saturating_add(want));
+    if end == range.1 {
+        reserve.pop();
+    } else {
+        range.0 = end;
+    }
+    drop(reserve);
+    RESERVE_BYTES.fetch_sub(end - start, Ordering::Relaxed);
+    mork_kernel_log!(debug, "heap grows by {:#x}..{:#x}", start, end);
+    extend(start, end - start).is_ok()
+}
 
-pub fn init(free_mem_start: usize, free_mem_end: usize) {
-    mork_kernel_log!(debug, "start: {:#x}, end: {:#x}", free_mem_start, free_mem_end);
+// regions may be discontiguous, each one must be unused memory inside the kernel window
+pub fn add_region(start: usize, end: usize) -> ResultWithErr<MmError> {
+    mork_kernel_log!(debug, "start: {:#x}, end: {:#x}", start, end);
+    if end <= start {
+        mork_kernel_log!(warn, "invalid heap range, start: {:#x}, end: {:#x}", start, end);
+        return Err(MmError::InvalidAddress);
+    }
     unsafe {
-        HEAP.lock().init(free_mem_start, free_mem_end - free_mem_start);
+        HEAP.lock().add_to_heap(start, end);
+    }
+    Ok(())
+}
+
+// takes a region given to add_region out of the heap again if all of it is free
+pub(crate) fn remove_region(start: usize, end: usize) -> bool {
+    flush_caches();
+    unsafe { HEAP.lock().remove_region(start, end) }
+}
+
+// regions added by extend, the only ones shrink may hand back
+static EXTENDED: IrqMutex<Vec<(usize, usize)>> = IrqMutex::new(Vec::new());
+
+// grows the heap at runtime, start and len follow add_region
+pub fn extend(start: usize, len: usize) -> ResultWithErr<MmError> {
+    let end = start.checked_add(len).ok_or(MmError::InvalidAddress)?;
+    add_region(start, end)?;
+    EXTENDED.lock().push((start, end));
+    Ok(())
+}
+
+// best effort, returns the extended regions that were entirely free and are no longer part of the heap
+// the heap is the frame allocator too, so the caller decides where the memory goes next
+pub fn shrink() -> Vec<(usize, usize)> {
+    flush_caches();
+    let mut extended = EXTENDED.lock();
+    // the most recent regions sit on top of the heap and are the least likely to be in use
+    extended.sort_unstable_by_key(|(start, _)| usize::MAX - start);
+    let mut released = Vec::new();
+    extended.retain(|&(start, end)| {
+        let removed = unsafe { HEAP.lock().remove_region(start, end) };
+        if removed {
+            released.push((start, end));
+        }
+        !removed
+    });
+    for (start, end) in released.iter() {
+        mork_kernel_log!(info, "heap shrunk by {:#x}..{:#x}", start, end);
+    }
+    released
+}
+
+// usize::MAX keeps every allocation in the buddy heap
+pub fn set_large_threshold(bytes: usize) {
+    LARGE_THRESHOLD.store(bytes, Ordering::Relaxed);
+}
+
+// (total, allocated) bytes, cheap enough for the frame allocator hot path
+pub(crate) fn usage() -> (usize, usize) {
+    let heap = HEAP.lock();
+    (heap.total_bytes(), heap.allocated_bytes())
+}
+
+pub(crate) fn zone_usage(zone: Zone) -> (usize, usize) {
+    HEAP.lock().zone_usage(zone)
+}
+
+pub fn stats() -> HeapStats {
+    let cached = heap_cache::cached_bytes();
+    let heap = HEAP.lock();
+    HeapStats {
+        total: heap.total_bytes(),
+        allocated: heap.allocated_bytes(),
+        requested: heap.requested_bytes(),
+        free: heap.total_bytes() - heap.allocated_bytes(),
+        cached,
+        largest_free_block: heap.largest_free_block(),
+        reserve: reserve_bytes(),
+        free_blocks: {
+            let mut counts = [0; ORDER];
+            heap.count_free_blocks(&mut counts);
+            counts
+        },
     }
 }
 
+pub(crate) fn largest_free_block() -> usize {
+    HEAP.lock().largest_free_block()
+}
+
+// for the timer tick or an idle hart, cached blocks can not merge with their buddies
+pub fn flush_caches() {
+    frame::drain_zeroed();
+    coloring::drain();
+    #[cfg(feature = "heap-poison")]
+    heap_poison::drain(release);
+    heap_cache::flush(&HEAP);
+}
+
+pub fn log_fragmentation() {
+    let stats = stats();
+    // share of the free bytes that a single largest block request cannot use
+    let fragmentation = 100 - (stats.largest_free_block * 100).checked_div(stats.free).unwrap_or(100);
+    mork_kernel_log!(info, "heap total: {:#x}, allocated: {:#x}, requested: {:#x}, free: {:#x}, cached: {:#x}, reserve: {:#x}, largest free block: {:#x}, fragmentation: {}%",
+        stats.total, stats.allocated, stats.requested, stats.free, stats.cached, stats.reserve, stats.largest_free_block,
+        fragmentation);
+    for (order, count) in stats.free_blocks.iter().enumerate().filter(|(_, count)| **count != 0) {
+        mork_kernel_log!(info, "  order {:>2} ({:#x} bytes): {} free", order, 1usize << order, count);
+    }
+}
+
+// e.g. let _tag = heap::tag_allocations("page table");
+#[cfg(feature = "heap-track")]
+pub fn tag_allocations(tag: &'static str) -> AllocTagGuard {
+    heap_track::tag_allocations(tag)
+}
+
+// logs every live global allocator allocation grouped by tag, frames are not tracked
+#[cfg(feature = "heap-track")]
+pub fn dump_outstanding() {
+    heap_track::dump_outstanding();
+}
+
+#[cfg(feature = "alloc-error-handler")]
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    let (total, used) = usage();
+    mork_kernel_log!(error, "fail to allocate {:#x} bytes aligned to {:#x}, heap used: {:#x}/{:#x}, largest free block: {:#x}",
+        layout.size(), layout.align(), used, total, largest_free_block());
+    panic!("kernel heap exhausted");
+}
+
 struct Global;
 
-#[global_allocator]
+#[cfg_attr(not(test), global_allocator)]
 static GLOBAL: Global = Global;
 
 unsafe impl GlobalAlloc for Global {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        HEAP.lock().alloc(layout).ok()
-            .map_or(0 as *mut u8, |allocation| allocation.as_ptr())
+        let allocation = alloc_checked(layout, false);
+        #[cfg(feature = "heap-track")]
+        if let Some(allocation) = allocation {
+            heap_track::record(allocation, layout.size());
+        }
+        allocation.map_or(ptr::null_mut(), |allocation| allocation.as_ptr())
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let allocation = alloc_checked(layout, true);
+        #[cfg(feature = "heap-track")]
+        if let Some(allocation) = allocation {
+            heap_track::record(allocation, layout.size());
+        }
+        allocation.map_or(ptr::null_mut(), |allocation| allocation.as_ptr())
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if resize_checked(ptr, layout, new_size) {
+            #[cfg(feature = "heap-track")]
+            heap_track::record(unsafe { NonNull::new_unchecked(ptr) }, new_size);
+            return ptr;
+        }
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        let new_ptr = unsafe { self.alloc(new_layout) };
+        if !new_ptr.is_null() {
+            unsafe {
+                ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+        }
+        new_ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        HEAP.lock().dealloc(unsafe { NonNull::new_unchecked(ptr) }, layout);
+        #[cfg(feature = "heap-track")]
+        heap_track::forget(unsafe { NonNull::new_unchecked(ptr) });
+        #[cfg(feature = "heap-redzone")]
+        let (ptr, layout) = heap_redzone::check(ptr, layout);
+        if vmalloc::is_vmalloc_addr(ptr as usize) {
+            unsafe { vmalloc::vfree(ptr as usize); }
+            return;
+        }
+        dealloc_contiguous(unsafe { NonNull::new_unchecked(ptr) }, layout);
+    }
+}
+
+// vmalloc needs the kernel page table, and can not give more than page alignment
+fn is_large(layout: Layout) -> bool {
+    layout.size() >= LARGE_THRESHOLD.load(Ordering::Relaxed) && layout.align() <= FRAME_SIZE
+        && kernel_template::is_initialized()
+}
+
+#[cfg(not(feature = "heap-redzone"))]
+fn alloc_checked(layout: Layout, zeroed: bool) -> Option<NonNull<u8>> {
+    alloc_raw(layout, zeroed)
+}
+
+// the backend sees the padded layout, the caller only the bytes between the redzones
+#[cfg(feature = "heap-redzone")]
+fn alloc_checked(layout: Layout, zeroed: bool) -> Option<NonNull<u8>> {
+    heap_redzone::alloc(layout, |padded| alloc_raw(padded, zeroed))
+}
+
+#[cfg(not(feature = "heap-redzone"))]
+fn resize_checked(ptr: *mut u8, layout: Layout, new_size: usize) -> bool {
+    resize_in_place(ptr, layout, new_size)
+}
+
+#[cfg(feature = "heap-redzone")]
+fn resize_checked(ptr: *mut u8, layout: Layout, new_size: usize) -> bool {
+    heap_redzone::resize(ptr, layout, new_size, resize_in_place)
+}
+
+fn alloc_raw(layout: Layout, zeroed: bool) -> Option<NonNull<u8>> {
+    // vmalloc areas are built from zeroed frames already
+    alloc_large(layout).or_else(|| {
+        let allocation = alloc_contiguous(layout)?;
+        if zeroed {
+            unsafe {
+                ptr::write_bytes(allocation.as_ptr(), 0, layout.size());
+            }
+        }
+        Some(allocation)
+    })
+}
+
+fn alloc_large(layout: Layout) -> Option<NonNull<u8>> {
+    is_large(layout)
+        .then(|| vmalloc::try_vmalloc(layout.size()))
+        .flatten()
+        .and_then(|vaddr| NonNull::new(vaddr as *mut u8))
+}
+
+// growing vecs would otherwise copy on every doubling
+fn resize_in_place(ptr: *mut u8, layout: Layout, new_size: usize) -> bool {
+    if vmalloc::is_vmalloc_addr(ptr as usize) {
+        // the tail of the last page is already mapped
+        return vmalloc::try_area_len(ptr as usize).is_some_and(|len| new_size <= len);
+    }
+    let Ok(new_layout) = Layout::from_size_align(new_size, layout.align()) else {
+        return false;
+    };
+    match (heap_cache::class_of(layout), heap_cache::class_of(new_layout)) {
+        // cached blocks stay at their class size
+        (Some(class), Some(new_class)) => class == new_class,
+        (None, None) => HEAP.lock().grow_in_place(unsafe { NonNull::new_unchecked(ptr) }, layout, new_size),
+        _ => false,
+    }
+}
+
+// a single block inside the kernel window, which is what the frame allocator hands out
+pub(crate) fn alloc_contiguous(layout: Layout) -> Option<NonNull<u8>> {
+    alloc_contiguous_in(Zone::Normal, layout)
+}
+
+pub(crate) fn alloc_contiguous_in(zone: Zone, layout: Layout) -> Option<NonNull<u8>> {
+    // cached blocks of other classes may merge into what is missing
+    let allocation = try_alloc(zone, layout).or_else(|| {
+        flush_caches();
+        try_alloc(zone, layout)
+    });
+    let allocation = allocation.or_else(|| {
+        while grow(layout) {
+            if let Some(allocation) = try_alloc(zone, layout) {
+                return Some(allocation);
+            }
+        }
+        None
+    });
+    // the heap lock is released before the handler runs, it may free memory itself
+    allocation.or_else(|| {
+        oom::handle(layout).then(|| try_alloc(zone, layout)).flatten()
+    })
+}
+
+pub(crate) fn dealloc_contiguous(ptr: NonNull<u8>, layout: Layout) {
+    #[cfg(feature = "kasan")]
+    kasan::poison(ptr.as_ptr() as usize, layout.size(), kasan::HEAP_FREED);
+    // the block that leaves the quarantine is freed instead
+    #[cfg(feature = "heap-poison")]
+    let Some((ptr, layout)) = heap_poison::quarantine(ptr, layout) else {
         return;
+    };
+    release(ptr, layout);
+}
+
+fn release(ptr: NonNull<u8>, layout: Layout) {
+    match heap_cache::class_of(layout) {
+        Some(class) => heap_cache::dealloc(&HEAP, class, ptr),
+        None => HEAP.lock().dealloc(ptr, layout),
+    }
+}
+
+// for background work, never flushes, grows the heap or wakes the oom handler
+pub(crate) fn try_alloc_contiguous(layout: Layout) -> Option<NonNull<u8>> {
+    try_alloc(Zone::Normal, layout)
+}
+
+// the hart caches hold blocks of any zone, so only normal allocations go through them
+fn try_alloc(zone: Zone, layout: Layout) -> Option<NonNull<u8>> {
+    let allocation = match (zone, heap_cache::class_of(layout)) {
+        (Zone::Normal, Some(class)) => heap_cache::alloc(&HEAP, class),
+        _ => HEAP.lock().alloc_in(zone, layout).ok(),
+    };
+    #[cfg(feature = "kasan")]
+    if let Some(allocation) = allocation {
+        kasan::unpoison(allocation.as_ptr() as usize, layout.size());
     }
+    allocation
 }
\ No newline at end of file
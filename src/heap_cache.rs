@@ -0,0 +1,128 @@
+use core::alloc::Layout;
+use core::ptr::{self, NonNull};
+use crate::hart;
+use crate::heap::HeapBackend;
+use crate::sync::IrqMutex;
+
+// blocks from a word up to 2^MAX_SHIFT bytes are cached per hart
+const MIN_SHIFT: usize = size_of::<usize>().trailing_zeros() as usize;
+const MAX_SHIFT: usize = 9;
+const CLASSES: usize = MAX_SHIFT - MIN_SHIFT + 1;
+// a class above HIGH blocks is trimmed to HIGH / 2, a miss refills BATCH blocks under one heap lock
+const HIGH: usize = 32;
+const BATCH: usize = 8;
+// hart masks are a usize
+const MAX_HARTS: usize = usize::BITS as usize;
+
+// intrusive like the buddy free lists
+struct ClassCache {
+    head: *mut usize,
+    len: usize,
+}
+
+unsafe impl Send for ClassCache {}
+
+impl ClassCache {
+    const fn new() -> Self {
+        Self { head: ptr::null_mut(), len: 0 }
+    }
+
+    fn push(&mut self, block: *mut usize) {
+        unsafe {
+            *block = self.head as usize;
+        }
+        self.head = block;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<*mut usize> {
+        if self.head.is_null() {
+            return None;
+        }
+        let block = self.head;
+        self.head = unsafe { *block as *mut usize };
+        self.len -= 1;
+        Some(block)
+    }
+}
+
+struct HartCache {
+    classes: [ClassCache; CLASSES],
+}
+
+// only the owning hart touches its cache on the hot path, the lock is for flushes from other harts.
+// lock order: a hart cache, then the heap
+static CACHES: [IrqMutex<HartCache>; MAX_HARTS] =
+    [const { IrqMutex::new(HartCache { classes: [const { ClassCache::new() }; CLASSES] }) }; MAX_HARTS];
+
+// cached layouts always reach the heap at their class size, whatever the caller asked for
+pub(crate) fn class_of(layout: Layout) -> Option<usize> {
+    let size = layout.size().next_power_of_two().max(layout.align()).max(size_of::<usize>());
+    let shift = size.trailing_zeros() as usize;
+    (shift <= MAX_SHIFT).then(|| shift - MIN_SHIFT)
+}
+
+fn class_layout(class: usize) -> Layout {
+    let size = 1 << (class + MIN_SHIFT);
+    Layout::from_size_align(size, size).unwrap()
+}
+
+pub(crate) fn alloc<H: HeapBackend>(heap: &IrqMutex<H>, class: usize) -> Option<NonNull<u8>> {
+    let Some(cache) = CACHES.get(hart::hart_id()) else {
+        return heap.lock().alloc(class_layout(class)).ok();
+    };
+    let mut cache = cache.lock();
+    let list = &mut cache.classes[class];
+    if list.len == 0 {
+        let mut heap = heap.lock();
+        for _ in 0..BATCH {
+            let Ok(block) = heap.alloc(class_layout(class)) else {
+                break;
+            };
+            list.push(block.as_ptr() as *mut usize);
+        }
+    }
+    list.pop().map(|block| NonNull::new(block as *mut u8).unwrap())
+}
+
+pub(crate) fn dealloc<H: HeapBackend>(heap: &IrqMutex<H>, class: usize, ptr: NonNull<u8>) {
+    let Some(cache) = CACHES.get(hart::hart_id()) else {
+        heap.lock().dealloc(ptr, class_layout(class));
+        return;
+    };
+    let mut cache = cache.lock();
+    let list = &mut cache.classes[class];
+    list.push(ptr.as_ptr() as *mut usize);
+    if list.len > HIGH {
+        trim(heap, class, list, HIGH / 2);
+    }
+}
+
+// hands every cached block back, so the buddy heap can merge them again
+pub(crate) fn flush<H: HeapBackend>(heap: &IrqMutex<H>) {
+    for cache in &CACHES {
+        let mut cache = cache.lock();
+        for (class, list) in cache.classes.iter_mut().enumerate() {
+            trim(heap, class, list, 0);
+        }
+    }
+}
+
+pub(crate) fn cached_bytes() -> usize {
+    CACHES.iter()
+        .map(|cache| cache.lock().classes.iter().enumerate()
+            .map(|(class, list)| list.len << (class + MIN_SHIFT))
+            .sum::<usize>())
+        .sum()
+}
+
+fn trim<H: HeapBackend>(heap: &IrqMutex<H>, class: usize, list: &mut ClassCache, keep: usize) {
+    if list.len <= keep {
+        return;
+    }
+    let mut heap = heap.lock();
+    while list.len > keep {
+        let block = list.pop().unwrap();
+        heap.dealloc(NonNull::new(block as *mut u8).unwrap(), class_layout(class));
+    }
+}
@@ -0,0 +1,53 @@
+use core::alloc::Layout;
+use core::ptr::{self, NonNull};
+use core::slice;
+use crate::sync::IrqMutex;
+
+// freed blocks are filled with POISON and parked in a ring before the heap may hand them out again
+const POISON: u8 = 0x6b;
+const RING: usize = 64;
+
+struct Quarantine {
+    blocks: [Option<(NonNull<u8>, Layout)>; RING],
+    next: usize,
+}
+
+unsafe impl Send for Quarantine {}
+
+static QUARANTINE: IrqMutex<Quarantine> = IrqMutex::new(Quarantine { blocks: [None; RING], next: 0 });
+
+// returns the oldest parked block, which is the one to really free now
+pub(crate) fn quarantine(ptr: NonNull<u8>, layout: Layout) -> Option<(NonNull<u8>, Layout)> {
+    let mut quarantine = QUARANTINE.lock();
+    if let Some((_, first)) = quarantine.blocks.iter().flatten().find(|(block, _)| *block == ptr) {
+        panic!("double free of {:#x} as {:?}, first freed as {:?}", ptr.as_ptr() as usize, layout, first);
+    }
+    unsafe {
+        ptr::write_bytes(ptr.as_ptr(), POISON, layout.size());
+    }
+    let next = quarantine.next;
+    quarantine.next = (next + 1) % RING;
+    let evicted = quarantine.blocks[next].replace((ptr, layout));
+    if let Some((block, layout)) = evicted {
+        check(block, layout);
+    }
+    evicted
+}
+
+// hands every parked block to release, e.g. when the heap runs short
+pub(crate) fn drain(mut release: impl FnMut(NonNull<u8>, Layout)) {
+    let blocks = core::mem::replace(&mut QUARANTINE.lock().blocks, [None; RING]);
+    for (block, layout) in blocks.into_iter().flatten() {
+        check(block, layout);
+        release(block, layout);
+    }
+}
+
+// any byte that lost the poison was written after the free
+fn check(block: NonNull<u8>, layout: Layout) {
+    let bytes = unsafe { slice::from_raw_parts(block.as_ptr(), layout.size()) };
+    if let Some(offset) = bytes.iter().position(|byte| *byte != POISON) {
+        panic!("use after free at {:#x}, offset {:#x} of the block {:#x} freed as {:?}, found {:#x}",
+            block.as_ptr() as usize + offset, offset, block.as_ptr() as usize, layout, bytes[offset]);
+    }
+}
@@ -0,0 +1,60 @@
+use core::alloc::Layout;
+use core::ptr::{self, NonNull};
+use core::slice;
+
+// canary bytes on both sides of every global allocator allocation, checked when it is freed
+const REDZONE: usize = 16;
+const CANARY: u8 = 0xcc;
+
+// the front redzone keeps the caller's alignment
+fn front(layout: Layout) -> usize {
+    layout.align().max(REDZONE)
+}
+
+fn padded(layout: Layout) -> Option<Layout> {
+    let size = front(layout).checked_add(layout.size())?.checked_add(REDZONE)?;
+    Layout::from_size_align(size, layout.align()).ok()
+}
+
+pub(crate) fn alloc(layout: Layout, alloc: impl FnOnce(Layout) -> Option<NonNull<u8>>) -> Option<NonNull<u8>> {
+    let front = front(layout);
+    let base = alloc(padded(layout)?)?.as_ptr();
+    unsafe {
+        ptr::write_bytes(base, CANARY, front);
+        ptr::write_bytes(base.add(front + layout.size()), CANARY, REDZONE);
+        NonNull::new(base.add(front))
+    }
+}
+
+// the back redzone moves along with the end of the allocation
+pub(crate) fn resize(ptr: *mut u8, layout: Layout, new_size: usize, resize: impl FnOnce(*mut u8, Layout, usize) -> bool)
+    -> bool {
+    let Some(new_padded) = Layout::from_size_align(new_size, layout.align()).ok().and_then(padded) else {
+        return false;
+    };
+    let (base, padded) = check(ptr, layout);
+    if !resize(base, padded, new_padded.size()) {
+        return false;
+    }
+    unsafe {
+        ptr::write_bytes(ptr.add(new_size), CANARY, REDZONE);
+    }
+    true
+}
+
+// returns the block and the layout the backend handed out
+pub(crate) fn check(ptr: *mut u8, layout: Layout) -> (*mut u8, Layout) {
+    let front = front(layout);
+    let padded = padded(layout).unwrap();
+    let base = unsafe { ptr.sub(front) };
+    let bytes = unsafe { slice::from_raw_parts(base, padded.size()) };
+    let (before, after) = (&bytes[..front], &bytes[front + layout.size()..]);
+    // offsets relative to the start of the allocation, negative ones are underruns
+    let offset = before.iter().rposition(|byte| *byte != CANARY).map(|index| index as isize - front as isize)
+        .or_else(|| after.iter().position(|byte| *byte != CANARY).map(|index| (layout.size() + index) as isize));
+    if let Some(offset) = offset {
+        panic!("heap redzone of {:#x} as {:?} overwritten at offset {}, found {:#x}",
+            ptr as usize, layout, offset, bytes[(front as isize + offset) as usize]);
+    }
+    (base, padded)
+}
@@ -0,0 +1,133 @@
+use alloc::vec::Vec;
+use core::ptr::NonNull;
+use mork_common::mork_kernel_log;
+use crate::hart;
+use crate::sync::IrqMutex;
+
+// fixed size, the table must not allocate from the heap it tracks
+const CAPACITY: usize = 4096;
+// hart masks are a usize
+const MAX_HARTS: usize = usize::BITS as usize;
+const UNTAGGED: &str = "untagged";
+
+#[derive(Clone, Copy)]
+struct Record {
+    addr: usize,
+    size: usize,
+    tag: &'static str,
+}
+
+// open addressing with linear probing, removals shift the probe chain back instead of leaving tombstones
+struct Table {
+    slots: [Option<Record>; CAPACITY],
+    len: usize,
+    // allocations that did not fit, the report is incomplete once this is not zero
+    dropped: usize,
+}
+
+static TABLE: IrqMutex<Table> = IrqMutex::new(Table { slots: [None; CAPACITY], len: 0, dropped: 0 });
+static TAGS: [IrqMutex<&'static str>; MAX_HARTS] = [const { IrqMutex::new(UNTAGGED) }; MAX_HARTS];
+
+// allocations on this hart carry the tag until the guard drops, guards nest
+pub struct AllocTagGuard {
+    previous: &'static str,
+}
+
+impl Drop for AllocTagGuard {
+    fn drop(&mut self) {
+        if let Some(tag) = TAGS.get(hart::hart_id()) {
+            *tag.lock() = self.previous;
+        }
+    }
+}
+
+pub(crate) fn tag_allocations(tag: &'static str) -> AllocTagGuard {
+    let previous = TAGS.get(hart::hart_id())
+        .map_or(UNTAGGED, |current| core::mem::replace(&mut *current.lock(), tag));
+    AllocTagGuard { previous }
+}
+
+fn current_tag() -> &'static str {
+    TAGS.get(hart::hart_id()).map_or(UNTAGGED, |tag| *tag.lock())
+}
+
+fn slot_of(addr: usize) -> usize {
+    // blocks are at least word aligned, the low bits carry nothing
+    (addr >> 3).wrapping_mul(0x9e37_79b9_7f4a_7c15) % CAPACITY
+}
+
+// also updates the size of an allocation that was resized in place
+pub(crate) fn record(ptr: NonNull<u8>, size: usize) {
+    let addr = ptr.as_ptr() as usize;
+    let tag = current_tag();
+    let mut table = TABLE.lock();
+    let mut slot = slot_of(addr);
+    for _ in 0..CAPACITY {
+        match table.slots[slot] {
+            Some(ref mut record) if record.addr == addr => {
+                *record = Record { addr, size, tag };
+                return;
+            }
+            Some(_) => slot = (slot + 1) % CAPACITY,
+            None => {
+                table.slots[slot] = Some(Record { addr, size, tag });
+                table.len += 1;
+                return;
+            }
+        }
+    }
+    table.dropped += 1;
+}
+
+pub(crate) fn forget(ptr: NonNull<u8>) {
+    let addr = ptr.as_ptr() as usize;
+    let mut table = TABLE.lock();
+    let mut hole = slot_of(addr);
+    loop {
+        match table.slots[hole] {
+            Some(record) if record.addr == addr => break,
+            Some(_) => hole = (hole + 1) % CAPACITY,
+            // one of the dropped ones, or allocated before tracking started
+            None => return,
+        }
+    }
+    table.len -= 1;
+    // move later entries of the chain into the hole unless that would put them before their home slot
+    let mut next = hole;
+    loop {
+        next = (next + 1) % CAPACITY;
+        let Some(record) = table.slots[next] else {
+            break;
+        };
+        let home = slot_of(record.addr);
+        let movable = if hole <= next { home <= hole || home > next } else { home <= hole && home > next };
+        if movable {
+            table.slots[hole] = Some(record);
+            hole = next;
+        }
+    }
+    table.slots[hole] = None;
+}
+
+pub(crate) fn dump_outstanding() {
+    // allocate before taking the lock, the copy itself must not allocate
+    let mut records = Vec::with_capacity(CAPACITY);
+    let dropped = {
+        let table = TABLE.lock();
+        records.extend(table.slots.iter().flatten().copied());
+        table.dropped
+    };
+    records.sort_unstable_by_key(|record| (record.tag, record.addr));
+    let bytes: usize = records.iter().map(|record| record.size).sum();
+    mork_kernel_log!(info, "{} outstanding heap allocations, {:#x} bytes", records.len(), bytes);
+    if dropped != 0 {
+        mork_kernel_log!(warn, "{} allocations were not tracked, the table is full", dropped);
+    }
+    for group in records.chunk_by(|a, b| a.tag == b.tag) {
+        let bytes: usize = group.iter().map(|record| record.size).sum();
+        mork_kernel_log!(info, "  {}: {} allocations, {:#x} bytes", group[0].tag, group.len(), bytes);
+        for record in group {
+            mork_kernel_log!(debug, "    {:#x}: {:#x} bytes", record.addr, record.size);
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use alloc::vec::Vec;
+use spin::mutex::Mutex;
+use mork_common::mork_kernel_log;
+use mork_common::types::ResultWithErr;
+use crate::addr::{self, phys_to_virt, PhysAddr};
+use crate::attributes::MapAttributes;
+use crate::error::MmError;
+use crate::frame::FRAME_SIZE;
+use crate::frame_info::{self, FrameFlags};
+use crate::memory::{self, MemoryRegion};
+use crate::page_table::MutPageTableWrapper;
+use crate::shootdown::online_harts;
+use crate::{compaction, heap, kernel_layout, kernel_template};
+
+// ram added at runtime and not removed yet, the only ranges that can go again.
+// the lock also keeps adds and removes apart
+static HOTPLUGGED: Mutex<Vec<MemoryRegion>> = Mutex::new(Vec::new());
+
+pub(crate) fn add(start: PhysAddr, len: usize) -> ResultWithErr<MmError> {
+    let end = check_range(start, len)?;
+    if !kernel_template::is_initialized() {
+        mork_kernel_log!(warn, "ram hotplugged before mm init, {:#x}", start.as_usize());
+        return Err(MmError::InvalidParam);
+    }
+    let mut hotplugged = HOTPLUGGED.lock();
+    if memory::overlaps_ram(start, len) || memory::is_reserved(start, len) {
+        mork_kernel_log!(warn, "hotplugged ram {:#x}..{:#x} is known already", start.as_usize(), end.as_usize());
+        return Err(MmError::AlreadyMapped);
+    }
+    let (vstart, vend) = (phys_to_virt(start).as_usize(), phys_to_virt(end).as_usize());
+    kernel_layout::extend_direct_map(vend)?;
+    addr::extend_window(vend);
+    let mut wrapper = MutPageTableWrapper::new(kernel_template::kernel_root()).with_harts(online_harts());
+    let attrs = MapAttributes::READ | MapAttributes::WRITE | MapAttributes::GLOBAL | MapAttributes::ACCESSED
+        | MapAttributes::DIRTY;
+    let result = wrapper.map_kernel_region(vstart, vend, attrs)
+        .and_then(|_| frame_info::add_section(start, end));
+    if let Err(err) = result {
+        let _ = wrapper.unmap_kernel_region(vstart, vend);
+        return Err(err);
+    }
+    // the range may need root entries that the user roots do not have yet
+    kernel_template::sync_kernel_mappings();
+    memory::add_ram_region(start, end);
+    heap::add_region(vstart, vend)?;
+    hotplugged.push(MemoryRegion { start, end });
+    mork_kernel_log!(info, "hotplugged {} KiB of ram at {:#x}", len >> 10, start.as_usize());
+    Ok(())
+}
+
+// best effort, user frames move out of the range first. anything else still using it,
+// e.g. a page table or a kernel object, keeps the whole range online
+pub(crate) fn remove(start: PhysAddr, len: usize) -> ResultWithErr<MmError> {
+    let end = check_range(start, len)?;
+    let mut hotplugged = HOTPLUGGED.lock();
+    let Some(index) = hotplugged.iter().position(|region| region.start == start && region.end == end) else {
+        mork_kernel_log!(warn, "only hotplugged ram can be removed, {:#x}..{:#x}", start.as_usize(), end.as_usize());
+        return Err(MmError::InvalidParam);
+    };
+    let pfns = start.ppn()..end.ppn();
+    let (vstart, vend) = (phys_to_virt(start).as_usize(), phys_to_virt(end).as_usize());
+    if compaction::movable_frames(pfns.clone()).is_none() || !compaction::evacuate(pfns.clone())
+        || !heap::remove_region(vstart, vend) {
+        mork_kernel_log!(warn, "hotplugged ram {:#x}..{:#x} is still in use", start.as_usize(), end.as_usize());
+        return Err(MmError::InUse);
+    }
+    // stale lookups see the frames as reserved until the range comes back
+    for pfn in pfns {
+        frame_info::frame_info(pfn).unwrap().insert_flags(FrameFlags::RESERVED);
+    }
+    memory::remove_ram_region(start, end);
+    hotplugged.swap_remove(index);
+    mork_kernel_log!(info, "removed {} KiB of hotplugged ram at {:#x}", len >> 10, start.as_usize());
+    MutPageTableWrapper::new(kernel_template::kernel_root()).with_harts(online_harts())
+        .unmap_kernel_region(vstart, vend)
+}
+
+fn check_range(start: PhysAddr, len: usize) -> Result<PhysAddr, MmError> {
+    if !start.as_usize().is_multiple_of(FRAME_SIZE) || !len.is_multiple_of(FRAME_SIZE) {
+        mork_kernel_log!(warn, "hotplugged ram must be frame aligned, {:#x}, {:#x}", start.as_usize(), len);
+        return Err(MmError::AlignmentError);
+    }
+    let end = start.as_usize().checked_add(len).filter(|_| len != 0).ok_or(MmError::InvalidParam)?;
+    Ok(PhysAddr::new(end))
+}
@@ -0,0 +1,132 @@
+use mork_common::mork_kernel_log;
+use mork_common::types::ResultWithErr;
+use mork_common::utils::alignas::is_aligned;
+use mork_hal::config::HAL_PAGE_LEVEL;
+use mork_hal::mm::PageTableImpl;
+use crate::addr::PhysAddr;
+use crate::attributes::MapAttributes;
+use crate::error::MmError;
+use crate::frame::FRAME_SIZE;
+use crate::page_table::{check_frame, next_table, OwnedPageTable, PageTable, PTE_COUNT};
+
+// the whole sv39 range is available to the device, there is no kernel half
+pub const IOVA_END: usize = 1 << (12 + 9 * HAL_PAGE_LEVEL);
+
+// first stage table of a riscv iommu device context, same pte format as the cpu tables.
+// the caller invalidates the iotlb after unmapping, mork-mm has no access to the command queue
+pub struct IoPageTable {
+    root: OwnedPageTable,
+}
+
+impl IoPageTable {
+    pub fn new() -> Option<Self> {
+        Some(Self { root: PageTable::alloc_owned()? })
+    }
+
+    // goes into the fsc field of the device context
+    pub fn root_paddr(&self) -> PhysAddr {
+        self.root.paddr()
+    }
+
+    pub fn map(&mut self, iova: usize, paddr: PhysAddr, len: usize, attrs: MapAttributes) -> ResultWithErr<MmError> {
+        check_iova(iova, len)?;
+        if !is_aligned(paddr.as_usize(), FRAME_SIZE) {
+            mork_kernel_log!(warn, "paddr must be aligned, {:#x}", paddr.as_usize());
+            return Err(MmError::AlignmentError);
+        }
+        check_frame(paddr, len, attrs)?;
+        // devices without a process context access with user privilege
+        let attrs = (attrs - MapAttributes::EXECUTE - MapAttributes::GLOBAL - MapAttributes::COW)
+            | MapAttributes::ACCESSED | MapAttributes::DIRTY;
+        for offset in (0..len).step_by(FRAME_SIZE) {
+            if let Err(err) = self.map_page(iova + offset, paddr + offset, attrs) {
+                if offset != 0 {
+                    let _ = self.unmap(iova, offset);
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    // holes are skipped, so a partially mapped range can be cleaned up
+    pub fn unmap(&mut self, iova: usize, len: usize) -> ResultWithErr<MmError> {
+        check_iova(iova, len)?;
+        let level = HAL_PAGE_LEVEL - 1;
+        for page in (iova..iova + len).step_by(FRAME_SIZE) {
+            if let Some(table) = self.walk(page, false)? {
+                table.unmap_user_leaf(page, level);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn translate(&mut self, iova: usize) -> Option<PhysAddr> {
+        let level = HAL_PAGE_LEVEL - 1;
+        let table = self.walk(iova, false).ok()??;
+        let pte = table.page_table_impl[PageTableImpl::get_index(iova, level).unwrap()];
+        pte.valid().then(|| PhysAddr::from_ppn(pte.get_ppn()) + (iova & (FRAME_SIZE - 1)))
+    }
+
+    // frees the tables, the mapped frames belong to whoever mapped them
+    pub fn destroy(self) {
+        free_tables(self.root.leak(), 0);
+    }
+
+    fn map_page(&mut self, iova: usize, paddr: PhysAddr, attrs: MapAttributes) -> ResultWithErr<MmError> {
+        let level = HAL_PAGE_LEVEL - 1;
+        let table = self.walk(iova, true)?.unwrap();
+        if table.page_table_impl[PageTableImpl::get_index(iova, level).unwrap()].valid() {
+            mork_kernel_log!(warn, "iova has been mapped, {:#x}", iova);
+            return Err(MmError::AlreadyMapped);
+        }
+        table.map_user_frame(iova, paddr, level, attrs);
+        Ok(())
+    }
+
+    // the last level table covering iova, None if it is missing and populate is not set
+    fn walk(&mut self, iova: usize, populate: bool) -> Result<Option<&mut PageTable>, MmError> {
+        let mut table: &mut PageTable = &mut self.root;
+        for level in 0..HAL_PAGE_LEVEL - 1 {
+            let pte = table.page_table_impl[PageTableImpl::get_index(iova, level).unwrap()];
+            if pte.valid() {
+                if pte.is_leaf() {
+                    mork_kernel_log!(warn, "iova {:#x} is covered by a huge leaf", iova);
+                    return Err(MmError::AlreadyMapped);
+                }
+                table = unsafe { &mut *next_table(&pte) };
+            } else if populate {
+                let next = PageTable::alloc().ok_or(MmError::OutOfMemory)?;
+                table.page_table_impl.map_page_table(iova, next.paddr().as_usize(), level);
+                table = next;
+            } else {
+                return Ok(None);
+            }
+        }
+        Ok(Some(table))
+    }
+}
+
+fn check_iova(iova: usize, len: usize) -> ResultWithErr<MmError> {
+    if len == 0 || !is_aligned(iova, FRAME_SIZE) || !is_aligned(len, FRAME_SIZE) {
+        mork_kernel_log!(warn, "iova/len must be aligned, {:#x}, {:#x}", iova, len);
+        return Err(MmError::AlignmentError);
+    }
+    if iova.checked_add(len).is_none_or(|end| end > IOVA_END) {
+        mork_kernel_log!(warn, "iova out of range, {:#x}, len: {:#x}", iova, len);
+        return Err(MmError::InvalidAddress);
+    }
+    Ok(())
+}
+
+fn free_tables(table: &mut PageTable, level: usize) {
+    if level < HAL_PAGE_LEVEL - 1 {
+        for index in 0..PTE_COUNT {
+            let pte = table.page_table_impl[index];
+            if pte.valid() && !pte.is_leaf() {
+                free_tables(unsafe { &mut *next_table(&pte) }, level + 1);
+            }
+        }
+    }
+    table.free();
+}
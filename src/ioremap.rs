@@ -0,0 +1,67 @@
+use spin::mutex::Mutex;
+use mork_common::mork_kernel_log;
+use crate::addr::PhysAddr;
+use crate::attributes::MapAttributes;
+use crate::error::MmError;
+use crate::frame::FRAME_SIZE;
+use crate::kernel_layout::{RegionAllocator, MMIO_START, REGION_SIZE};
+use crate::kernel_template;
+use crate::memory;
+use crate::page_table::MutPageTableWrapper;
+use crate::shootdown::online_harts;
+
+static MMIO_SPACE: Mutex<RegionAllocator> = Mutex::new(RegionAllocator::new(MMIO_START, MMIO_START + REGION_SIZE));
+
+// maps device registers uncached, DEVICE is used unless attrs asks for NON_CACHEABLE
+pub fn ioremap(paddr: PhysAddr, len: usize, attrs: MapAttributes) -> Result<usize, MmError> {
+    if len == 0 || attrs.intersects(MapAttributes::EXECUTE | MapAttributes::USER) {
+        mork_kernel_log!(warn, "invalid ioremap of {:#x}, len: {:#x}, attrs: {:?}", paddr.as_usize(), len, attrs);
+        return Err(MmError::InvalidParam);
+    }
+    // ram is already mapped cacheable by the direct map, a second alias with another type is not allowed
+    if memory::overlaps_ram(paddr, len) {
+        mork_kernel_log!(warn, "ioremap of ram rejected, {:#x}, len: {:#x}", paddr.as_usize(), len);
+        return Err(MmError::InvalidAddress);
+    }
+    let offset = paddr.as_usize() & (FRAME_SIZE - 1);
+    let start = PhysAddr::new(paddr.as_usize() - offset);
+    let len = (offset + len).checked_next_multiple_of(FRAME_SIZE).ok_or(MmError::InvalidParam)?;
+    let mut attrs = attrs | MapAttributes::READ | MapAttributes::ACCESSED | MapAttributes::DIRTY;
+    if !attrs.intersects(MapAttributes::NON_CACHEABLE | MapAttributes::DEVICE) {
+        attrs |= MapAttributes::DEVICE;
+    }
+
+    let mut space = MMIO_SPACE.lock();
+    let vaddr = space.alloc(len).ok_or_else(|| {
+        mork_kernel_log!(warn, "mmio region exhausted, {:#x} bytes requested", len);
+        MmError::OutOfMemory
+    })?;
+    let mut wrapper = MutPageTableWrapper::new(kernel_template::kernel_root()).with_harts(online_harts());
+    for page in (0..len).step_by(FRAME_SIZE) {
+        if let Err(err) = wrapper.map_kernel_phys(vaddr + page, start + page, attrs) {
+            unmap(&mut wrapper, vaddr, page);
+            space.free(vaddr);
+            return Err(err);
+        }
+    }
+    Ok(vaddr + offset)
+}
+
+pub fn iounmap(vaddr: usize) {
+    let vaddr = vaddr & !(FRAME_SIZE - 1);
+    let mut space = MMIO_SPACE.lock();
+    let Some(len) = space.free(vaddr) else {
+        mork_kernel_log!(warn, "iounmap of unknown mapping {:#x}", vaddr);
+        return;
+    };
+    let mut wrapper = MutPageTableWrapper::new(kernel_template::kernel_root()).with_harts(online_harts());
+    unmap(&mut wrapper, vaddr, len);
+}
+
+fn unmap(wrapper: &mut MutPageTableWrapper, vaddr: usize, len: usize) {
+    wrapper.begin_batch();
+    for page in (0..len).step_by(FRAME_SIZE) {
+        let _ = wrapper.unmap_kernel_page(vaddr + page);
+    }
+    wrapper.finish_batch();
+}
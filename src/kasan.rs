@@ -0,0 +1,140 @@
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use mork_common::mork_kernel_log;
+use mork_common::types::ResultWithErr;
+use crate::error::MmError;
+use crate::vmalloc;
+
+// one shadow byte per 8 bytes of the kernel window: 0 means all of them are addressable,
+// 1..=7 only the first ones, anything with the sign bit set means none and tells why
+const GRANULE: usize = 8;
+pub const HEAP_FREED: u8 = 0xfb;
+
+// zero until init, memory outside [WINDOW_START, WINDOW_END) is never checked
+static SHADOW: AtomicUsize = AtomicUsize::new(0);
+static WINDOW_START: AtomicUsize = AtomicUsize::new(0);
+static WINDOW_END: AtomicUsize = AtomicUsize::new(0);
+
+// memory allocated before init stays addressable, so early allocations are not false positives
+pub(crate) fn init(start: usize, end: usize) -> ResultWithErr<MmError> {
+    let start = start & !(GRANULE - 1);
+    let len = (end - start).div_ceil(GRANULE);
+    // the shadow comes from zeroed frames, every byte starts out addressable
+    let shadow = vmalloc::vmalloc(len).ok_or(MmError::OutOfMemory)?;
+    WINDOW_START.store(start, Ordering::Relaxed);
+    WINDOW_END.store(end, Ordering::Relaxed);
+    SHADOW.store(shadow, Ordering::Release);
+    mork_kernel_log!(info, "kasan shadow of {:#x} bytes covers {:#x}..{:#x}", len, start, end);
+    Ok(())
+}
+
+fn shadow_of(addr: usize) -> Option<*mut u8> {
+    let shadow = SHADOW.load(Ordering::Acquire);
+    let in_window = (WINDOW_START.load(Ordering::Relaxed)..WINDOW_END.load(Ordering::Relaxed)).contains(&addr);
+    (shadow != 0 && in_window).then(|| (shadow + (addr - WINDOW_START.load(Ordering::Relaxed)) / GRANULE) as *mut u8)
+}
+
+// addr is granule aligned, which every heap block is
+pub fn unpoison(addr: usize, size: usize) {
+    let Some(shadow) = shadow_of(addr) else {
+        return;
+    };
+    unsafe {
+        ptr::write_bytes(shadow, 0, size / GRANULE);
+        if !size.is_multiple_of(GRANULE) {
+            shadow.add(size / GRANULE).write((size % GRANULE) as u8);
+        }
+    }
+}
+
+pub fn poison(addr: usize, size: usize, value: u8) {
+    let Some(shadow) = shadow_of(addr) else {
+        return;
+    };
+    unsafe {
+        ptr::write_bytes(shadow, value, size.div_ceil(GRANULE));
+    }
+}
+
+// the first byte of [addr, addr + size) that may not be touched
+fn first_bad(addr: usize, size: usize) -> Option<(usize, u8)> {
+    (addr..addr.saturating_add(size)).find_map(|byte| {
+        let value = unsafe { *shadow_of(byte)? };
+        let addressable = value == 0 || (value as i8 > 0 && byte % GRANULE < value as usize);
+        (!addressable).then_some((byte, value))
+    })
+}
+
+fn report(addr: usize, size: usize, write: bool, fatal: bool) {
+    let Some((bad, value)) = first_bad(addr, size) else {
+        return;
+    };
+    let kind = match value {
+        HEAP_FREED => "freed heap memory",
+        _ => "an unaddressable granule",
+    };
+    mork_kernel_log!(error, "kasan: {} of {} bytes at {:#x} touches {} at {:#x}",
+        if write { "write" } else { "read" }, size, addr, kind, bad);
+    if fatal {
+        panic!("kasan: invalid access at {:#x}", bad);
+    }
+}
+
+// hooks called by code built with -Zsanitizer=kernel-address in outline mode,
+// this crate itself must not be instrumented
+macro_rules! check_hooks {
+    ($($size:literal => $load:ident, $store:ident, $load_noabort:ident, $store_noabort:ident;)*) => {
+        $(
+            #[unsafe(no_mangle)]
+            pub extern "C" fn $load(addr: usize) {
+                report(addr, $size, false, true);
+            }
+
+            #[unsafe(no_mangle)]
+            pub extern "C" fn $store(addr: usize) {
+                report(addr, $size, true, true);
+            }
+
+            #[unsafe(no_mangle)]
+            pub extern "C" fn $load_noabort(addr: usize) {
+                report(addr, $size, false, false);
+            }
+
+            #[unsafe(no_mangle)]
+            pub extern "C" fn $store_noabort(addr: usize) {
+                report(addr, $size, true, false);
+            }
+        )*
+    };
+}
+
+check_hooks! {
+    1 => __asan_load1, __asan_store1, __asan_load1_noabort, __asan_store1_noabort;
+    2 => __asan_load2, __asan_store2, __asan_load2_noabort, __asan_store2_noabort;
+    4 => __asan_load4, __asan_store4, __asan_load4_noabort, __asan_store4_noabort;
+    8 => __asan_load8, __asan_store8, __asan_load8_noabort, __asan_store8_noabort;
+    16 => __asan_load16, __asan_store16, __asan_load16_noabort, __asan_store16_noabort;
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn __asan_loadN(addr: usize, size: usize) {
+    report(addr, size, false, true);
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn __asan_storeN(addr: usize, size: usize) {
+    report(addr, size, true, true);
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn __asan_loadN_noabort(addr: usize, size: usize) {
+    report(addr, size, false, false);
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn __asan_storeN_noabort(addr: usize, size: usize) {
+    report(addr, size, true, false);
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn __asan_handle_no_return() {}
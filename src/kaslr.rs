@@ -0,0 +1,48 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use mork_common::mork_kernel_log;
+use mork_common::types::ResultWithErr;
+use mork_hal::KERNEL_OFFSET;
+use crate::addr::PhysAddr;
+use crate::error::MmError;
+use crate::kernel_layout::DIRECT_MAP_MAX;
+
+// slides keep gigapage alignment so the window can still be mapped with 1 GiB leaves
+pub const SLIDE_ALIGN: usize = 1 << 30;
+
+static SLIDE: AtomicUsize = AtomicUsize::new(0);
+static SEALED: AtomicBool = AtomicBool::new(false);
+
+pub fn slide() -> usize {
+    SLIDE.load(Ordering::Relaxed)
+}
+
+// base of the kernel window, every phys/virt conversion goes through it
+pub fn kernel_offset() -> usize {
+    KERNEL_OFFSET + slide()
+}
+
+// the slid window has to stay inside the direct map region, the seed comes from the hal entropy source
+pub fn choose_slide(seed: u64, ram_end: PhysAddr) -> usize {
+    let window = ram_end.as_usize().next_multiple_of(SLIDE_ALIGN);
+    let slots = DIRECT_MAP_MAX.saturating_sub(window) / SLIDE_ALIGN + 1;
+    (seed as usize % slots) * SLIDE_ALIGN
+}
+
+// only before init, the boot code must already run from the slid window
+pub fn set_slide(slide: usize) -> ResultWithErr<MmError> {
+    if SEALED.load(Ordering::Acquire) {
+        mork_kernel_log!(warn, "kernel slide can not change after mm init");
+        return Err(MmError::InvalidParam);
+    }
+    if !slide.is_multiple_of(SLIDE_ALIGN) || slide >= DIRECT_MAP_MAX {
+        mork_kernel_log!(warn, "invalid kernel slide {:#x}", slide);
+        return Err(MmError::InvalidParam);
+    }
+    SLIDE.store(slide, Ordering::Relaxed);
+    Ok(())
+}
+
+pub(crate) fn seal() {
+    SEALED.store(true, Ordering::Release);
+    mork_kernel_log!(info, "kernel window at {:#x}", kernel_offset());
+}
@@ -0,0 +1,183 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use spin::mutex::Mutex;
+use mork_common::mork_kernel_log;
+use mork_common::types::ResultWithErr;
+use mork_hal::KERNEL_OFFSET;
+use crate::error::MmError;
+use crate::frame::FRAME_SIZE;
+use crate::kaslr;
+use crate::page_table::{MutPageTableWrapper, PageTable};
+
+// sv39 leaves 256 GiB above KERNEL_OFFSET, the lower half of it is kept for the direct map
+pub const DIRECT_MAP_START: usize = KERNEL_OFFSET;
+pub const DIRECT_MAP_MAX: usize = 128 << 30;
+// every other region is a single root entry, created at init so user roots copy it once and never miss it
+pub const REGION_SIZE: usize = 1 << 30;
+pub const VMALLOC_START: usize = DIRECT_MAP_START + DIRECT_MAP_MAX;
+pub const MMIO_START: usize = VMALLOC_START + REGION_SIZE;
+pub const PERCPU_START: usize = MMIO_START + REGION_SIZE;
+pub const FIXMAP_START: usize = PERCPU_START + REGION_SIZE;
+pub const DMA_START: usize = FIXMAP_START + REGION_SIZE;
+pub const STACK_START: usize = DMA_START + REGION_SIZE;
+pub const TRAMPOLINE_START: usize = STACK_START + REGION_SIZE;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KernelRegionKind {
+    DirectMap,
+    Vmalloc,
+    Mmio,
+    Percpu,
+    Fixmap,
+    Dma,
+    Stack,
+    Trampoline,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct KernelRegion {
+    pub kind: KernelRegionKind,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl KernelRegion {
+    pub fn contains(&self, vaddr: usize) -> bool {
+        (self.start..self.end).contains(&vaddr)
+    }
+}
+
+static REGIONS: Mutex<Vec<KernelRegion>> = Mutex::new(Vec::new());
+
+pub(crate) fn init(kernel_page_table: &mut PageTable, direct_map_end: usize) -> ResultWithErr<MmError> {
+    if direct_map_end - DIRECT_MAP_START > DIRECT_MAP_MAX {
+        mork_kernel_log!(warn, "direct map does not fit its region, end: {:#x}", direct_map_end);
+        return Err(MmError::InvalidAddress);
+    }
+    // a kaslr slide leaves the bottom of the region unmapped
+    reserve(KernelRegionKind::DirectMap, kaslr::kernel_offset(), direct_map_end)?;
+    let mut wrapper = MutPageTableWrapper::new(kernel_page_table).with_boot_pool();
+    for (kind, start) in [
+        (KernelRegionKind::Vmalloc, VMALLOC_START),
+        (KernelRegionKind::Mmio, MMIO_START),
+        (KernelRegionKind::Percpu, PERCPU_START),
+        (KernelRegionKind::Fixmap, FIXMAP_START),
+        (KernelRegionKind::Dma, DMA_START),
+        (KernelRegionKind::Stack, STACK_START),
+        (KernelRegionKind::Trampoline, TRAMPOLINE_START),
+    ] {
+        reserve(kind, start, start + REGION_SIZE)?;
+        wrapper.populate(start, 1)?;
+    }
+    Ok(())
+}
+
+// see addr::extend_window
+pub(crate) fn extend_direct_map(direct_map_end: usize) -> ResultWithErr<MmError> {
+    if direct_map_end - DIRECT_MAP_START > DIRECT_MAP_MAX {
+        mork_kernel_log!(warn, "direct map does not fit its region, end: {:#x}", direct_map_end);
+        return Err(MmError::InvalidAddress);
+    }
+    let mut regions = REGIONS.lock();
+    let region = regions.iter_mut().find(|region| region.kind == KernelRegionKind::DirectMap)
+        .ok_or(MmError::InvalidParam)?;
+    region.end = region.end.max(direct_map_end);
+    Ok(())
+}
+
+pub fn reserve(kind: KernelRegionKind, start: usize, end: usize) -> ResultWithErr<MmError> {
+    if start >= end || start < KERNEL_OFFSET {
+        mork_kernel_log!(warn, "invalid kernel region {:?}, {:#x}..{:#x}", kind, start, end);
+        return Err(MmError::InvalidParam);
+    }
+    let mut regions = REGIONS.lock();
+    if let Some(other) = regions.iter().find(|region| region.start < end && start < region.end) {
+        mork_kernel_log!(warn, "kernel region {:?} {:#x}..{:#x} overlaps {:?} {:#x}..{:#x}",
+            kind, start, end, other.kind, other.start, other.end);
+        return Err(MmError::AlreadyMapped);
+    }
+    regions.push(KernelRegion { kind, start, end });
+    Ok(())
+}
+
+pub fn region(kind: KernelRegionKind) -> Option<KernelRegion> {
+    REGIONS.lock().iter().find(|region| region.kind == kind).copied()
+}
+
+pub fn region_of(vaddr: usize) -> Option<KernelRegion> {
+    REGIONS.lock().iter().find(|region| region.contains(vaddr)).copied()
+}
+
+pub fn regions() -> Vec<KernelRegion> {
+    REGIONS.lock().clone()
+}
+
+// hands out page aligned ranges of a region, first fit with an unmapped guard page after each range
+pub(crate) struct RegionAllocator {
+    start: usize,
+    end: usize,
+    // start -> len
+    ranges: BTreeMap<usize, usize>,
+}
+
+impl RegionAllocator {
+    pub(crate) const fn new(start: usize, end: usize) -> Self {
+        Self { start, end, ranges: BTreeMap::new() }
+    }
+
+    pub(crate) fn alloc(&mut self, len: usize) -> Option<usize> {
+        let needed = len.checked_add(FRAME_SIZE)?;
+        let mut cursor = self.start;
+        for (start, range_len) in self.ranges.iter() {
+            if start - cursor >= needed {
+                break;
+            }
+            cursor = start + range_len + FRAME_SIZE;
+        }
+        if cursor > self.end || self.end - cursor < needed {
+            return None;
+        }
+        self.ranges.insert(cursor, len);
+        Some(cursor)
+    }
+
+    // returns the length of the range that started at start
+    pub(crate) fn free(&mut self, start: usize) -> Option<usize> {
+        self.ranges.remove(&start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: usize = 0x10_0000;
+
+    #[test]
+    fn ranges_keep_a_guard_page_apart() {
+        let mut space = RegionAllocator::new(START, START + 16 * FRAME_SIZE);
+        assert_eq!(space.alloc(2 * FRAME_SIZE), Some(START));
+        assert_eq!(space.alloc(FRAME_SIZE), Some(START + 3 * FRAME_SIZE));
+        assert_eq!(space.alloc(FRAME_SIZE), Some(START + 5 * FRAME_SIZE));
+    }
+
+    #[test]
+    fn freed_range_is_reused_when_it_fits() {
+        let mut space = RegionAllocator::new(START, START + 16 * FRAME_SIZE);
+        let first = space.alloc(2 * FRAME_SIZE).unwrap();
+        let second = space.alloc(FRAME_SIZE).unwrap();
+        assert_eq!(space.free(first), Some(2 * FRAME_SIZE));
+        assert_eq!(space.free(first), None);
+        // the hole before second still needs room for the guard page
+        assert_eq!(space.alloc(3 * FRAME_SIZE), Some(second + 2 * FRAME_SIZE));
+        assert_eq!(space.alloc(2 * FRAME_SIZE), Some(first));
+    }
+
+    #[test]
+    fn exhausted_space_fails() {
+        let mut space = RegionAllocator::new(START, START + 4 * FRAME_SIZE);
+        assert_eq!(space.alloc(3 * FRAME_SIZE), Some(START));
+        assert_eq!(space.alloc(FRAME_SIZE), None);
+        assert_eq!(space.alloc(usize::MAX), None);
+    }
+}
@@ -0,0 +1,22 @@
+use mork_common::mork_kernel_log;
+use mork_common::types::ResultWithErr;
+use spin::mutex::{Mutex, MutexGuard};
+use crate::error::MmError;
+
+// the hal maps every kernel leaf writable and can not change the permissions of one afterwards,
+// so the root and the direct map tables can not be write-protected until it can
+static KERNEL_MAPPINGS: Mutex<()> = Mutex::new(());
+
+pub(crate) fn init() -> ResultWithErr<MmError> {
+    mork_kernel_log!(warn, "the hal can not write-protect kernel leaves, kernel page tables stay writable");
+    Ok(())
+}
+
+// serializes the writers of the kernel mappings, which would unprotect the tables while it is held
+pub struct KernelMappingsGuard {
+    _locked: MutexGuard<'static, ()>,
+}
+
+pub fn unlock_kernel_mappings() -> KernelMappingsGuard {
+    KernelMappingsGuard { _locked: KERNEL_MAPPINGS.lock() }
+}
@@ -0,0 +1,60 @@
+use alloc::collections::BTreeSet;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_init::LazyInit;
+use spin::mutex::Mutex;
+use mork_common::types::ResultWithErr;
+use crate::error::MmError;
+use crate::page_table::{copy_kernel_entries, OwnedPageTable, PageTable};
+use crate::shootdown::{online_harts, shootdown, FlushRequest};
+
+static KERNEL_ROOT: AtomicUsize = AtomicUsize::new(0);
+static KERNEL_TEMPLATE: LazyInit<Mutex<OwnedPageTable>> = LazyInit::new();
+// lock order: KERNEL_TEMPLATE, then USER_ROOTS
+static USER_ROOTS: Mutex<BTreeSet<usize>> = Mutex::new(BTreeSet::new());
+
+pub fn init(kernel_page_table: &PageTable) -> ResultWithErr<MmError> {
+    let mut template = PageTable::alloc_owned().ok_or(MmError::OutOfMemory)?;
+    copy_kernel_entries(&mut template, kernel_page_table);
+    KERNEL_ROOT.store(kernel_page_table.get_ptr(), Ordering::Release);
+    KERNEL_TEMPLATE.init_by(Mutex::new(template));
+    Ok(())
+}
+
+pub(crate) fn is_initialized() -> bool {
+    KERNEL_ROOT.load(Ordering::Acquire) != 0
+}
+
+// only valid after init
+pub(crate) fn kernel_root() -> &'static mut PageTable {
+    unsafe { &mut *(KERNEL_ROOT.load(Ordering::Acquire) as *mut PageTable) }
+}
+
+pub fn init_user_root(page_table: &mut PageTable) {
+    let template = KERNEL_TEMPLATE.lock();
+    copy_kernel_entries(page_table, &template);
+    USER_ROOTS.lock().insert(page_table.get_ptr());
+}
+
+pub fn register_user_root(page_table: &mut PageTable) {
+    let _template = KERNEL_TEMPLATE.lock();
+    USER_ROOTS.lock().insert(page_table.get_ptr());
+}
+
+pub fn unregister_user_root(page_table: &PageTable) {
+    USER_ROOTS.lock().remove(&page_table.get_ptr());
+}
+
+pub fn is_user_root(root: usize) -> bool {
+    USER_ROOTS.lock().contains(&root)
+}
+
+pub fn sync_kernel_mappings() {
+    let kernel_page_table = unsafe { &*(KERNEL_ROOT.load(Ordering::Acquire) as *const PageTable) };
+    let mut template = KERNEL_TEMPLATE.lock();
+    copy_kernel_entries(&mut template, kernel_page_table);
+    for root in USER_ROOTS.lock().iter() {
+        let page_table = unsafe { &mut *(*root as *mut PageTable) };
+        copy_kernel_entries(page_table, &template);
+    }
+    shootdown(online_harts(), FlushRequest::All);
+}
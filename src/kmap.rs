@@ -0,0 +1,70 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use mork_common::mork_kernel_log;
+use mork_common::types::ResultWithErr;
+use crate::addr::PhysAddr;
+use crate::attributes::MapAttributes;
+use crate::error::MmError;
+use crate::frame::FRAME_SIZE;
+use crate::kernel_layout::FIXMAP_START;
+use crate::kernel_template;
+use crate::page_table::{MutPageTableWrapper, PageTable};
+use crate::shootdown::online_harts;
+
+// one bit per slot in SLOTS_USED
+const KMAP_SLOTS: usize = 64;
+// the first slots of the fixmap region, their last level table is created at init
+pub const KMAP_START: usize = FIXMAP_START;
+
+static SLOTS_USED: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn init(kernel_page_table: &mut PageTable) -> ResultWithErr<MmError> {
+    MutPageTableWrapper::new(kernel_page_table).with_boot_pool().populate(KMAP_START, 2)
+}
+
+// maps any frame, inside the direct map or not, the mapping is shared by every hart
+pub fn kmap(paddr: PhysAddr) -> Option<usize> {
+    let slot = alloc_slot()?;
+    let vaddr = KMAP_START + slot * FRAME_SIZE;
+    let frame = PhysAddr::new(paddr.as_usize() & !(FRAME_SIZE - 1));
+    let attrs = MapAttributes::READ | MapAttributes::WRITE | MapAttributes::ACCESSED | MapAttributes::DIRTY;
+    let result = MutPageTableWrapper::new(kernel_template::kernel_root()).map_kernel_phys(vaddr, frame, attrs);
+    if let Err(err) = result {
+        mork_kernel_log!(warn, "fail to kmap {:#x}, {}", paddr.as_usize(), err);
+        SLOTS_USED.fetch_and(!(1 << slot), Ordering::Release);
+        return None;
+    }
+    Some(vaddr + (paddr.as_usize() & (FRAME_SIZE - 1)))
+}
+
+pub fn kunmap(vaddr: usize) {
+    let vaddr = vaddr & !(FRAME_SIZE - 1);
+    let slot = vaddr.wrapping_sub(KMAP_START) / FRAME_SIZE;
+    if vaddr < KMAP_START || slot >= KMAP_SLOTS {
+        mork_kernel_log!(warn, "kunmap of {:#x} outside of the kmap window", vaddr);
+        return;
+    }
+    // the slot is reused only after every hart dropped the translation
+    let mut wrapper = MutPageTableWrapper::new(kernel_template::kernel_root()).with_harts(online_harts());
+    if wrapper.unmap_kernel_page(vaddr).is_ok() {
+        SLOTS_USED.fetch_and(!(1 << slot), Ordering::Release);
+    }
+}
+
+pub fn is_kmap_addr(vaddr: usize) -> bool {
+    (KMAP_START..KMAP_START + KMAP_SLOTS * FRAME_SIZE).contains(&vaddr)
+}
+
+fn alloc_slot() -> Option<usize> {
+    let mut used = SLOTS_USED.load(Ordering::Relaxed);
+    loop {
+        if used == u64::MAX {
+            mork_kernel_log!(warn, "kmap slots exhausted");
+            return None;
+        }
+        let slot = used.trailing_ones() as usize;
+        match SLOTS_USED.compare_exchange_weak(used, used | (1 << slot), Ordering::Acquire, Ordering::Relaxed) {
+            Ok(_) => return Some(slot),
+            Err(current) => used = current,
+        }
+    }
+}
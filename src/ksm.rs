@@ -0,0 +1,129 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use mork_hal::config::HAL_PAGE_LEVEL;
+use spin::mutex::Mutex;
+use crate::addr::{virt_to_phys, VirtAddr};
+use crate::attributes::MapAttributes;
+use crate::frame::{self, FRAME_SIZE};
+use crate::frame_info::{self, FrameFlags, FrameInfo};
+use crate::page_table::MutPageTableWrapper;
+use crate::rmap::Mapping;
+
+// hash of every scanned page at its last scan, a page that changed in between is likely to change again
+static CHECKSUMS: Mutex<BTreeMap<(usize, usize), u64>> = Mutex::new(BTreeMap::new());
+// a stable page per hash that nothing was merged with yet
+static UNSTABLE: Mutex<BTreeMap<u64, Mapping>> = Mutex::new(BTreeMap::new());
+// merged frames per hash, the contents stay as they are since every mapping is read-only.
+// freed frames are dropped when they are next looked at
+static STABLE: Mutex<BTreeMap<u64, Vec<usize>>> = Mutex::new(BTreeMap::new());
+
+// merges the page at vaddr with an identical page if there is one, returns whether its frame was freed.
+// only the scanned address space changes, a page of another one is merged by its owner when it scans it
+pub(crate) fn merge_page(wrapper: &mut MutPageTableWrapper, root: usize, vaddr: usize) -> bool {
+    let mapping = Mapping { root, vaddr };
+    let Some((paddr, info, attrs)) = private_page(wrapper, vaddr) else {
+        return false;
+    };
+    let hash = hash_page(paddr);
+    if CHECKSUMS.lock().insert((root, vaddr), hash) != Some(hash) {
+        return false;
+    }
+    let merged = STABLE.lock().get_mut(&hash).and_then(|frames| {
+        frames.retain(|frame| is_merged(*frame));
+        frames.iter().copied().find(|frame| same_contents(*frame, paddr))
+    });
+    if let Some(target) = merged {
+        return merge_into(wrapper, vaddr, paddr, attrs, target);
+    }
+
+    let seen = UNSTABLE.lock().insert(hash, mapping);
+    if !matches!(seen, Some(other) if other != mapping) {
+        return false;
+    }
+    // another page looked the same, this one turns into the merged frame the other is merged into later
+    if wrapper.protect_range(vaddr, FRAME_SIZE, shared_attrs(attrs)).is_err() {
+        return false;
+    }
+    UNSTABLE.lock().remove(&hash);
+    info.insert_flags(FrameFlags::MERGED);
+    STABLE.lock().entry(hash).or_default().push(paddr);
+    wrapper.account_merged(FRAME_SIZE, 0);
+    false
+}
+
+// the address space is destroyed, its pages are no candidates anymore
+pub(crate) fn forget_space(root: usize) {
+    CHECKSUMS.lock().retain(|(page_root, _), _| *page_root != root);
+    UNSTABLE.lock().retain(|_, mapping| mapping.root != root);
+}
+
+// [vaddr, vaddr + len) is unmapped, a page mapped there later starts over
+pub(crate) fn forget_range(root: usize, vaddr: usize, len: usize) {
+    let end = vaddr.saturating_add(len);
+    let mut checksums = CHECKSUMS.lock();
+    while let Some((&key, _)) = checksums.range((root, vaddr)..(root, end)).next() {
+        checksums.remove(&key);
+    }
+    drop(checksums);
+    UNSTABLE.lock().retain(|_, mapping| mapping.root != root || !(vaddr..end).contains(&mapping.vaddr));
+}
+
+fn merge_into(wrapper: &mut MutPageTableWrapper, vaddr: usize, paddr: usize, attrs: MapAttributes, target: usize)
+    -> bool {
+    // no write may land between the comparison and the switch
+    if attrs.is_w() && wrapper.protect_range(vaddr, FRAME_SIZE, attrs - MapAttributes::WRITE).is_err() {
+        return false;
+    }
+    let target_phys = virt_to_phys(VirtAddr::new(target)).unwrap();
+    // the mapping holds the target before it maps it, so its other holders cannot free it meanwhile
+    frame_info::get_shared(target_phys);
+    if !same_contents(paddr, target) || wrapper.replace_frame(vaddr, target_phys, shared_attrs(attrs)).is_err() {
+        frame_info::put_shared(target_phys);
+        let _ = wrapper.protect_range(vaddr, FRAME_SIZE, attrs);
+        return false;
+    }
+    wrapper.account_merged(FRAME_SIZE, 0);
+    unsafe {
+        frame::dealloc_frame(paddr);
+    }
+    true
+}
+
+// a 4K user frame only this mapping refers to and nobody pinned
+fn private_page(wrapper: &MutPageTableWrapper, vaddr: usize) -> Option<(usize, &'static FrameInfo, MapAttributes)> {
+    let (paddr, level, attrs) = wrapper.translate(vaddr)?;
+    let pfn = frame_info::pfn(paddr)?;
+    let info = frame_info::frame_info(pfn)?;
+    let flags = info.flags();
+    let private = level == HAL_PAGE_LEVEL - 1 && !attrs.is_cow() && flags.contains(FrameFlags::USER)
+        && !flags.contains(FrameFlags::MERGED) && !frame_info::is_pinned(pfn)
+        && frame_info::is_exclusive(virt_to_phys(VirtAddr::new(paddr)).unwrap());
+    private.then_some((paddr, info, attrs))
+}
+
+// writes break the sharing through the cow fault path
+fn shared_attrs(attrs: MapAttributes) -> MapAttributes {
+    if attrs.is_w() {
+        (attrs - MapAttributes::WRITE) | MapAttributes::COW
+    } else {
+        attrs
+    }
+}
+
+fn is_merged(paddr: usize) -> bool {
+    frame_info::pfn(paddr).and_then(frame_info::frame_info)
+        .is_some_and(|info| info.flags().contains(FrameFlags::MERGED))
+}
+
+fn page_words(paddr: usize) -> &'static [u64] {
+    unsafe { core::slice::from_raw_parts(paddr as *const u64, FRAME_SIZE / size_of::<u64>()) }
+}
+
+// fnv-1a over words
+fn hash_page(paddr: usize) -> u64 {
+    page_words(paddr).iter().fold(0xcbf2_9ce4_8422_2325, |hash, word| (hash ^ word).wrapping_mul(0x100_0000_01b3))
+}
+
+fn same_contents(a: usize, b: usize) -> bool {
+    page_words(a) == page_words(b)
+}
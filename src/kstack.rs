@@ -0,0 +1,88 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use spin::mutex::Mutex;
+use mork_common::mork_kernel_log;
+use crate::frame::FRAME_SIZE;
+use crate::kernel_layout::{RegionAllocator, REGION_SIZE, STACK_START};
+use crate::vmalloc;
+
+// identifies a stack by its base for the lifetime of the allocation
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StackId(pub usize);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KernelStack {
+    // lowest mapped address, the page below it is the guard
+    pub base: usize,
+    pub size: usize,
+}
+
+impl KernelStack {
+    // initial stack pointer, stacks grow down
+    pub fn top(&self) -> usize {
+        self.base + self.size
+    }
+
+    pub fn id(&self) -> StackId {
+        StackId(self.base)
+    }
+}
+
+struct KernelStacks {
+    space: RegionAllocator,
+    // base -> frames backing the stack
+    frames: BTreeMap<usize, Vec<usize>>,
+}
+
+static STACKS: Mutex<KernelStacks> = Mutex::new(KernelStacks {
+    space: RegionAllocator::new(STACK_START, STACK_START + REGION_SIZE),
+    frames: BTreeMap::new(),
+});
+
+// an overflow runs into the unmapped guard page below the stack instead of the heap
+pub fn alloc_kernel_stack(size: usize) -> Option<KernelStack> {
+    if size == 0 {
+        return None;
+    }
+    let size = size.checked_next_multiple_of(FRAME_SIZE)?;
+    let mut stacks = STACKS.lock();
+    let Some(start) = stacks.space.alloc(size + FRAME_SIZE) else {
+        mork_kernel_log!(warn, "kernel stack region exhausted, {:#x} bytes requested", size);
+        return None;
+    };
+    let base = start + FRAME_SIZE;
+    let Some(frames) = vmalloc::map_frames(base, size) else {
+        stacks.space.free(start);
+        return None;
+    };
+    stacks.frames.insert(base, frames);
+    Some(KernelStack { base, size })
+}
+
+/// # Safety
+///
+/// `stack` must come from `alloc_kernel_stack`, and no hart may run on it anymore.
+pub unsafe fn free_kernel_stack(stack: KernelStack) {
+    let mut stacks = STACKS.lock();
+    let Some(frames) = stacks.frames.remove(&stack.base) else {
+        mork_kernel_log!(warn, "free of unknown kernel stack {:#x}", stack.base);
+        return;
+    };
+    vmalloc::unmap_frames(stack.base, &frames);
+    stacks.space.free(stack.base - FRAME_SIZE);
+}
+
+// for the trap handler, tells an overflow into a guard page apart from any other kernel fault
+pub fn is_kernel_stack_guard_fault(fault_vaddr: usize) -> Option<StackId> {
+    if !is_kernel_stack_addr(fault_vaddr) {
+        return None;
+    }
+    // the fault may hit while this hart holds the lock, never spin on it from the trap path
+    let stacks = STACKS.try_lock()?;
+    let (&base, _) = stacks.frames.range(fault_vaddr + 1..).next()?;
+    (fault_vaddr >= base - FRAME_SIZE).then_some(StackId(base))
+}
+
+pub fn is_kernel_stack_addr(vaddr: usize) -> bool {
+    (STACK_START..STACK_START + REGION_SIZE).contains(&vaddr)
+}
@@ -1,20 +1,203 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(test, allow(dead_code))]
+#![cfg_attr(feature = "alloc-error-handler", feature(alloc_error_handler))]
 extern crate alloc;
 
 use alloc::string::String;
+use alloc::vec::Vec;
 use mork_common::mork_kernel_log;
 use mork_common::types::ResultWithErr;
+use crate::addr::{PhysAddr, VirtAddr};
+use crate::error::MmError;
+use crate::memory::{MemoryMap, MemoryRegion, ReservedRegion};
 use crate::page_table::PageTable;
 
+pub mod addr;
+pub mod address_space;
+pub mod asid;
+pub mod attributes;
+pub mod backend;
+pub mod cache;
+pub mod cma;
+pub mod coloring;
+pub mod compaction;
+pub mod dma;
+pub mod early;
+pub mod error;
+pub mod eviction;
+pub mod fault;
+pub mod fixmap;
+pub mod frame;
+pub mod frame_info;
+pub mod hart;
+pub mod heap;
+pub mod iommu;
+pub mod ioremap;
+#[cfg(feature = "kasan")]
+pub mod kasan;
+pub mod kaslr;
+pub mod kernel_layout;
+pub mod kernel_protect;
+pub mod kernel_template;
+pub mod kmap;
+pub mod kstack;
+pub mod memory;
+pub mod migrate;
+pub mod oom;
 pub mod page_table;
-mod heap;
+pub mod percpu;
+pub mod policy;
+pub mod rmap;
+pub mod shootdown;
+pub mod stats;
+pub mod swap;
+pub mod sync;
+pub mod tlb;
+pub mod trampoline;
+pub mod uaccess;
+pub mod vm_area;
+pub mod vmalloc;
+pub mod watermark;
+#[cfg(feature = "zpool")]
+pub mod zpool;
+mod bad_frame;
+mod balloon;
+#[cfg(not(any(feature = "heap-linked-list", feature = "heap-tlsf")))]
+mod buddy;
+mod heap_cache;
+mod hotplug;
+#[cfg(feature = "ksm")]
+mod ksm;
+#[cfg(feature = "heap-poison")]
+mod heap_poison;
+#[cfg(feature = "heap-redzone")]
+mod heap_redzone;
+#[cfg(feature = "heap-track")]
+mod heap_track;
+#[cfg(feature = "heap-linked-list")]
+mod linked_list;
+#[cfg(feature = "heap-tlsf")]
+mod tlsf;
+mod soft_pte;
+mod zoned;
 
-pub fn init(kernel_page_table: &mut PageTable) -> ResultWithErr<String> {
+#[cfg(all(feature = "heap-linked-list", feature = "heap-tlsf"))]
+compile_error!("heap-linked-list and heap-tlsf are exclusive, pick one heap backend");
+
+pub fn init(kernel_page_table: &mut PageTable, memory_map: &MemoryMap) -> ResultWithErr<String> {
     mork_kernel_log!(info, "start mm init");
-    let (_, kernel_end, memory_end) = mork_hal::get_memory_info().map_err(|_| "fail to get memory info")?;
-    heap::init(kernel_end, memory_end);
+    if !hart::is_registered() {
+        return Err("hart ops are not set".into());
+    }
+    let ram_end = memory_map.ram_end().ok_or("memory map has no ram")?;
+    kaslr::seal();
+    heap::seal_config();
+    addr::init(addr::phys_to_virt(ram_end).as_usize());
+    let early_reserved = memory::seal_early_reserved();
+    let mut result = Ok(());
+    memory_map.for_each_free(early_reserved.iter().flatten(), |start, end| {
+        if result.is_ok() {
+            result = heap::add_boot_region(addr::phys_to_virt(start).as_usize(), addr::phys_to_virt(end).as_usize());
+        }
+    });
+    result?;
+    if let Some((start, end)) = early::take_rest() {
+        heap::add_boot_region(start, end)?;
+    }
+    frame::init_zero_page()?;
+    for region in memory_map.ram {
+        memory::add_ram_region(region.start, region.end);
+    }
+    memory::add_reserved_region(ReservedRegion { region: memory_map.kernel, name: "kernel", no_map: false });
+    for reserved in memory_map.reserved.iter().chain(early_reserved.iter().flatten()) {
+        memory::add_reserved_region(*reserved);
+    }
     page_table::map_kernel_window(kernel_page_table)?;
+    kernel_layout::init(kernel_page_table, addr::phys_to_virt(ram_end).as_usize())?;
+    kmap::init(kernel_page_table)?;
+    fixmap::init(kernel_page_table)?;
+    kernel_template::init(kernel_page_table)?;
     kernel_page_table.page_table_impl.active();
+    asid::init();
+    uaccess::disable_user_access();
+    percpu::init_hart(hart::hart_id())?;
+    let ram_start = memory::ram_regions().iter().map(|region| region.start).min().ok_or("memory map has no ram")?;
+    frame_info::init(ram_start, ram_end)?;
+    cma::init();
+    #[cfg(feature = "kasan")]
+    kasan::init(addr::phys_to_virt(ram_start).as_usize(), addr::phys_to_virt(ram_end).as_usize())?;
+    shootdown::hart_online(hart::hart_id());
+    kernel_protect::init()?;
     mork_kernel_log!(info, "kernel page table map success");
     Ok(())
-}
\ No newline at end of file
+}
+
+// called by every secondary hart once the boot hart finished init
+pub fn init_secondary(hart_id: usize) -> ResultWithErr<String> {
+    if !kernel_template::is_initialized() {
+        return Err("mm is not initialized".into());
+    }
+    kernel_template::kernel_root().page_table_impl.active();
+    uaccess::disable_user_access();
+    percpu::init_hart(hart_id)?;
+    // from now on the hart takes part in tlb shootdowns
+    shootdown::hart_online(hart_id);
+    mork_kernel_log!(info, "hart {} joined the kernel page table", hart_id);
+    Ok(())
+}
+
+// only before init, the range never reaches the heap or the frame allocator
+pub fn reserve_region(paddr: PhysAddr, len: usize, name: &'static str) -> ResultWithErr<MmError> {
+    let end = paddr.as_usize().checked_add(len).filter(|_| len != 0).ok_or(MmError::InvalidParam)?;
+    let region = MemoryRegion { start: paddr, end: PhysAddr::new(end) };
+    memory::reserve_early(ReservedRegion { region, name, no_map: false })
+}
+
+// once the root task runs, nothing may execute or reference the init sections anymore
+pub fn reclaim_init_memory() -> ResultWithErr<MmError> {
+    let (start, end) = page_table::remap_init_sections()?;
+    if start == end {
+        return Ok(());
+    }
+    let region = MemoryRegion {
+        start: addr::virt_to_phys(VirtAddr::new(start)).ok_or(MmError::InvalidAddress)?,
+        end: addr::virt_to_phys(VirtAddr::new(end)).ok_or(MmError::InvalidAddress)?,
+    };
+    memory::release_reserved("kernel", region);
+    heap::add_region(start, end)?;
+    mork_kernel_log!(info, "reclaimed {} KiB of init memory", (end - start) >> 10);
+    Ok(())
+}
+
+// ram that showed up at runtime, e.g. plugged by virtio-mem. start and len are physical and frame aligned
+pub fn hotplug_add(start: PhysAddr, len: usize) -> ResultWithErr<MmError> {
+    hotplug::add(start, len)
+}
+
+// only whole ranges given to hotplug_add, fails with the range still online while it is in use
+pub fn hotplug_remove(start: PhysAddr, len: usize) -> ResultWithErr<MmError> {
+    hotplug::remove(start, len)
+}
+
+// for a virtio-balloon driver, returns the pfns to report to the host, at most frames of them
+pub fn inflate_balloon(frames: usize) -> Vec<usize> {
+    balloon::inflate(frames)
+}
+
+// the pfns returned may be used again as soon as this returns
+pub fn deflate_balloon(frames: usize) -> Vec<usize> {
+    balloon::deflate(frames)
+}
+
+// for a machine check or ecc handler, returns the user mappings that lost the frame
+pub fn mark_frame_poisoned(pfn: usize) -> Result<Vec<rmap::Mapping>, MmError> {
+    bad_frame::poison(pfn)
+}
+
+pub fn poisoned_frames() -> Vec<usize> {
+    bad_frame::poisoned_frames()
+}
+
+pub fn stats() -> stats::GlobalStats {
+    stats::collect()
+}
@@ -0,0 +1,222 @@
+use core::alloc::Layout;
+use core::ptr::{self, NonNull};
+use crate::heap::HeapBackend;
+
+// header of a free range, stored in the range itself
+struct Node {
+    size: usize,
+    next: *mut Node,
+}
+
+// every block is a multiple of this, so no hole is ever too small to hold a node
+const MIN_BLOCK: usize = size_of::<Node>();
+
+// first fit over an address ordered free list, neighbours merge on free.
+// smaller than the buddy heap and without rounding to powers of two, but allocation walks the list
+pub struct LinkedListHeap {
+    head: Node,
+    user: usize,
+    allocated: usize,
+    total: usize,
+}
+
+unsafe impl Send for LinkedListHeap {}
+
+impl LinkedListHeap {
+    pub const fn empty() -> Self {
+        Self {
+            head: Node { size: 0, next: ptr::null_mut() },
+            user: 0,
+            allocated: 0,
+            total: 0,
+        }
+    }
+
+    // links [addr, addr + size) in, merging it with the free ranges around it
+    unsafe fn insert(&mut self, addr: usize, size: usize) {
+        let head: *mut Node = &mut self.head;
+        let mut prev = head;
+        unsafe {
+            while !(*prev).next.is_null() && ((*prev).next as usize) < addr {
+                prev = (*prev).next;
+            }
+            let next = (*prev).next;
+            let node = if prev != head && prev as usize + (*prev).size == addr {
+                (*prev).size += size;
+                prev
+            } else {
+                let node = addr as *mut Node;
+                node.write(Node { size, next });
+                (*prev).next = node;
+                node
+            };
+            if !next.is_null() && node as usize + (*node).size == next as usize {
+                (*node).size += (*next).size;
+                (*node).next = (*next).next;
+            }
+        }
+    }
+
+    fn nodes(&self) -> impl Iterator<Item = &Node> {
+        let mut current = self.head.next;
+        core::iter::from_fn(move || {
+            let node = unsafe { current.as_ref()? };
+            current = node.next;
+            Some(node)
+        })
+    }
+}
+
+impl HeapBackend for LinkedListHeap {
+    unsafe fn add_to_heap(&mut self, start: usize, end: usize) {
+        let start = start.next_multiple_of(MIN_BLOCK);
+        let end = end & !(MIN_BLOCK - 1);
+        if start >= end {
+            return;
+        }
+        unsafe {
+            self.insert(start, end - start);
+        }
+        self.total += end - start;
+    }
+
+    unsafe fn remove_region(&mut self, start: usize, end: usize) -> bool {
+        let start = start.next_multiple_of(MIN_BLOCK);
+        let end = end & !(MIN_BLOCK - 1);
+        if start >= end {
+            return false;
+        }
+        let mut prev: *mut Node = &mut self.head;
+        unsafe {
+            while !(*prev).next.is_null() {
+                let node = (*prev).next;
+                let node_end = node as usize + (*node).size;
+                if node as usize <= start && end <= node_end {
+                    (*prev).next = (*node).next;
+                    if (node as usize) < start {
+                        self.insert(node as usize, start - node as usize);
+                    }
+                    if end < node_end {
+                        self.insert(end, node_end - end);
+                    }
+                    self.total -= end - start;
+                    return true;
+                }
+                prev = node;
+            }
+        }
+        false
+    }
+
+    fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, ()> {
+        let size = block_size(layout);
+        let align = layout.align().max(MIN_BLOCK);
+        let mut prev: *mut Node = &mut self.head;
+        unsafe {
+            while !(*prev).next.is_null() {
+                let node = (*prev).next;
+                let (start, end) = (node as usize, node as usize + (*node).size);
+                let aligned = start.next_multiple_of(align);
+                if aligned + size <= end {
+                    // the padding in front and the rest behind go back to the list
+                    (*prev).next = (*node).next;
+                    if aligned > start {
+                        self.insert(start, aligned - start);
+                    }
+                    if aligned + size < end {
+                        self.insert(aligned + size, end - aligned - size);
+                    }
+                    self.user += layout.size();
+                    self.allocated += size;
+                    return Ok(NonNull::new_unchecked(aligned as *mut u8));
+                }
+                prev = node;
+            }
+        }
+        Err(())
+    }
+
+    fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let size = block_size(layout);
+        unsafe {
+            self.insert(ptr.as_ptr() as usize, size);
+        }
+        self.user -= layout.size();
+        self.allocated -= size;
+    }
+
+    fn total_bytes(&self) -> usize {
+        self.total
+    }
+
+    fn allocated_bytes(&self) -> usize {
+        self.allocated
+    }
+
+    fn requested_bytes(&self) -> usize {
+        self.user
+    }
+
+    fn largest_free_block(&self) -> usize {
+        self.nodes().map(|node| node.size).max().unwrap_or(0)
+    }
+
+    fn count_free_blocks(&self, counts: &mut [usize]) {
+        for node in self.nodes() {
+            let order = (usize::BITS - 1 - node.size.leading_zeros()) as usize;
+            counts[order.min(counts.len() - 1)] += 1;
+        }
+    }
+}
+
+fn block_size(layout: Layout) -> usize {
+    layout.size().max(MIN_BLOCK).next_multiple_of(MIN_BLOCK)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use super::*;
+
+    const REGION: usize = 16 << 10;
+
+    fn heap(memory: &mut Vec<u128>) -> (LinkedListHeap, usize) {
+        let start = memory.as_mut_ptr() as usize;
+        let mut heap = LinkedListHeap::empty();
+        unsafe { heap.add_to_heap(start, start + REGION); }
+        (heap, start)
+    }
+
+    #[test]
+    fn freed_blocks_merge_back_into_one() {
+        let mut memory = vec![0u128; REGION / 16];
+        let (mut heap, _) = heap(&mut memory);
+        assert_eq!(heap.largest_free_block(), REGION);
+        let layouts = [Layout::from_size_align(24, 8).unwrap(), Layout::from_size_align(100, 64).unwrap(),
+                       Layout::from_size_align(512, 8).unwrap()];
+        let blocks: Vec<_> = layouts.iter().map(|layout| heap.alloc(*layout).unwrap()).collect();
+        assert_eq!(blocks[1].as_ptr() as usize % 64, 0);
+        assert_eq!(heap.requested_bytes(), 24 + 100 + 512);
+        for index in [1, 0, 2] {
+            heap.dealloc(blocks[index], layouts[index]);
+        }
+        assert_eq!(heap.largest_free_block(), REGION);
+        let mut counts = [0; 32];
+        heap.count_free_blocks(&mut counts);
+        assert_eq!(counts.iter().sum::<usize>(), 1);
+    }
+
+    #[test]
+    fn region_leaves_only_while_free() {
+        let mut memory = vec![0u128; REGION / 16];
+        let (mut heap, start) = heap(&mut memory);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let block = heap.alloc(layout).unwrap();
+        assert!(!unsafe { heap.remove_region(start, start + REGION) });
+        heap.dealloc(block, layout);
+        assert!(unsafe { heap.remove_region(start, start + REGION) });
+        assert_eq!(heap.total_bytes(), 0);
+        assert!(heap.alloc(layout).is_err());
+    }
+}
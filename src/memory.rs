@@ -0,0 +1,205 @@
+use alloc::vec::Vec;
+use spin::mutex::Mutex;
+use mork_common::mork_kernel_log;
+use mork_common::types::ResultWithErr;
+use crate::addr::PhysAddr;
+use crate::error::MmError;
+
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryRegion {
+    pub start: PhysAddr,
+    pub end: PhysAddr,
+}
+
+impl MemoryRegion {
+    pub fn contains(&self, paddr: PhysAddr, len: usize) -> bool {
+        paddr >= self.start && paddr.as_usize() + len <= self.end.as_usize()
+    }
+
+    pub fn overlaps(&self, paddr: PhysAddr, len: usize) -> bool {
+        paddr < self.end && paddr.as_usize() + len > self.start.as_usize()
+    }
+}
+
+// a physically contiguous piece of a buffer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PhysSegment {
+    pub paddr: PhysAddr,
+    pub len: usize,
+}
+
+// extends the last segment when the new piece follows it physically
+pub(crate) fn push_segment(segments: &mut Vec<PhysSegment>, paddr: PhysAddr, len: usize) {
+    match segments.last_mut() {
+        Some(last) if last.paddr + last.len == paddr => last.len += len,
+        _ => segments.push(PhysSegment { paddr, len }),
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ReservedRegion {
+    pub region: MemoryRegion,
+    pub name: &'static str,
+    // kept out of the kernel window as well, e.g. firmware guarded by pmp
+    pub no_map: bool,
+}
+
+// handed over by the boot code, every address is physical
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryMap<'a> {
+    pub ram: &'a [MemoryRegion],
+    // the kernel image, never given to the allocators
+    pub kernel: MemoryRegion,
+    // dtb, opensbi, initrd and the like
+    pub reserved: &'a [ReservedRegion],
+}
+
+impl MemoryMap<'_> {
+    pub fn ram_end(&self) -> Option<PhysAddr> {
+        self.ram.iter().map(|region| region.end).max()
+    }
+
+    // calls f with every page aligned piece of ram that is neither reserved nor the kernel image
+    pub(crate) fn for_each_free<'a>(&'a self, extra: impl Iterator<Item = &'a ReservedRegion> + Clone,
+                                    mut f: impl FnMut(PhysAddr, PhysAddr)) {
+        let holes = self.reserved.iter().chain(extra)
+            .map(|reserved| reserved.region)
+            .chain(core::iter::once(self.kernel))
+            .map(page_round_out);
+        for region in self.ram {
+            subtract(page_round_in(*region), holes.clone(), &mut f);
+        }
+    }
+}
+
+const EARLY_RESERVED_MAX: usize = 32;
+
+// filled before the heap exists, so it can't be a vec
+struct EarlyReserved {
+    regions: [Option<ReservedRegion>; EARLY_RESERVED_MAX],
+    sealed: bool,
+}
+
+static EARLY_RESERVED: Mutex<EarlyReserved> = Mutex::new(EarlyReserved {
+    regions: [None; EARLY_RESERVED_MAX],
+    sealed: false,
+});
+
+static RAM_REGIONS: Mutex<Vec<MemoryRegion>> = Mutex::new(Vec::new());
+static RESERVED_REGIONS: Mutex<Vec<ReservedRegion>> = Mutex::new(Vec::new());
+
+pub fn add_ram_region(start: PhysAddr, end: PhysAddr) {
+    RAM_REGIONS.lock().push(MemoryRegion { start, end });
+}
+
+pub(crate) fn remove_ram_region(start: PhysAddr, end: PhysAddr) {
+    RAM_REGIONS.lock().retain(|region| region.start != start || region.end != end);
+}
+
+pub fn ram_regions() -> Vec<MemoryRegion> {
+    RAM_REGIONS.lock().clone()
+}
+
+pub fn is_ram(paddr: PhysAddr, len: usize) -> bool {
+    RAM_REGIONS.lock().iter().any(|region| region.contains(paddr, len))
+}
+
+pub fn overlaps_ram(paddr: PhysAddr, len: usize) -> bool {
+    RAM_REGIONS.lock().iter().any(|region| region.overlaps(paddr, len))
+}
+
+pub(crate) fn add_reserved_region(reserved: ReservedRegion) {
+    RESERVED_REGIONS.lock().push(reserved);
+}
+
+pub(crate) fn reserve_early(reserved: ReservedRegion) -> ResultWithErr<MmError> {
+    let mut early = EARLY_RESERVED.lock();
+    if early.sealed {
+        mork_kernel_log!(warn, "{} reserved after the allocators are up, {:#x?}", reserved.name, reserved.region);
+        return Err(MmError::InvalidParam);
+    }
+    let Some(slot) = early.regions.iter_mut().find(|slot| slot.is_none()) else {
+        mork_kernel_log!(warn, "too many early reserved regions, drop {}", reserved.name);
+        return Err(MmError::OutOfMemory);
+    };
+    *slot = Some(reserved);
+    Ok(())
+}
+
+// later reservations are refused once the allocators have been handed the free memory
+pub(crate) fn seal_early_reserved() -> [Option<ReservedRegion>; EARLY_RESERVED_MAX] {
+    let mut early = EARLY_RESERVED.lock();
+    early.sealed = true;
+    early.regions
+}
+
+// hands part of a reserved region back, e.g. the init sections of the kernel image
+pub(crate) fn release_reserved(name: &str, released: MemoryRegion) {
+    let mut reserved = RESERVED_REGIONS.lock();
+    let mut kept = Vec::with_capacity(reserved.len() + 1);
+    for region in reserved.drain(..) {
+        if region.name != name {
+            kept.push(region);
+            continue;
+        }
+        subtract(region.region, core::iter::once(released), &mut |start, end| {
+            kept.push(ReservedRegion { region: MemoryRegion { start, end }, ..region });
+        });
+    }
+    *reserved = kept;
+}
+
+pub fn reserved_regions() -> Vec<ReservedRegion> {
+    RESERVED_REGIONS.lock().clone()
+}
+
+pub fn find_reserved(name: &str) -> Option<ReservedRegion> {
+    RESERVED_REGIONS.lock().iter().find(|reserved| reserved.name == name).copied()
+}
+
+pub fn is_reserved(paddr: PhysAddr, len: usize) -> bool {
+    RESERVED_REGIONS.lock().iter().any(|reserved| reserved.region.overlaps(paddr, len))
+}
+
+// ram pieces of the window, no_map reserved ranges are cut out
+pub(crate) fn for_each_mapped_ram(mut f: impl FnMut(PhysAddr, PhysAddr)) {
+    let reserved = RESERVED_REGIONS.lock();
+    let holes = reserved.iter()
+        .filter(|reserved| reserved.no_map)
+        .map(|reserved| page_round_out(reserved.region));
+    for region in RAM_REGIONS.lock().iter() {
+        subtract(page_round_in(*region), holes.clone(), &mut f);
+    }
+}
+
+fn subtract<I>(region: MemoryRegion, holes: I, f: &mut impl FnMut(PhysAddr, PhysAddr))
+    where I: Iterator<Item = MemoryRegion> + Clone {
+    let mut cursor = region.start;
+    while cursor < region.end {
+        let next_hole = holes.clone()
+            .filter(|hole| hole.end > cursor && hole.start < region.end)
+            .min_by_key(|hole| hole.start);
+        let Some(hole) = next_hole else {
+            f(cursor, region.end);
+            break;
+        };
+        if hole.start > cursor {
+            f(cursor, hole.start);
+        }
+        cursor = hole.end;
+    }
+}
+
+fn page_round_in(region: MemoryRegion) -> MemoryRegion {
+    MemoryRegion {
+        start: PhysAddr::new((region.start.as_usize() + 4096 - 1) & !(4096 - 1)),
+        end: PhysAddr::new(region.end.as_usize() & !(4096 - 1)),
+    }
+}
+
+fn page_round_out(region: MemoryRegion) -> MemoryRegion {
+    MemoryRegion {
+        start: PhysAddr::new(region.start.as_usize() & !(4096 - 1)),
+        end: PhysAddr::new((region.end.as_usize() + 4096 - 1) & !(4096 - 1)),
+    }
+}
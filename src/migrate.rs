@@ -0,0 +1,95 @@
+use core::sync::atomic::Ordering;
+use alloc::vec::Vec;
+use mork_common::mork_kernel_log;
+use mork_common::types::ResultWithErr;
+use mork_hal::config::HAL_PAGE_LEVEL;
+use crate::addr::{phys_to_virt, PhysAddr};
+use crate::attributes::MapAttributes;
+use crate::error::MmError;
+use crate::frame::{self, FRAME_SIZE};
+use crate::frame_info::{self, FrameFlags};
+use crate::rmap::{self, Mapping};
+
+// moves a user frame to new_pfn, a frame the caller allocated, and frees the old frame.
+// the address spaces mapping it must not change meanwhile, the caller serializes migration
+// with map and unmap like any other page table update
+pub fn migrate_frame(old_pfn: usize, new_pfn: usize) -> ResultWithErr<MmError> {
+    let (Some(old_info), Some(new_info)) = (frame_info::frame_info(old_pfn), frame_info::frame_info(new_pfn)) else {
+        mork_kernel_log!(warn, "frame outside of ram, {:#x}, {:#x}", old_pfn, new_pfn);
+        return Err(MmError::InvalidAddress);
+    };
+    // kernel frames are referenced through the kernel window, only user frames can move
+    if !old_info.flags().contains(FrameFlags::ALLOCATED | FrameFlags::USER) {
+        mork_kernel_log!(warn, "frame {:#x} is not a user frame", old_pfn);
+        return Err(MmError::InvalidParam);
+    }
+    // a pinned page is promised to stay on its frame, e.g. for dma
+    if frame_info::is_pinned(old_pfn) {
+        mork_kernel_log!(warn, "frame {:#x} is pinned", old_pfn);
+        return Err(MmError::InUse);
+    }
+    if old_pfn == new_pfn || !new_info.flags().contains(FrameFlags::ALLOCATED)
+        || new_info.mapcount.load(Ordering::Acquire) != 0 {
+        mork_kernel_log!(warn, "frame {:#x} cannot take over frame {:#x}", new_pfn, old_pfn);
+        return Err(MmError::InvalidParam);
+    }
+    let (old, new) = (PhysAddr::from_ppn(old_pfn), PhysAddr::from_ppn(new_pfn));
+    let mappings = rmap::mappings(old_pfn);
+    let mut attrs = Vec::with_capacity(mappings.len());
+    for mapping in &mappings {
+        match mapping.wrapper().translate(mapping.vaddr) {
+            // huge leaves only move as a whole
+            Some((paddr, level, leaf_attrs)) if paddr == phys_to_virt(old).as_usize()
+                && level == HAL_PAGE_LEVEL - 1 => attrs.push(leaf_attrs),
+            _ => {
+                mork_kernel_log!(warn, "mapping at {:#x} keeps frame {:#x} in place", mapping.vaddr, old_pfn);
+                return Err(MmError::InvalidParam);
+            }
+        }
+    }
+
+    // writes landing after the copy would be lost, the mappings stay read-only until they point
+    // to the copy. a write fault in between finds the page mapped and retries
+    let result = mappings.iter().zip(&attrs)
+        .filter(|(_, attrs)| attrs.is_w())
+        .try_for_each(|(mapping, attrs)| {
+            mapping.wrapper().protect_range(mapping.vaddr, FRAME_SIZE, *attrs - MapAttributes::WRITE)
+        });
+    if let Err(err) = result {
+        restore(&mappings, &attrs);
+        return Err(err);
+    }
+    unsafe {
+        core::ptr::copy_nonoverlapping(phys_to_virt(old).as_usize() as *const u8,
+                                       phys_to_virt(new).as_usize() as *mut u8, FRAME_SIZE);
+    }
+    for (index, mapping) in mappings.iter().enumerate() {
+        if let Err(err) = mapping.wrapper().replace_frame(mapping.vaddr, new, attrs[index]) {
+            // the moved mappings go back, no two address spaces may see different contents
+            for (mapping, attrs) in mappings[..index].iter().zip(&attrs) {
+                let _ = mapping.wrapper().replace_frame(mapping.vaddr, old, *attrs);
+            }
+            restore(&mappings, &attrs);
+            return Err(err);
+        }
+    }
+    new_info.insert_flags(FrameFlags::USER);
+    new_info.owner.store(old_info.owner.load(Ordering::Relaxed), Ordering::Relaxed);
+    // the holders of a shared frame now hold the new one, the old frame goes right away
+    new_info.refcount.store(old_info.refcount.swap(1, Ordering::AcqRel), Ordering::Relaxed);
+    if old_info.flags().contains(FrameFlags::MERGED) {
+        new_info.insert_flags(FrameFlags::MERGED);
+        old_info.remove_flags(FrameFlags::MERGED);
+    }
+    unsafe {
+        frame::dealloc_frame(phys_to_virt(old).as_usize());
+    }
+    Ok(())
+}
+
+// gives every mapping its attributes back, wherever it points to by now
+fn restore(mappings: &[Mapping], attrs: &[MapAttributes]) {
+    for (mapping, attrs) in mappings.iter().zip(attrs) {
+        let _ = mapping.wrapper().protect_range(mapping.vaddr, FRAME_SIZE, *attrs);
+    }
+}
@@ -0,0 +1,32 @@
+use core::alloc::Layout;
+use core::sync::atomic::{AtomicBool, Ordering};
+use mork_common::mork_kernel_log;
+use crate::sync::IrqMutex;
+
+pub trait OomHandler: Send + Sync {
+    // frees memory for layout, e.g. by reclaiming pages or tearing down a victim address space,
+    // returns whether the allocation is worth retrying
+    fn handle_oom(&self, layout: Layout) -> bool;
+}
+
+static OOM_HANDLER: IrqMutex<Option<&'static dyn OomHandler>> = IrqMutex::new(None);
+// allocations made by the handler itself must not recurse into it
+static IN_OOM: AtomicBool = AtomicBool::new(false);
+
+pub fn set_handler(handler: &'static dyn OomHandler) {
+    *OOM_HANDLER.lock() = Some(handler);
+}
+
+// returns whether the failed allocation should be retried
+pub(crate) fn handle(layout: Layout) -> bool {
+    let Some(handler) = *OOM_HANDLER.lock() else {
+        return false;
+    };
+    if IN_OOM.swap(true, Ordering::AcqRel) {
+        return false;
+    }
+    mork_kernel_log!(warn, "out of memory, size: {:#x}, align: {:#x}", layout.size(), layout.align());
+    let retry = handler.handle_oom(layout);
+    IN_OOM.store(false, Ordering::Release);
+    retry
+}
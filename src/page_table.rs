@@ -1,15 +1,61 @@
-use alloc::boxed::Box;
-use alloc::format;
-use alloc::string::String;
+use core::cell::UnsafeCell;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use alloc::vec::Vec;
 use mork_capability::cap::PageTableCap;
 use mork_common::types::ResultWithErr;
 use mork_common::utils::alignas::is_aligned;
 use mork_common::mork_kernel_log;
-use mork_common::syscall::message_info::ResponseLabel;
-use mork_hal::config::{HAL_PAGE_LEVEL, PAGE_SIZE_NORMAL};
+use mork_hal::config::HAL_PAGE_LEVEL;
 use mork_hal::KERNEL_OFFSET;
 use mork_hal::mm::{PageTableEntryImpl, PageTableImpl};
+use crate::addr::{is_user_range, phys_to_virt, virt_to_phys, PhysAddr, VirtAddr};
+use crate::attributes::MapAttributes;
+use crate::error::MmError;
+use crate::frame::FRAME_SIZE;
+use crate::frame_info::{self, FrameFlags};
 use crate::page_table::SearchResult::{Found, Missing};
+use crate::shootdown::{online_harts, shootdown, FlushRequest};
+use crate::soft_pte::{self, SoftPte};
+use crate::stats::MemoryStats;
+use crate::tlb::TlbBatch;
+use crate::{frame, kaslr, kernel_protect, kernel_template, memory, policy, rmap, swap, tlb};
+
+pub(crate) const PTE_COUNT: usize = 4096 / size_of::<PageTableEntryImpl>();
+
+// attributes of a leaf that protect_range carries over instead of taking them from the caller
+const PROTECT_KEPT: MapAttributes = MapAttributes::COW.union(MapAttributes::PROMOTED).union(MapAttributes::GLOBAL)
+    .union(MapAttributes::NON_CACHEABLE).union(MapAttributes::DEVICE).union(MapAttributes::ACCESSED)
+    .union(MapAttributes::DIRTY);
+
+// what the hal sets for every kernel leaf
+const KERNEL_LEAF_ATTRS: MapAttributes = MapAttributes::READ.union(MapAttributes::WRITE)
+    .union(MapAttributes::EXECUTE).union(MapAttributes::GLOBAL).union(MapAttributes::ACCESSED)
+    .union(MapAttributes::DIRTY);
+
+static PAGE_TABLE_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+pub fn page_table_frames() -> usize {
+    PAGE_TABLE_FRAMES.load(Ordering::Relaxed)
+}
+
+// one bit per table in BOOT_TABLES
+const BOOT_TABLE_COUNT: usize = 64;
+
+#[repr(C, align(4096))]
+struct BootTables(UnsafeCell<[[u8; 4096]; BOOT_TABLE_COUNT]>);
+
+unsafe impl Sync for BootTables {}
+
+// page tables for the kernel window and the root task, independent of the heap
+static BOOT_TABLES: BootTables = BootTables(UnsafeCell::new([[0; 4096]; BOOT_TABLE_COUNT]));
+static BOOT_TABLES_USED: AtomicU64 = AtomicU64::new(0);
+
+fn boot_table_index(ptr: usize) -> Option<usize> {
+    let base = BOOT_TABLES.0.get() as usize;
+    (base..base + BOOT_TABLE_COUNT * 4096).contains(&ptr).then(|| (ptr - base) / 4096)
+}
 
 #[repr(C, align(4096))]
 #[derive(Clone, Copy)]
@@ -22,19 +68,171 @@ impl PageTable {
         Self { page_table_impl: PageTableImpl::new() }
     }
 
+    // a zeroed frame straight from the frame allocator, nothing is built on the stack
+    pub fn alloc_owned() -> Option<OwnedPageTable> {
+        Self::alloc().map(OwnedPageTable)
+    }
+
+    pub fn alloc() -> Option<&'static mut Self> {
+        let paddr = frame::alloc_frame()?;
+        frame_info::mark_allocated(paddr, FRAME_SIZE, FrameFlags::PAGE_TABLE);
+        PAGE_TABLE_FRAMES.fetch_add(1, Ordering::Relaxed);
+        Some(unsafe { &mut *(paddr as *mut Self) })
+    }
+
+    // falls back to the heap once the pool is used up
+    pub fn alloc_boot() -> Option<&'static mut Self> {
+        let mut used = BOOT_TABLES_USED.load(Ordering::Relaxed);
+        while used != u64::MAX {
+            let index = used.trailing_ones() as usize;
+            match BOOT_TABLES_USED.compare_exchange_weak(used, used | (1 << index), Ordering::Acquire,
+                                                         Ordering::Relaxed) {
+                Ok(_) => {
+                    PAGE_TABLE_FRAMES.fetch_add(1, Ordering::Relaxed);
+                    let table = unsafe { &mut (*BOOT_TABLES.0.get())[index] };
+                    return Some(unsafe { &mut *(table as *mut [u8; 4096] as *mut Self) });
+                }
+                Err(current) => used = current,
+            }
+        }
+        mork_kernel_log!(warn, "boot page table pool exhausted, fall back to the heap");
+        Self::alloc()
+    }
+
+    fn alloc_from(boot: bool) -> Option<&'static mut Self> {
+        if boot { Self::alloc_boot() } else { Self::alloc() }
+    }
+
+    pub fn new_user() -> Option<&'static mut Self> {
+        let page_table = Self::alloc()?;
+        // the kernel entries share the trampoline table, so the trampoline shows up here as well
+        kernel_template::init_user_root(page_table);
+        Some(page_table)
+    }
+
+    pub fn free(&mut self) {
+        if let Some(index) = boot_table_index(self.get_ptr()) {
+            // pool tables are handed out zeroed, like frames
+            unsafe {
+                core::ptr::write_bytes(self.get_ptr() as *mut u8, 0, 4096);
+            }
+            BOOT_TABLES_USED.fetch_and(!(1 << index), Ordering::Release);
+        } else {
+            unsafe {
+                frame::dealloc_frame(self.get_ptr());
+            }
+        }
+        soft_pte::forget_table(self.get_ptr());
+        PAGE_TABLE_FRAMES.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    // the hardware only gets the permissions, the rest of attrs is kept in the soft pte store.
+    // accessed and dirty can not be read back, the leaf counts as accessed and, if writable, as dirty
+    pub fn map_user_frame(&mut self, vaddr: usize, paddr: PhysAddr, level: usize, attrs: MapAttributes) {
+        self.page_table_impl
+            .map_frame_for_user(vaddr, paddr.as_usize(), level, attrs.is_x(), attrs.is_w(), attrs.is_r());
+        let mut attrs = attrs | MapAttributes::USER | MapAttributes::ACCESSED;
+        if attrs.is_w() {
+            attrs |= MapAttributes::DIRTY;
+        }
+        let index = PageTableImpl::get_index(vaddr, level).unwrap();
+        soft_pte::set(self.get_ptr(), index, SoftPte::Leaf(attrs));
+    }
+
+    // the hal maps kernel leaves with fixed permissions. kernel leaves stay out of the soft pte store,
+    // vmalloc maps them on behalf of the global allocator which the store allocates from
+    pub fn map_kernel_frame(&mut self, vaddr: usize, paddr: PhysAddr, level: usize) {
+        self.page_table_impl.map_frame_for_kernel(vaddr, paddr.as_usize(), level);
+    }
+
+    pub(crate) fn unmap_user_leaf(&mut self, vaddr: usize, level: usize) {
+        self.page_table_impl.unmap_frame(vaddr, level);
+        soft_pte::take(self.get_ptr(), PageTableImpl::get_index(vaddr, level).unwrap());
+    }
+
+    // also drops a swap entry or the attributes of a leaf the entry held
+    pub(crate) fn clear_entry(&mut self, index: usize) {
+        self.page_table_impl[index] = PageTableEntryImpl::default();
+        soft_pte::take(self.get_ptr(), index);
+    }
+
+    // the swap slot an empty entry refers to
+    pub(crate) fn swap_slot(&self, index: usize) -> Option<usize> {
+        match soft_pte::get(self.get_ptr(), index) {
+            Some(SoftPte::Swapped(slot)) if !self.page_table_impl[index].valid() => Some(slot),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn leaf_attrs(&self, index: usize) -> MapAttributes {
+        match soft_pte::get(self.get_ptr(), index) {
+            Some(SoftPte::Leaf(attrs)) => attrs,
+            _ => KERNEL_LEAF_ATTRS,
+        }
+    }
+
+    pub fn paddr(&self) -> PhysAddr {
+        virt_to_phys(VirtAddr::new(self.get_ptr())).expect("page table outside of kernel window")
+    }
+
     pub fn get_ptr(&self) -> usize {
         self as *const _ as usize
     }
-    pub fn from_cap(cap: &PageTableCap) -> & mut Self {
+    // the capability names a table the kernel owns, the reference does not borrow from it
+    #[allow(clippy::mut_from_ref)]
+    pub fn from_cap(cap: &PageTableCap) -> &mut Self {
         unsafe {
-            &mut *((cap.base_ptr() << 12) as usize as *mut Self)
+            &mut *((cap.base_ptr() << 12) as *mut Self)
         }
     }
 }
 
+impl Default for PageTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// frees the table itself on drop, the tables below it stay with whoever built them
+pub struct OwnedPageTable(&'static mut PageTable);
+
+impl OwnedPageTable {
+    pub fn leak(self) -> &'static mut PageTable {
+        let owned = ManuallyDrop::new(self);
+        unsafe { core::ptr::read(&owned.0) }
+    }
+}
+
+impl Deref for OwnedPageTable {
+    type Target = PageTable;
+
+    fn deref(&self) -> &PageTable {
+        self.0
+    }
+}
+
+impl DerefMut for OwnedPageTable {
+    fn deref_mut(&mut self) -> &mut PageTable {
+        self.0
+    }
+}
+
+impl Drop for OwnedPageTable {
+    fn drop(&mut self) {
+        self.0.free();
+    }
+}
+
 pub struct MutPageTableWrapper<'a> {
     page_table: &'a mut PageTable,
     level: usize,
+    // identifies the address space in the reverse map, also for wrappers below the root
+    root: usize,
+    asid: Option<usize>,
+    harts: usize,
+    batch: Option<TlbBatch>,
+    stats: Option<&'a mut MemoryStats>,
+    boot: bool,
 }
 
 pub enum SearchResult<'a> {
@@ -49,152 +247,589 @@ pub struct PageTableWrapper <'a> {
 impl<'a> MutPageTableWrapper<'a> {
     pub fn new(root: &'a mut PageTable) -> Self {
         Self {
-            page_table: root,
             level: 0,
+            root: root.get_ptr(),
+            page_table: root,
+            asid: None,
+            harts: 0,
+            batch: None,
+            stats: None,
+            boot: false,
         }
     }
 
-    pub fn map_kernel(&mut self, vaddr: usize, paddr: usize) -> Result<usize, String> {
-        let aligned_size = PageTableImpl::get_size(0).unwrap();
-        if !is_aligned(vaddr, aligned_size) || !is_aligned(paddr, aligned_size) {
-            return Err(format!("Kernel map vaddr must aligned for the first level, vaddr: {:#x}, {:#x}", vaddr, paddr));
+    pub fn with_asid(mut self, asid: usize) -> Self {
+        self.asid = Some(asid);
+        self
+    }
+
+    pub fn with_harts(mut self, harts: usize) -> Self {
+        self.harts = harts;
+        self
+    }
+
+    pub fn with_stats(mut self, stats: &'a mut MemoryStats) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    // intermediate tables come from the static boot pool
+    pub fn with_boot_pool(mut self) -> Self {
+        self.boot = true;
+        self
+    }
+
+    pub fn begin_batch(&mut self) {
+        if self.batch.is_none() {
+            self.batch = Some(TlbBatch::new(self.asid, self.harts));
+        }
+    }
+
+    pub fn finish_batch(&mut self) {
+        if let Some(mut batch) = self.batch.take() {
+            batch.flush();
+        }
+    }
+
+    pub fn map_kernel(&mut self, vaddr: usize, paddr: usize, max_size: usize) -> Result<usize, MmError> {
+        let Some(level) = (0..HAL_PAGE_LEVEL)
+            .find(|level| {
+                let size = PageTableImpl::get_size(*level).unwrap();
+                size <= max_size && is_aligned(vaddr, size) && is_aligned(paddr, size)
+            }) else {
+            mork_kernel_log!(warn, "Kernel map vaddr must be page aligned, vaddr: {:#x}, {:#x}", vaddr, paddr);
+            return Err(MmError::AlignmentError);
+        };
+        let phys = to_phys(paddr)?;
+        let page_table = self.search_or_populate(vaddr, level)?;
+        page_table.map_kernel_frame(vaddr, phys, level);
+        Ok(PageTableImpl::get_size(level).unwrap())
+    }
+
+    pub fn map_kernel_region(&mut self, start: usize, end: usize, attrs: MapAttributes) -> ResultWithErr<MmError> {
+        if !is_aligned(start, 4096) || !is_aligned(end, 4096) {
+            mork_kernel_log!(warn, "kernel region must be page aligned, {:#x}, {:#x}", start, end);
+            return Err(MmError::AlignmentError);
+        }
+        attrs.validate()?;
+        let mut current = start;
+        while current < end {
+            let level = (0..HAL_PAGE_LEVEL)
+                .find(|level| {
+                    let size = PageTableImpl::get_size(*level).unwrap();
+                    is_aligned(current, size) && current + size <= end
+                })
+                .unwrap();
+            let phys = to_phys(current)?;
+            let page_table = self.search_or_populate(current, level)?;
+            page_table.map_kernel_frame(current, phys, level);
+            current += PageTableImpl::get_size(level).unwrap();
+        }
+        Ok(())
+    }
+
+    // creates the tables down to level without mapping anything
+    pub(crate) fn populate(&mut self, vaddr: usize, level: usize) -> ResultWithErr<MmError> {
+        self.search_or_populate(vaddr, level).map(|_| ())
+    }
+
+    // a single 4K page outside of the kernel window, e.g. for vmalloc
+    pub fn map_kernel_page(&mut self, vaddr: usize, paddr: usize, attrs: MapAttributes) -> ResultWithErr<MmError> {
+        let phys = to_phys(paddr)?;
+        self.map_kernel_phys(vaddr, phys, attrs)
+    }
+
+    // like map_kernel_page for frames outside of ram, such as device registers
+    pub fn map_kernel_phys(&mut self, vaddr: usize, paddr: PhysAddr, attrs: MapAttributes) -> ResultWithErr<MmError> {
+        if vaddr < KERNEL_OFFSET {
+            mork_kernel_log!(warn, "vaddr out of kernel space, {:#x}", vaddr);
+            return Err(MmError::InvalidAddress);
         }
-        let mask = (1usize << 39) - 1;
-        self.page_table.page_table_impl.map_frame_for_kernel(vaddr & mask, paddr - KERNEL_OFFSET, 0);
-        Ok(aligned_size)
+        if !is_aligned(vaddr, 4096) || !is_aligned(paddr.as_usize(), 4096) {
+            mork_kernel_log!(warn, "vaddr/paddr must be aligned, {:#x}, {:#x}", vaddr, paddr.as_usize());
+            return Err(MmError::AlignmentError);
+        }
+        policy::check_user_attrs(attrs)?;
+        let level = HAL_PAGE_LEVEL - 1;
+        let page_table = self.search_or_populate(vaddr, level)?;
+        let index = PageTableImpl::get_index(vaddr, level).unwrap();
+        if page_table.page_table_impl[index].valid() {
+            mork_kernel_log!(warn, "kernel page has been mapped, {:#x}", vaddr);
+            return Err(MmError::AlreadyMapped);
+        }
+        page_table.map_kernel_frame(vaddr, paddr, level);
+        Ok(())
     }
 
-    pub fn map_page_table(&mut self, vaddr: usize, paddr: usize) -> Result<usize, ResponseLabel> {
+    // replaces the huge leaf covering vaddr by 4K leaves with the same attributes, returns whether it split.
+    // the translation does not change, so stale tlb entries of the huge leaf are harmless
+    pub(crate) fn split_kernel_leaf(&mut self, vaddr: usize) -> Result<bool, MmError> {
+        let boot = self.boot;
+        let mut split = false;
+        loop {
+            let Found(level, page_table) = self.search_for_modify(vaddr, HAL_PAGE_LEVEL) else {
+                return Err(MmError::NotMapped);
+            };
+            if level == HAL_PAGE_LEVEL - 1 {
+                return Ok(split);
+            }
+            let paddr = leaf_paddr(&page_table.page_table_impl[PageTableImpl::get_index(vaddr, level).unwrap()]);
+            let base = vaddr & !(PageTableImpl::get_size(level).unwrap() - 1);
+            let size = PageTableImpl::get_size(level + 1).unwrap();
+            let Some(inner_page_table) = PageTable::alloc_from(boot) else {
+                mork_kernel_log!(warn, "fail to allocate page table to split {:#x}", vaddr);
+                return Err(MmError::OutOfMemory);
+            };
+            for index in 0..PTE_COUNT {
+                inner_page_table.map_kernel_frame(base + index * size, paddr + index * size, level + 1);
+            }
+            page_table.page_table_impl.map_page_table(vaddr, inner_page_table.paddr().as_usize(), level);
+            split = true;
+        }
+    }
+
+    // whole leaves go, the ones reaching past the range are split first and holes are skipped.
+    // emptied tables stay for the next mapping of the range
+    pub(crate) fn unmap_kernel_region(&mut self, start: usize, end: usize) -> ResultWithErr<MmError> {
+        let mut current = start;
+        while current < end {
+            let size = match self.search_for_modify(current, HAL_PAGE_LEVEL) {
+                Found(level, page_table) => {
+                    let size = PageTableImpl::get_size(level).unwrap();
+                    if !is_aligned(current, size) || current + size > end {
+                        self.split_kernel_leaf(current)?;
+                        continue;
+                    }
+                    page_table.page_table_impl.unmap_frame(current, level);
+                    size
+                }
+                Missing(level, _) => PageTableImpl::get_size(level).unwrap(),
+            };
+            current = (current & !(size - 1)) + size;
+        }
+        self.flush_space();
+        Ok(())
+    }
+
+    // returns the physical address that was mapped
+    pub fn unmap_kernel_page(&mut self, vaddr: usize) -> Result<PhysAddr, MmError> {
+        if vaddr < KERNEL_OFFSET || !is_aligned(vaddr, 4096) {
+            mork_kernel_log!(warn, "invalid kernel page, {:#x}", vaddr);
+            return Err(MmError::InvalidAddress);
+        }
+        let paddr = match self.search_for_modify(vaddr, HAL_PAGE_LEVEL) {
+            Found(level, page_table) if level == HAL_PAGE_LEVEL - 1 => {
+                let index = PageTableImpl::get_index(vaddr, level).unwrap();
+                let paddr = leaf_paddr(&page_table.page_table_impl[index]);
+                page_table.page_table_impl.unmap_frame(vaddr, level);
+                paddr
+            }
+            _ => {
+                mork_kernel_log!(warn, "kernel page is not mapped, {:#x}", vaddr);
+                return Err(MmError::NotMapped);
+            }
+        };
+        self.flush_page(vaddr);
+        Ok(paddr)
+    }
+
+    pub fn map_page_table(&mut self, vaddr: usize, paddr: usize) -> Result<usize, MmError> {
+        check_user_range(vaddr, 1)?;
         if !is_aligned(vaddr, 4096) || !is_aligned(paddr, 4096) {
             mork_kernel_log!(warn, "vaddr/paddr must be aligned, {:#x}, {:#x}", vaddr, paddr);
-            return Err(ResponseLabel::InvalidParam);
+            return Err(MmError::AlignmentError);
         }
+        let phys = to_phys(paddr)?;
         match self.search_for_modify(vaddr, HAL_PAGE_LEVEL) {
             Missing(level, page_table) => {
                 if level == HAL_PAGE_LEVEL - 1 {
                     mork_kernel_log!(warn, "page table has been mapped, {:#x}, {:#x}", vaddr, paddr);
-                    Err(ResponseLabel::MappedAlready)
+                    Err(MmError::AlreadyMapped)
                 } else {
-                    page_table.page_table_impl.map_page_table(vaddr, paddr - KERNEL_OFFSET, level);
+                    // the table comes from the caller and starts without software state
+                    soft_pte::forget_table(paddr);
+                    page_table.page_table_impl.map_page_table(vaddr, phys.as_usize(), level);
+                    self.account_page_tables(1, 0);
                     Ok(level + 1)
                 }
             }
             _ => {
                 mork_kernel_log!(warn, "frame has been mapped, {:#x}, {:#x}", vaddr, paddr);
-                Err(ResponseLabel::MappedAlready)
+                Err(MmError::AlreadyMapped)
             }
         }
     }
 
-    pub fn map_frame(&mut self, vaddr: usize, paddr: usize, frame_level: usize, is_x: bool, is_w: bool, is_r: bool)
-        -> ResultWithErr<ResponseLabel> {
+    pub fn map_frame(&mut self, vaddr: usize, paddr: usize, frame_level: usize, attrs: MapAttributes)
+        -> ResultWithErr<MmError> {
+        check_user_range(vaddr, PageTableImpl::get_align(frame_level).unwrap())?;
+        policy::check_user_attrs(attrs)?;
         let align = PageTableImpl::get_align(frame_level).unwrap();
         if !is_aligned(vaddr, align) || !is_aligned(paddr, align) {
             mork_kernel_log!(warn, "vaddr/paddr must be aligned, {:#x}, {:#x}", vaddr, paddr);
-            return Err(ResponseLabel::InvalidParam);
+            return Err(MmError::AlignmentError);
         }
+        let phys = to_phys(paddr)?;
+        check_frame(phys, align, attrs)?;
+        self.check_quota(align)?;
         match self.search_for_modify(vaddr, HAL_PAGE_LEVEL) {
             Missing(level, page_table) => {
                 if level == frame_level - 1 {
-                    page_table
-                        .page_table_impl
-                        .map_frame_for_user(
-                            vaddr,
-                            paddr - KERNEL_OFFSET,
-                            level,
-                            is_x, is_w, is_r
-                        );
+                    page_table.map_user_frame(vaddr, phys, level, attrs);
+                    rmap::add(phys, self.root, vaddr);
+                    self.account_resident(align, 0);
                     Ok(())
                 } else {
                     mork_kernel_log!(warn, "page table need to been mapped first, {:#x}, {:#x}", vaddr, paddr);
-                    Err(ResponseLabel::PageTableMiss)
+                    Err(MmError::PageTableMiss)
                 }
             }
             _ => {
                 mork_kernel_log!(warn, "frame has been mapped, {:#x}, {:#x}", vaddr, paddr);
-                Err(ResponseLabel::MappedAlready)
+                Err(MmError::AlreadyMapped)
             }
         }
     }
 
-    pub fn unmap_frame(&mut self, vaddr: usize) -> ResultWithErr<ResponseLabel> {
+    pub fn map_frame_populate(&mut self, vaddr: usize, paddr: usize, frame_level: usize, attrs: MapAttributes)
+        -> ResultWithErr<MmError> {
+        check_user_range(vaddr, PageTableImpl::get_align(frame_level).unwrap())?;
+        policy::check_user_attrs(attrs)?;
+        let align = PageTableImpl::get_align(frame_level).unwrap();
+        if !is_aligned(vaddr, align) || !is_aligned(paddr, align) {
+            mork_kernel_log!(warn, "vaddr/paddr must be aligned, {:#x}, {:#x}", vaddr, paddr);
+            return Err(MmError::AlignmentError);
+        }
+        let phys = to_phys(paddr)?;
+        check_frame(phys, align, attrs)?;
+        self.check_quota(align)?;
+        let level = frame_level - 1;
+        let page_table = self.search_or_populate(vaddr, level)?;
+        let index = PageTableImpl::get_index(vaddr, level).unwrap();
+        if page_table.page_table_impl[index].valid() {
+            mork_kernel_log!(warn, "frame has been mapped, {:#x}, {:#x}", vaddr, paddr);
+            return Err(MmError::AlreadyMapped);
+        }
+        page_table.map_user_frame(vaddr, phys, level, attrs);
+        rmap::add(phys, self.root, vaddr);
+        self.account_resident(align, 0);
+        Ok(())
+    }
+
+    pub fn map_huge_frame(&mut self, vaddr: usize, paddr: usize, level: usize, attrs: MapAttributes)
+        -> ResultWithErr<MmError> {
+        check_user_range(vaddr, 1)?;
+        policy::check_user_attrs(attrs)?;
+        if level >= HAL_PAGE_LEVEL - 1 {
+            mork_kernel_log!(warn, "huge frame level must be below the last level, level: {}", level);
+            return Err(MmError::InvalidLevel);
+        }
+        let size = PageTableImpl::get_size(level).ok_or(MmError::InvalidLevel)?;
+        if !is_aligned(vaddr, size) || !is_aligned(paddr, size) {
+            mork_kernel_log!(warn, "vaddr/paddr must be aligned to {:#x}, {:#x}, {:#x}", size, vaddr, paddr);
+            return Err(MmError::AlignmentError);
+        }
+        let phys = to_phys(paddr)?;
+        check_frame(phys, size, attrs)?;
+        self.check_quota(size)?;
+        match self.search_for_modify(vaddr, level + 1) {
+            Missing(level_inner, page_table) if level_inner == level => {
+                page_table.map_user_frame(vaddr, phys, level, attrs);
+                rmap::add(phys, self.root, vaddr);
+                self.account_resident(size, 0);
+                Ok(())
+            }
+            Missing(level_inner, _) if level_inner < level => {
+                mork_kernel_log!(warn, "page table need to been mapped first, {:#x}, {:#x}", vaddr, paddr);
+                Err(MmError::PageTableMiss)
+            }
+            _ => {
+                mork_kernel_log!(warn, "vaddr has been mapped, {:#x}, {:#x}", vaddr, paddr);
+                Err(MmError::AlreadyMapped)
+            }
+        }
+    }
+
+    pub fn map_range(&mut self, vaddr: usize, paddr: usize, len: usize, attrs: MapAttributes)
+        -> ResultWithErr<MmError> {
+        check_user_range(vaddr, len)?;
+        policy::check_user_attrs(attrs)?;
+        if len == 0 || !is_aligned(vaddr, 4096) || !is_aligned(paddr, 4096) || !is_aligned(len, 4096) {
+            mork_kernel_log!(warn, "vaddr/paddr/len must be aligned, {:#x}, {:#x}, {:#x}", vaddr, paddr, len);
+            return Err(MmError::AlignmentError);
+        }
+        let phys = to_phys(paddr)?;
+        check_frame(phys, len, attrs)?;
+        to_phys(paddr + len - 1)?;
+        self.check_quota(len)?;
+        for offset in (0..len).step_by(4096) {
+            if let Found(_, _) = self.search_for_modify(vaddr + offset, HAL_PAGE_LEVEL) {
+                mork_kernel_log!(warn, "vaddr has been mapped, {:#x}", vaddr + offset);
+                return Err(MmError::AlreadyMapped);
+            }
+        }
+        let frame_level = HAL_PAGE_LEVEL - 1;
+        for offset in (0..len).step_by(4096) {
+            let page_table = match self.search_or_populate(vaddr + offset, frame_level) {
+                Ok(page_table) => page_table,
+                Err(err) => {
+                    // all or nothing, the pages mapped so far go again
+                    self.rollback_range(vaddr, offset, phys);
+                    return Err(err);
+                }
+            };
+            page_table.map_user_frame(vaddr + offset, phys + offset, frame_level, attrs);
+            rmap::add(phys + offset, self.root, vaddr + offset);
+            self.account_resident(4096, 0);
+        }
+        self.flush_space();
+        Ok(())
+    }
+
+    // undoes the 4K leaves map_range made for [vaddr, vaddr + len), the tables stay
+    fn rollback_range(&mut self, vaddr: usize, len: usize, phys: PhysAddr) {
+        for offset in (0..len).step_by(4096) {
+            if let Found(level, page_table) = self.search_for_modify(vaddr + offset, HAL_PAGE_LEVEL) {
+                page_table.unmap_user_leaf(vaddr + offset, level);
+                rmap::remove(phys + offset, self.root, vaddr + offset);
+                self.account_resident(0, 4096);
+            }
+        }
+        self.flush_space();
+    }
+
+    pub fn unmap_frame(&mut self, vaddr: usize) -> ResultWithErr<MmError> {
+        check_user_range(vaddr, 1)?;
         if !is_aligned(vaddr, 4096) {
             mork_kernel_log!(warn, "vaddr must be aligned, {:#x}", vaddr);
-            return Err(ResponseLabel::InvalidParam);
+            return Err(MmError::AlignmentError);
         }
+        self.demote_edges(vaddr, vaddr + FRAME_SIZE)?;
         match self.search_for_modify(vaddr, HAL_PAGE_LEVEL) {
             Found(level, page_table) => {
                 mork_kernel_log!(debug, "found frame in level {} page table, vaddr: {:#x}",
                     level, vaddr);
-                page_table.page_table_impl.unmap_frame(vaddr, level);
-                Ok(())
+                let paddr = leaf_paddr(&page_table.page_table_impl[PageTableImpl::get_index(vaddr, level).unwrap()]);
+                page_table.unmap_user_leaf(vaddr, level);
+                rmap::remove(paddr, self.root, vaddr);
+                self.account_resident(0, PageTableImpl::get_size(level).unwrap());
             }
             Missing(level, _) => {
                 mork_kernel_log!(warn, "fail to lookup vaddr {:#x}, level: {}", vaddr, level);
-                Err(ResponseLabel::InvalidParam)
+                return Err(MmError::NotMapped);
+            }
+        }
+        self.flush_page(vaddr);
+        Ok(())
+    }
+
+    pub fn unmap_range(&mut self, vaddr: usize, len: usize) -> Result<Vec<usize>, MmError> {
+        check_user_range(vaddr, len)?;
+        if len == 0 || !is_aligned(vaddr, 4096) || !is_aligned(len, 4096) {
+            mork_kernel_log!(warn, "vaddr/len must be aligned, {:#x}, {:#x}", vaddr, len);
+            return Err(MmError::AlignmentError);
+        }
+        let end = vaddr + len;
+        self.split_edges(vaddr, end)?;
+        self.check_range(vaddr, end, true)?;
+
+        let nested = self.batch.is_some();
+        self.begin_batch();
+        let mut frames = Vec::new();
+        let mut current = vaddr;
+        while current < end {
+            match self.search_for_modify(current, HAL_PAGE_LEVEL) {
+                Found(level, page_table) => {
+                    let index = PageTableImpl::get_index(current, level).unwrap();
+                    let pte = page_table.page_table_impl[index];
+                    let size = PageTableImpl::get_size(level).unwrap();
+                    let attrs = page_table.leaf_attrs(index);
+                    page_table.unmap_user_leaf(current, level);
+                    if is_merged(leaf_paddr(&pte)) {
+                        self.account_merged(0, size);
+                    }
+                    for (phys, page, _) in leaf_frames(leaf_paddr(&pte), current, size, attrs) {
+                        rmap::remove(phys, self.root, page);
+                        let paddr = phys_to_virt(phys).as_usize();
+                        // a frame still shared with other mappings stays with them
+                        if !frame::is_zero_page(paddr) && !frame_info::put_shared(phys) {
+                            frames.push(paddr);
+                        }
+                    }
+                    self.account_resident(0, size);
+                    self.flush_page(current);
+                    current += size;
+                }
+                Missing(level, page_table) => {
+                    let index = PageTableImpl::get_index(current, level).unwrap();
+                    if let Some(slot) = page_table.swap_slot(index) {
+                        page_table.clear_entry(index);
+                        swap::free_slot(slot);
+                    }
+                    let size = PageTableImpl::get_size(level).unwrap();
+                    current = (current & !(size - 1)) + size;
+                }
+            }
+        }
+        if !nested {
+            self.finish_batch();
+        }
+        Ok(frames)
+    }
+
+    pub fn protect_range(&mut self, vaddr: usize, len: usize, attrs: MapAttributes)
+        -> ResultWithErr<MmError> {
+        check_user_range(vaddr, len)?;
+        // taken from the leaves themselves, callers may pass attributes they read back
+        let attrs = attrs - PROTECT_KEPT;
+        policy::check_user_attrs(attrs)?;
+        if len == 0 || !is_aligned(vaddr, 4096) || !is_aligned(len, 4096) {
+            mork_kernel_log!(warn, "vaddr/len must be aligned, {:#x}, {:#x}", vaddr, len);
+            return Err(MmError::AlignmentError);
+        }
+        let end = vaddr + len;
+        self.split_edges(vaddr, end)?;
+        self.check_range(vaddr, end, false)?;
+
+        let nested = self.batch.is_some();
+        self.begin_batch();
+        let mut current = vaddr;
+        while current < end {
+            if let Found(level, page_table) = self.search_for_modify(current, HAL_PAGE_LEVEL) {
+                let index = PageTableImpl::get_index(current, level).unwrap();
+                let pte = page_table.page_table_impl[index];
+                // only the permissions change, a cow leaf stays cow even while it is read-only
+                let mut leaf_attrs = attrs | (page_table.leaf_attrs(index) & PROTECT_KEPT);
+                // a shared frame turns writable only through the cow fault
+                if leaf_attrs.is_cow() {
+                    leaf_attrs -= MapAttributes::WRITE;
+                }
+                page_table.map_user_frame(current, leaf_paddr(&pte), level, leaf_attrs);
+                self.flush_page(current);
+                current += PageTableImpl::get_size(level).unwrap();
+            } else {
+                break;
+            }
+        }
+        if !nested {
+            self.finish_batch();
+        }
+        Ok(())
+    }
+
+    // returns whether the leaf at vaddr was accessed since the last call
+    pub fn get_and_clear_accessed(&mut self, vaddr: usize) -> Result<bool, MmError> {
+        let Found(level, page_table) = self.search_for_modify(vaddr, HAL_PAGE_LEVEL) else {
+            return Err(MmError::NotMapped);
+        };
+        let index = PageTableImpl::get_index(vaddr, level).unwrap();
+        let attrs = page_table.leaf_attrs(index);
+        if !attrs.contains(MapAttributes::ACCESSED) {
+            return Ok(false);
+        }
+        // the hardware bit can not be read through the hal, only the soft one is cleared.
+        // it is set again when the page is mapped anew, so eviction degrades to a fifo with one more round
+        soft_pte::set(page_table.get_ptr(), index, SoftPte::Leaf(attrs - MapAttributes::ACCESSED));
+        Ok(true)
+    }
+
+    pub fn is_dirty(&self, vaddr: usize) -> Result<bool, MmError> {
+        let (_, _, attrs) = self.translate(vaddr).ok_or(MmError::NotMapped)?;
+        Ok(attrs.contains(MapAttributes::DIRTY))
+    }
+
+    // turns the unmapped leaf slot of vaddr into a swap entry, the hardware entry stays invalid
+    pub(crate) fn set_swap_entry(&mut self, vaddr: usize, slot: usize) -> ResultWithErr<MmError> {
+        match self.search_for_modify(vaddr, HAL_PAGE_LEVEL) {
+            Missing(level, page_table) if level == HAL_PAGE_LEVEL - 1 => {
+                let index = PageTableImpl::get_index(vaddr, level).unwrap();
+                soft_pte::set(page_table.get_ptr(), index, SoftPte::Swapped(slot));
+                Ok(())
             }
+            Missing(_, _) => Err(MmError::PageTableMiss),
+            Found(_, _) => Err(MmError::AlreadyMapped),
         }
     }
 
-    pub fn unmap_page_table(&mut self, vaddr: usize, paddr: usize, level: usize) -> ResultWithErr<ResponseLabel> {
+    pub(crate) fn swap_entry(&mut self, vaddr: usize) -> Option<usize> {
+        match self.search_for_modify(vaddr, HAL_PAGE_LEVEL) {
+            Missing(level, page_table) if level == HAL_PAGE_LEVEL - 1 => {
+                let index = PageTableImpl::get_index(vaddr, level).unwrap();
+                page_table.swap_slot(index)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn unmap_page_table(&mut self, vaddr: usize, paddr: usize, level: usize) -> ResultWithErr<MmError> {
+        check_user_range(vaddr, 1)?;
         if !is_aligned(vaddr, 4096) {
             mork_kernel_log!(warn, "vaddr must be aligned, {:#x}", vaddr);
-            return Err(ResponseLabel::InvalidParam);
+            return Err(MmError::AlignmentError);
         }
         match self.search_for_modify(vaddr, level - 1)  {
             Found(_, _) => {
                 mork_kernel_log!(warn, "mapped frame founded, unmap frame first, vaddr: {:#x}", vaddr);
-                Err(ResponseLabel::MappedAlready)
+                return Err(MmError::AlreadyMapped);
             }
             Missing(level_inner, page_table) => {
                 let index = PageTableImpl::get_index(vaddr, level_inner).unwrap();
                 let pte = page_table.page_table_impl[index];
-                unsafe {
-                    if pte.get_page_table().get_ptr() != paddr {
-                        mork_kernel_log!(warn, "page table not matched, target paddr: {:#x}, get paddr: {:#x}",
-                            paddr, pte.get_page_table().get_ptr());
-                        return Err(ResponseLabel::InvalidParam);
-                    }
-                    page_table.page_table_impl[index] = PageTableEntryImpl::default();
-                    Ok(())
+                if next_table(&pte) as usize != paddr {
+                    mork_kernel_log!(warn, "page table not matched, target paddr: {:#x}, get paddr: {:#x}",
+                        paddr, next_table(&pte) as usize);
+                    return Err(MmError::InvalidParam);
                 }
+                page_table.page_table_impl[index] = PageTableEntryImpl::default();
             }
         }
+        self.account_page_tables(0, 1);
+        self.flush_space();
+        Ok(())
     }
-    pub fn map_root_task_frame(&mut self, vaddr: usize, paddr: usize, is_x: bool, is_w: bool, is_r: bool)
-        -> ResultWithErr<String> {
+    pub fn map_root_task_frame(&mut self, vaddr: usize, paddr: usize, attrs: MapAttributes)
+        -> ResultWithErr<MmError> {
+        check_user_range(vaddr, 4096)?;
+        policy::check_user_attrs(attrs)?;
         if !is_aligned(vaddr, 4096) || !is_aligned(paddr, 4096) {
-            return Err(format!("vaddr/paddr must be aligned, {:#x}, {:#x}", vaddr, paddr).into());
+            mork_kernel_log!(warn, "vaddr/paddr must be aligned, {:#x}, {:#x}", vaddr, paddr);
+            return Err(MmError::AlignmentError);
         }
+        let phys = to_phys(paddr)?;
+        check_frame(phys, 4096, attrs)?;
+        self.check_quota(4096)?;
+        let boot = self.boot;
 
         match self.search_for_modify(vaddr, HAL_PAGE_LEVEL) {
             Missing(level, page_table) => {
                 if level == HAL_PAGE_LEVEL - 1 {
                     // mork_kernel_log!(debug, "map_root_task_frame, paddr: {:#x}, vaddr: {:#x}, \
-                    //     is_x: {}, is_w: {}, is_r: {}", paddr, vaddr, is_x, is_w, is_r);
-                    page_table
-                        .page_table_impl
-                        .map_frame_for_user(
-                            vaddr,
-                            paddr - KERNEL_OFFSET,
-                            level,
-                            is_x, is_w, is_r
-                        );
+                    //     attrs: {:?}", paddr, vaddr, attrs);
+                    page_table.map_user_frame(vaddr, phys, level, attrs);
+                    rmap::add(phys, self.root, vaddr);
+                    self.account_resident(4096, 0);
                 } else {
-                    let inner_page_table = Box::leak(Box::new(PageTable::new()));
+                    let inner_page_table = PageTable::alloc_from(boot).ok_or(MmError::OutOfMemory)?;
                     // mork_kernel_log!(debug, "inner_page_table_ptr: {:#x}", inner_page_table.get_ptr());
                     page_table
                         .page_table_impl
                         .map_page_table(
                             vaddr,
-                            inner_page_table.get_ptr() - KERNEL_OFFSET,
+                            inner_page_table.paddr().as_usize(),
                             level,
                         );
-                    let mut wrapper = Self {
+                    self.account_page_tables(1, 0);
+                    let mut wrapper = MutPageTableWrapper {
                         page_table: inner_page_table,
                         level: level + 1,
+                        root: self.root,
+                        asid: self.asid,
+                        harts: self.harts,
+                        batch: None,
+                        stats: self.stats.as_deref_mut(),
+                        boot,
                     };
-                    return wrapper.map_root_task_frame(vaddr, paddr, is_x, is_w, is_r);
+                    return wrapper.map_root_task_frame(vaddr, paddr, attrs);
                 }
             }
             _ => {
@@ -204,6 +839,419 @@ impl<'a> MutPageTableWrapper<'a> {
         Ok(())
     }
 
+    pub fn translate(&self, vaddr: usize) -> Option<(usize, usize, MapAttributes)> {
+        lookup(self.page_table, self.level, vaddr)
+    }
+
+    pub fn teardown(&mut self, mut release_frame: Option<&mut dyn FnMut(usize, usize)>) {
+        let (bytes, tables) = teardown_table(self.page_table, self.level, 0, self.root, &mut release_frame);
+        self.account_resident(0, bytes);
+        self.account_page_tables(0, tables);
+        self.flush_space();
+    }
+
+    pub fn clone_cow(&mut self, src: &mut PageTable) -> ResultWithErr<MmError> {
+        self.clone_table(src, self.level, 0, true)?;
+        tlb::flush_all();
+        Ok(())
+    }
+
+    pub fn clone_copy(&mut self, src: &mut PageTable) -> ResultWithErr<MmError> {
+        self.clone_table(src, self.level, 0, false)
+    }
+
+    pub fn resolve_cow_fault(&mut self, vaddr: usize) -> ResultWithErr<MmError> {
+        // only the faulting page is copied out of a promoted leaf
+        let page = vaddr & !(FRAME_SIZE - 1);
+        self.demote_edges(page, page + FRAME_SIZE)?;
+        let Found(level, page_table) = self.search_for_modify(vaddr, HAL_PAGE_LEVEL) else {
+            mork_kernel_log!(warn, "fail to lookup vaddr {:#x}", vaddr);
+            return Err(MmError::NotMapped);
+        };
+        let index = PageTableImpl::get_index(vaddr, level).unwrap();
+        let pte = page_table.page_table_impl[index];
+        let attrs = page_table.leaf_attrs(index);
+        if !attrs.is_cow() {
+            mork_kernel_log!(warn, "vaddr {:#x} is not a cow mapping", vaddr);
+            return Err(MmError::InvalidParam);
+        }
+        let size = PageTableImpl::get_size(level).unwrap();
+        let aligned_vaddr = vaddr & !(size - 1);
+        let old_paddr = leaf_paddr(&pte);
+        let writable = (attrs - MapAttributes::COW) | MapAttributes::WRITE;
+        let merged = is_merged(old_paddr);
+        // the other holders are gone already, the frame turns writable in place
+        if frame_info::is_exclusive(old_paddr) {
+            page_table.map_user_frame(aligned_vaddr, old_paddr, level, writable);
+            frame_info::frame_info(old_paddr.ppn()).unwrap().remove_flags(FrameFlags::MERGED);
+            self.flush_page(aligned_vaddr);
+            if merged {
+                self.account_merged(0, size);
+            }
+            return Ok(());
+        }
+        let Some(new_paddr) = copy_frame(old_paddr, size) else {
+            mork_kernel_log!(warn, "fail to allocate frame for cow, vaddr: {:#x}", vaddr);
+            return Err(MmError::OutOfMemory);
+        };
+        page_table.map_user_frame(aligned_vaddr, new_paddr, level, writable);
+        rmap::remove(old_paddr, self.root, aligned_vaddr);
+        rmap::add(new_paddr, self.root, aligned_vaddr);
+        self.flush_page(aligned_vaddr);
+        if merged {
+            self.account_merged(0, size);
+        }
+        // the mapping drops its reference, the frame goes if the others went meanwhile
+        let old = phys_to_virt(old_paddr).as_usize();
+        let unmapped = frame_info::frame_info(old_paddr.ppn())
+            .is_some_and(|info| info.mapcount.load(Ordering::Acquire) == 0);
+        if !frame_info::put_shared(old_paddr) && !frame::is_zero_page(old) && unmapped {
+            unsafe { frame::dealloc_frames(old, size); }
+        }
+        Ok(())
+    }
+
+    // points the 4K leaf at vaddr to another frame with the given attributes
+    pub(crate) fn replace_frame(&mut self, vaddr: usize, paddr: PhysAddr, attrs: MapAttributes)
+        -> ResultWithErr<MmError> {
+        let Found(level, page_table) = self.search_for_modify(vaddr, HAL_PAGE_LEVEL) else {
+            return Err(MmError::NotMapped);
+        };
+        if level != HAL_PAGE_LEVEL - 1 {
+            mork_kernel_log!(warn, "vaddr {:#x} is mapped by a level {} leaf", vaddr, level);
+            return Err(MmError::InvalidLevel);
+        }
+        let old = leaf_paddr(&page_table.page_table_impl[PageTableImpl::get_index(vaddr, level).unwrap()]);
+        page_table.map_user_frame(vaddr, paddr, level, attrs);
+        rmap::remove(old, self.root, vaddr);
+        rmap::add(paddr, self.root, vaddr);
+        self.flush_page(vaddr);
+        Ok(())
+    }
+
+    fn clone_table(&mut self, src: &mut PageTable, level: usize, base: usize, cow: bool)
+        -> ResultWithErr<MmError> {
+        let size = PageTableImpl::get_size(level).unwrap();
+        for index in 0..user_entry_end(level) {
+            let pte = src.page_table_impl[index];
+            let vaddr = base + index * size;
+            if !pte.valid() {
+                // both address spaces now refer to the swapped out page
+                if let Some(slot) = src.swap_slot(index) {
+                    let page_table = self.search_or_populate(vaddr, level)?;
+                    swap::dup_slot(slot)?;
+                    soft_pte::set(page_table.get_ptr(), index, SoftPte::Swapped(slot));
+                }
+                continue;
+            }
+            if pte.is_leaf() {
+                let mut paddr = leaf_paddr(&pte);
+                let mut attrs = src.leaf_attrs(index);
+                self.check_quota(size)?;
+                if !cow && attrs.contains(MapAttributes::PROMOTED) {
+                    self.copy_promoted(paddr, vaddr, level, attrs)?;
+                    continue;
+                }
+                if !cow {
+                    paddr = copy_frame(paddr, size).ok_or_else(|| {
+                        mork_kernel_log!(warn, "fail to allocate frame for clone, vaddr: {:#x}", vaddr);
+                        MmError::OutOfMemory
+                    })?;
+                } else if attrs.is_w() {
+                    attrs = (attrs - MapAttributes::WRITE) | MapAttributes::COW;
+                    src.map_user_frame(vaddr, paddr, level, attrs);
+                }
+                let page_table = self.search_or_populate(vaddr, level)?;
+                let dst_index = PageTableImpl::get_index(vaddr, level).unwrap();
+                if page_table.page_table_impl[dst_index].valid() {
+                    mork_kernel_log!(warn, "vaddr has been mapped in target, {:#x}", vaddr);
+                    return Err(MmError::AlreadyMapped);
+                }
+                page_table.map_user_frame(vaddr, paddr, level, attrs);
+                if is_merged(paddr) {
+                    self.account_merged(size, 0);
+                }
+                for (paddr, page, _) in leaf_frames(paddr, vaddr, size, attrs) {
+                    rmap::add(paddr, self.root, page);
+                    if cow {
+                        frame_info::get_shared(paddr);
+                    }
+                }
+                self.account_resident(size, 0);
+            } else {
+                let inner_page_table = unsafe {
+                    &mut *next_table(&pte)
+                };
+                self.clone_table(inner_page_table, level + 1, vaddr, cow)?;
+            }
+        }
+        Ok(())
+    }
+
+    // the copy of a promoted leaf is made of separate frames, so it is mapped with 4K leaves
+    fn copy_promoted(&mut self, paddr: PhysAddr, vaddr: usize, level: usize, attrs: MapAttributes)
+        -> ResultWithErr<MmError> {
+        for offset in (0..PageTableImpl::get_size(level).unwrap()).step_by(FRAME_SIZE) {
+            let copy = copy_frame(paddr + offset, FRAME_SIZE).ok_or_else(|| {
+                mork_kernel_log!(warn, "fail to allocate frame for clone, vaddr: {:#x}", vaddr + offset);
+                MmError::OutOfMemory
+            })?;
+            let page_table = self.search_or_populate(vaddr + offset, HAL_PAGE_LEVEL - 1)?;
+            page_table.map_user_frame(vaddr + offset, copy, HAL_PAGE_LEVEL - 1, attrs - MapAttributes::PROMOTED);
+            rmap::add(copy, self.root, vaddr + offset);
+            self.account_resident(FRAME_SIZE, 0);
+        }
+        Ok(())
+    }
+
+    // collapses the 4K leaves of the huge page sized extent at vaddr into one leaf a level up,
+    // if they map contiguous frames with the same attributes. returns whether it did
+    pub fn promote(&mut self, vaddr: usize) -> Result<bool, MmError> {
+        let level = HAL_PAGE_LEVEL - 2;
+        let size = PageTableImpl::get_size(level).unwrap();
+        check_user_range(vaddr, size)?;
+        if !is_aligned(vaddr, size) {
+            mork_kernel_log!(warn, "vaddr must be aligned to {:#x}, {:#x}", size, vaddr);
+            return Err(MmError::AlignmentError);
+        }
+        let Some(page_table) = self.table_at(vaddr, level) else {
+            return Ok(false);
+        };
+        let pte = page_table.page_table_impl[PageTableImpl::get_index(vaddr, level).unwrap()];
+        if !pte.valid() || pte.is_leaf() {
+            return Ok(false);
+        }
+        let leaf_table = unsafe { &mut *next_table(&pte) };
+        let first = leaf_table.page_table_impl[0];
+        let base = leaf_paddr(&first);
+        if !is_aligned(base.as_usize(), size) {
+            return Ok(false);
+        }
+        // accessed and dirty differ from page to page, the huge leaf takes their union
+        let usage = MapAttributes::ACCESSED | MapAttributes::DIRTY;
+        let attrs = leaf_table.leaf_attrs(0);
+        let mut merged = attrs;
+        for index in 0..PTE_COUNT {
+            let entry = leaf_table.page_table_impl[index];
+            let entry_attrs = leaf_table.leaf_attrs(index);
+            if !entry.valid() || !entry.is_leaf() || leaf_paddr(&entry) != base + index * FRAME_SIZE
+                || entry_attrs - usage != attrs - usage {
+                return Ok(false);
+            }
+            // a pinned page keeps its own leaf, a later demotion or partial unmap would rewrite it
+            if frame_info::is_pinned((base + index * FRAME_SIZE).ppn()) {
+                return Ok(false);
+            }
+            merged |= entry_attrs;
+        }
+        page_table.map_user_frame(vaddr, base, level, merged | MapAttributes::PROMOTED);
+        // the old table may only be reused once no hart walks through it anymore
+        self.shootdown_space();
+        leaf_table.free();
+        self.account_page_tables(0, 1);
+        Ok(true)
+    }
+
+    // splits the huge leaf covering vaddr into leaves one level down with the same translation,
+    // returns whether there was one to split
+    pub fn demote(&mut self, vaddr: usize) -> Result<bool, MmError> {
+        check_user_range(vaddr, 1)?;
+        match self.translate(vaddr) {
+            Some((_, level, _)) if level < HAL_PAGE_LEVEL - 1 => {}
+            _ => return Ok(false),
+        }
+        if let Some(stats) = self.stats.as_deref() {
+            stats.check_page_tables(1)?;
+        }
+        let (boot, root) = (self.boot, self.root);
+        let Found(level, page_table) = self.search_for_modify(vaddr, HAL_PAGE_LEVEL) else {
+            return Ok(false);
+        };
+        let index = PageTableImpl::get_index(vaddr, level).unwrap();
+        let (paddr, attrs) = (leaf_paddr(&page_table.page_table_impl[index]), page_table.leaf_attrs(index));
+        let base = vaddr & !(PageTableImpl::get_size(level).unwrap() - 1);
+        let size = PageTableImpl::get_size(level + 1).unwrap();
+        let Some(inner_page_table) = PageTable::alloc_from(boot) else {
+            mork_kernel_log!(warn, "fail to allocate page table to demote {:#x}", vaddr);
+            return Err(MmError::OutOfMemory);
+        };
+        for index in 0..PTE_COUNT {
+            inner_page_table.map_user_frame(base + index * size, paddr + index * size, level + 1,
+                attrs - MapAttributes::PROMOTED);
+        }
+        // the translation stays the same, a stale huge entry is flushed with the first page that changes
+        page_table.page_table_impl.map_page_table(vaddr, inner_page_table.paddr().as_usize(), level);
+        soft_pte::take(page_table.get_ptr(), index);
+        // the frames of a promoted leaf are in the reverse map one by one already
+        if !attrs.contains(MapAttributes::PROMOTED) {
+            rmap::remove(paddr, root, base);
+            for index in 0..PTE_COUNT {
+                rmap::add(paddr + index * size, root, base + index * size);
+            }
+        }
+        self.account_page_tables(1, 0);
+        Ok(true)
+    }
+
+    // a promoted leaf only partly inside [vaddr, end) goes back to 4K leaves first
+    fn demote_edges(&mut self, vaddr: usize, end: usize) -> ResultWithErr<MmError> {
+        for edge in [vaddr, end] {
+            let Some((_, level, attrs)) = self.translate(edge) else {
+                continue;
+            };
+            if attrs.contains(MapAttributes::PROMOTED) && !is_aligned(edge, PageTableImpl::get_size(level).unwrap()) {
+                self.demote(edge)?;
+            }
+        }
+        Ok(())
+    }
+
+    // a huge leaf only partly inside [vaddr, end) is split until the edge falls on a leaf boundary,
+    // the rest of it stays mapped as before
+    fn split_edges(&mut self, vaddr: usize, end: usize) -> ResultWithErr<MmError> {
+        for edge in [vaddr, end] {
+            while let Some((_, level, _)) = self.translate(edge) {
+                if is_aligned(edge, PageTableImpl::get_size(level).unwrap()) {
+                    break;
+                }
+                self.demote(edge)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn check_quota(&self, size: usize) -> ResultWithErr<MmError> {
+        self.stats.as_deref().map_or(Ok(()), |stats| stats.check_resident(size))
+    }
+
+    fn account_resident(&mut self, added: usize, removed: usize) {
+        if let Some(stats) = self.stats.as_deref_mut() {
+            stats.add_resident(added);
+            stats.sub_resident(removed);
+        }
+    }
+
+    pub(crate) fn account_merged(&mut self, added: usize, removed: usize) {
+        if let Some(stats) = self.stats.as_deref_mut() {
+            stats.add_merged(added);
+            stats.sub_merged(removed);
+        }
+    }
+
+    fn account_page_tables(&mut self, added: usize, removed: usize) {
+        if let Some(stats) = self.stats.as_deref_mut() {
+            stats.add_page_tables(added);
+            stats.sub_page_tables(removed);
+        }
+    }
+
+    fn flush_page(&mut self, vaddr: usize) {
+        if let Some(batch) = &mut self.batch {
+            batch.add_page(vaddr);
+            return;
+        }
+        shootdown(self.harts, FlushRequest::Page { vaddr, asid: self.asid });
+    }
+
+    fn flush_space(&mut self) {
+        if let Some(batch) = &mut self.batch {
+            batch.add_all();
+            return;
+        }
+        self.shootdown_space();
+    }
+
+    // right away, even inside a batch
+    fn shootdown_space(&self) {
+        match self.asid {
+            Some(asid) => shootdown(self.harts, FlushRequest::Asid(asid)),
+            None => shootdown(self.harts, FlushRequest::All),
+        }
+    }
+
+    fn check_range(&mut self, vaddr: usize, end: usize, allow_holes: bool) -> ResultWithErr<MmError> {
+        let mut current = vaddr;
+        while current < end {
+            match self.search_for_modify(current, HAL_PAGE_LEVEL) {
+                Found(level, _) => {
+                    let size = PageTableImpl::get_size(level).unwrap();
+                    if !is_aligned(current, size) || current + size > end {
+                        mork_kernel_log!(warn, "range only covers part of level {} frame, vaddr: {:#x}",
+                            level, current);
+                        return Err(MmError::InvalidParam);
+                    }
+                    current += size;
+                }
+                Missing(level, _) => {
+                    if !allow_holes {
+                        mork_kernel_log!(warn, "fail to lookup vaddr {:#x}, level: {}", current, level);
+                        return Err(MmError::PageTableMiss);
+                    }
+                    let size = PageTableImpl::get_size(level).unwrap();
+                    current = (current & !(size - 1)) + size;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // the table holding the level entries of vaddr, None if the walk ends above it
+    fn table_at(&mut self, vaddr: usize, level: usize) -> Option<&mut PageTable> {
+        let mut current_level = self.level;
+        let mut current_pt: &mut PageTable = &mut *self.page_table;
+        while current_level < level {
+            let pte = current_pt.page_table_impl[PageTableImpl::get_index(vaddr, current_level).unwrap()];
+            if !pte.valid() || pte.is_leaf() {
+                return None;
+            }
+            current_pt = unsafe { &mut *next_table(&pte) };
+            current_level += 1;
+        }
+        Some(current_pt)
+    }
+
+    fn search_or_populate(&mut self, vaddr: usize, target_level: usize) -> Result<&mut PageTable, MmError> {
+        let mut current_level = self.level;
+        let boot = self.boot;
+        let mut current_pt: &mut PageTable = &mut *self.page_table;
+
+        while current_level < target_level {
+            let index = PageTableImpl::get_index(vaddr, current_level)
+                .expect("Invalid page table index");
+            let pte = current_pt.page_table_impl[index];
+
+            if !pte.valid() {
+                if let Some(stats) = self.stats.as_deref() {
+                    stats.check_page_tables(1)?;
+                }
+                let Some(inner_page_table) = PageTable::alloc_from(boot) else {
+                    mork_kernel_log!(warn, "fail to allocate page table, vaddr: {:#x}", vaddr);
+                    return Err(MmError::OutOfMemory);
+                };
+                current_pt
+                    .page_table_impl
+                    .map_page_table(
+                        vaddr,
+                        inner_page_table.paddr().as_usize(),
+                        current_level,
+                    );
+                if let Some(stats) = self.stats.as_deref_mut() {
+                    stats.add_page_tables(1);
+                }
+                current_pt = inner_page_table;
+            } else if pte.is_leaf() {
+                mork_kernel_log!(warn, "vaddr {:#x} has been mapped in level {}", vaddr, current_level);
+                return Err(MmError::AlreadyMapped);
+            } else {
+                current_pt = unsafe {
+                    &mut *next_table(&pte)
+                };
+            }
+            current_level += 1;
+        }
+        Ok(current_pt)
+    }
+
     fn search_for_modify(&mut self, vaddr: usize, max_level: usize) -> SearchResult<'_> {
         let mut current_level = self.level;
         let mut current_pt: &mut PageTable = &mut *self.page_table;
@@ -230,7 +1278,7 @@ impl<'a> MutPageTableWrapper<'a> {
 
             // 进入下一级时需要转移所有权
             let next_pt = unsafe {
-                &mut *(pte.get_page_table().get_ptr() as *mut PageTable)
+                &mut *next_table(pte)
             };
             current_pt = next_pt;
             current_level += 1;
@@ -246,46 +1294,222 @@ impl<'a> PageTableWrapper<'a> {
     }
 
     pub fn va_to_pa(&self, vaddr: usize) -> Option<usize>{
-        let offset = vaddr & PAGE_SIZE_NORMAL;
-        let mut current_level = 0;
-        let mut current_pt: &PageTable = & *self.page_table;
-        loop {
-            if current_level >= HAL_PAGE_LEVEL {
-                // return Err(format!("Exceed max level {}", HAL_PAGE_LEVEL));
-                mork_kernel_log!(warn, "Exceed max level: {}", HAL_PAGE_LEVEL);
-                return None;
-            }
+        self.translate(vaddr).map(|(paddr, _, _)| paddr)
+    }
 
-            let index = PageTableImpl::get_index(vaddr, current_level)
-                .expect("Invalid page table index");
+    pub fn translate(&self, vaddr: usize) -> Option<(usize, usize, MapAttributes)> {
+        lookup(self.page_table, 0, vaddr)
+    }
+}
 
-            let pte = &current_pt.page_table_impl[index]; // 可变借用
+fn copy_frame(paddr: PhysAddr, size: usize) -> Option<PhysAddr> {
+    let new_frame = frame::alloc_frames(size)?;
+    unsafe {
+        core::ptr::copy_nonoverlapping(phys_to_virt(paddr).as_usize() as *const u8, new_frame as *mut u8, size);
+    }
+    frame_info::mark_allocated(new_frame, size, FrameFlags::USER);
+    virt_to_phys(VirtAddr::new(new_frame))
+}
 
-            if !pte.valid() {
-                return None;
-            }
+// (paddr, vaddr, len) of the frames behind a leaf as they were allocated, one by one for a promoted leaf
+fn leaf_frames(paddr: PhysAddr, vaddr: usize, size: usize, attrs: MapAttributes)
+    -> impl Iterator<Item = (PhysAddr, usize, usize)> {
+    let len = if attrs.contains(MapAttributes::PROMOTED) { FRAME_SIZE } else { size };
+    (0..size).step_by(len).map(move |offset| (paddr + offset, vaddr + offset, len))
+}
 
-            if pte.is_leaf() {
-                return Some((pte.get_ppn() << 12) + offset + KERNEL_OFFSET);
-            }
+fn is_merged(paddr: PhysAddr) -> bool {
+    frame_info::frame_info(paddr.ppn()).is_some_and(|info| info.flags().contains(FrameFlags::MERGED))
+}
 
-            // 进入下一级时需要转移所有权
-            let next_pt = unsafe {
-                & *(pte.get_page_table().get_ptr() as *mut PageTable)
+fn leaf_paddr(pte: &PageTableEntryImpl) -> PhysAddr {
+    PhysAddr::from_ppn(pte.get_ppn())
+}
+
+// the hal converts with the link time offset, which is wrong once the window slides
+pub(crate) fn next_table(pte: &PageTableEntryImpl) -> *mut PageTable {
+    phys_to_virt(leaf_paddr(pte)).as_usize() as *mut PageTable
+}
+
+pub(crate) fn check_user_range(vaddr: usize, len: usize) -> ResultWithErr<MmError> {
+    if !is_user_range(vaddr, len) {
+        mork_kernel_log!(warn, "vaddr out of user space, {:#x}, len: {:#x}", vaddr, len);
+        return Err(MmError::InvalidAddress);
+    }
+    Ok(())
+}
+
+pub(crate) fn check_frame(paddr: PhysAddr, len: usize, attrs: MapAttributes) -> ResultWithErr<MmError> {
+    if !attrs.contains(MapAttributes::DEVICE) && !memory::is_ram(paddr, len) {
+        mork_kernel_log!(warn, "paddr is not ram, {:#x}, len: {:#x}", paddr.as_usize(), len);
+        return Err(MmError::InvalidAddress);
+    }
+    Ok(())
+}
+
+fn to_phys(paddr: usize) -> Result<PhysAddr, MmError> {
+    virt_to_phys(VirtAddr::new(paddr)).ok_or_else(|| {
+        mork_kernel_log!(warn, "paddr out of kernel window, {:#x}", paddr);
+        MmError::InvalidAddress
+    })
+}
+
+pub(crate) fn copy_kernel_entries(page_table: &mut PageTable, kernel_page_table: &PageTable) {
+    for index in user_entry_end(0)..PTE_COUNT {
+        page_table.page_table_impl[index] = kernel_page_table.page_table_impl[index];
+    }
+}
+
+pub(crate) fn user_entry_end(level: usize) -> usize {
+    if level == 0 {
+        PageTableImpl::get_index(KERNEL_OFFSET, 0).unwrap()
+    } else {
+        PTE_COUNT
+    }
+}
+
+// returns the resident bytes and page tables released
+fn teardown_table(page_table: &mut PageTable, level: usize, base: usize, root: usize,
+                  release_frame: &mut Option<&mut dyn FnMut(usize, usize)>) -> (usize, usize) {
+    let (mut bytes, mut tables) = (0, 0);
+    for index in 0..user_entry_end(level) {
+        let pte = page_table.page_table_impl[index];
+        let size = PageTableImpl::get_size(level).unwrap();
+        let vaddr = base + index * size;
+        if !pte.valid() {
+            if let Some(slot) = page_table.swap_slot(index) {
+                swap::free_slot(slot);
+                page_table.clear_entry(index);
+            }
+            continue;
+        }
+        if pte.is_leaf() {
+            let attrs = page_table.leaf_attrs(index);
+            for (paddr, page, len) in leaf_frames(leaf_paddr(&pte), vaddr, size, attrs) {
+                rmap::remove(paddr, root, page);
+                // only the last holder of a shared frame releases it
+                if frame_info::put_shared(paddr) {
+                    continue;
+                }
+                let paddr = phys_to_virt(paddr).as_usize();
+                if let Some(release) = release_frame.as_mut().filter(|_| !frame::is_zero_page(paddr)) {
+                    release(paddr, len);
+                }
+            }
+            bytes += size;
+        } else {
+            let inner_page_table = unsafe {
+                &mut *next_table(&pte)
             };
-            current_pt = next_pt;
-            current_level += 1;
+            let (inner_bytes, inner_tables) = teardown_table(inner_page_table, level + 1, vaddr, root, release_frame);
+            inner_page_table.free();
+            bytes += inner_bytes;
+            tables += inner_tables + 1;
         }
+        page_table.clear_entry(index);
     }
+    (bytes, tables)
 }
 
-pub fn map_kernel_window(mut kernel_page_table: &mut PageTable) -> ResultWithErr<String> {
-    let mut wrapper = MutPageTableWrapper::new(&mut kernel_page_table);
-    let (_, _, end) = mork_hal::get_memory_info().map_err(|()| "failed to get memory info")?;
-    let mut start = KERNEL_OFFSET;
+fn lookup(page_table: &PageTable, level: usize, vaddr: usize) -> Option<(usize, usize, MapAttributes)> {
+    let mut current_level = level;
+    let mut current_pt: &PageTable = page_table;
+    loop {
+        if current_level >= HAL_PAGE_LEVEL {
+            mork_kernel_log!(warn, "Exceed max level: {}", HAL_PAGE_LEVEL);
+            return None;
+        }
+
+        let index = PageTableImpl::get_index(vaddr, current_level)
+            .expect("Invalid page table index");
+
+        let pte = &current_pt.page_table_impl[index];
+
+        if !pte.valid() {
+            return None;
+        }
+
+        if pte.is_leaf() {
+            let offset = vaddr & (PageTableImpl::get_size(current_level).unwrap() - 1);
+            return Some((
+                phys_to_virt(leaf_paddr(pte)).as_usize() + offset,
+                current_level,
+                current_pt.leaf_attrs(index),
+            ));
+        }
 
-    while start < end {
-        start += wrapper.map_kernel(start, start)?;
+        let next_pt = unsafe {
+            & *next_table(pte)
+        };
+        current_pt = next_pt;
+        current_level += 1;
     }
-    Ok(())
-}
\ No newline at end of file
+}
+
+unsafe extern "C" {
+    fn stext();
+    fn etext();
+    fn erodata();
+    fn sinit();
+    fn einit();
+}
+
+static INIT_RECLAIMED: AtomicBool = AtomicBool::new(false);
+
+pub fn map_kernel_window(kernel_page_table: &mut PageTable) -> ResultWithErr<MmError> {
+    let mut wrapper = MutPageTableWrapper::new(kernel_page_table).with_boot_pool();
+    let (stext, etext, erodata) = (stext as *const () as usize, etext as *const () as usize,
+                                   erodata as *const () as usize);
+    let rw = MapAttributes::READ | MapAttributes::WRITE;
+    let global = MapAttributes::GLOBAL | MapAttributes::ACCESSED | MapAttributes::DIRTY;
+    // devices below ram are still reached through the window
+    let ram_start = memory::ram_regions().iter().map(|region| region.start).min()
+        .ok_or(MmError::InvalidAddress)?;
+    let ram_start = phys_to_virt(ram_start).as_usize() & !(4096 - 1);
+    if ram_start > kaslr::kernel_offset() {
+        wrapper.map_kernel_region(kaslr::kernel_offset(), ram_start, rw | global)?;
+    }
+    let mut result = Ok(());
+    memory::for_each_mapped_ram(|start, end| {
+        let (start, end) = (phys_to_virt(start).as_usize(), phys_to_virt(end).as_usize());
+        let sections = [
+            (start, stext, rw),
+            (stext, etext, MapAttributes::READ | MapAttributes::EXECUTE),
+            (etext, erodata, MapAttributes::READ),
+            // data, bss and the free memory behind the kernel image
+            (erodata, end, rw),
+        ];
+        for (section_start, section_end, attrs) in sections {
+            let (section_start, section_end) = (section_start.max(start), section_end.min(end));
+            if result.is_ok() && section_start < section_end {
+                result = wrapper.map_kernel_region(section_start, section_end, attrs | global);
+            }
+        }
+    });
+    result
+}
+
+// the image mapping of the init sections is replaced by a plain data mapping, returns the range.
+// only whole pages are reclaimed, the partial ones at the edges may share data with other sections
+pub(crate) fn remap_init_sections() -> Result<(usize, usize), MmError> {
+    if INIT_RECLAIMED.swap(true, Ordering::AcqRel) {
+        mork_kernel_log!(warn, "init memory has been reclaimed already");
+        return Err(MmError::InvalidParam);
+    }
+    let start = (sinit as *const () as usize).next_multiple_of(4096);
+    let end = (einit as *const () as usize) & !(4096 - 1);
+    if start >= end {
+        return Ok((start, start));
+    }
+    let _unlocked = kernel_protect::unlock_kernel_mappings();
+    let mut wrapper = MutPageTableWrapper::new(kernel_template::kernel_root()).with_harts(online_harts());
+    let attrs = MapAttributes::READ | MapAttributes::WRITE | MapAttributes::ACCESSED | MapAttributes::DIRTY;
+    wrapper.begin_batch();
+    for vaddr in (start..end).step_by(4096) {
+        wrapper.split_kernel_leaf(vaddr)?;
+        let paddr = wrapper.unmap_kernel_page(vaddr)?;
+        wrapper.map_kernel_phys(vaddr, paddr, attrs)?;
+    }
+    wrapper.finish_batch();
+    Ok((start, end))
+}
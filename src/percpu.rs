@@ -0,0 +1,35 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use mork_common::mork_kernel_log;
+use mork_common::types::ResultWithErr;
+use crate::error::MmError;
+use crate::frame::FRAME_SIZE;
+use crate::kernel_layout::PERCPU_START;
+use crate::vmalloc;
+
+pub const PERCPU_SIZE: usize = 16 * FRAME_SIZE;
+// every area is followed by an unmapped guard page
+const PERCPU_STRIDE: usize = PERCPU_SIZE + FRAME_SIZE;
+// hart masks are a usize
+const MAX_HARTS: usize = usize::BITS as usize;
+
+// harts whose area is mapped
+static READY: AtomicUsize = AtomicUsize::new(0);
+
+// the area is zeroed and stays mapped for good
+pub(crate) fn init_hart(hart_id: usize) -> ResultWithErr<MmError> {
+    if hart_id >= MAX_HARTS {
+        mork_kernel_log!(warn, "no percpu area for hart {}", hart_id);
+        return Err(MmError::InvalidParam);
+    }
+    if READY.load(Ordering::Acquire) & (1 << hart_id) != 0 {
+        return Ok(());
+    }
+    vmalloc::map_frames(PERCPU_START + hart_id * PERCPU_STRIDE, PERCPU_SIZE).ok_or(MmError::OutOfMemory)?;
+    READY.fetch_or(1 << hart_id, Ordering::Release);
+    Ok(())
+}
+
+pub fn percpu_base(hart_id: usize) -> Option<usize> {
+    (hart_id < MAX_HARTS && READY.load(Ordering::Acquire) & (1 << hart_id) != 0)
+        .then(|| PERCPU_START + hart_id * PERCPU_STRIDE)
+}
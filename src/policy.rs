@@ -0,0 +1,26 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use mork_common::mork_kernel_log;
+use mork_common::types::ResultWithErr;
+use crate::attributes::MapAttributes;
+use crate::error::MmError;
+
+// W^X is enforced unless built with `allow-wx` or turned off by a boot option.
+// JIT-style users map a region RW, fill it, then `protect_range` it to RX.
+static ENFORCE_WX: AtomicBool = AtomicBool::new(!cfg!(feature = "allow-wx"));
+
+pub fn set_wx_enforce(enforce: bool) {
+    ENFORCE_WX.store(enforce, Ordering::Release);
+}
+
+pub fn wx_enforced() -> bool {
+    ENFORCE_WX.load(Ordering::Acquire)
+}
+
+pub fn check_user_attrs(attrs: MapAttributes) -> ResultWithErr<MmError> {
+    attrs.validate()?;
+    if wx_enforced() && attrs.is_w() && attrs.is_x() {
+        mork_kernel_log!(warn, "writable and executable mapping rejected, attrs: {:?}", attrs);
+        return Err(MmError::WxViolation);
+    }
+    Ok(())
+}
@@ -0,0 +1,65 @@
+use core::sync::atomic::Ordering;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use spin::mutex::Mutex;
+use crate::addr::PhysAddr;
+use crate::frame_info::{self, FrameFlags};
+use crate::page_table::{MutPageTableWrapper, PageTable};
+use crate::shootdown::online_harts;
+
+// a user leaf entry referring to a frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mapping {
+    // root page table of the address space, as registered with the kernel template
+    pub root: usize,
+    pub vaddr: usize,
+}
+
+impl Mapping {
+    // the flush covers the vaddr in every address space, the rmap does not know the asid
+    pub(crate) fn wrapper(&self) -> MutPageTableWrapper<'static> {
+        let root = unsafe { &mut *(self.root as *mut PageTable) };
+        MutPageTableWrapper::new(root).with_harts(online_harts())
+    }
+}
+
+// keyed by pfn, a huge leaf is recorded at its first frame, a promoted one at every frame
+static RMAP: Mutex<BTreeMap<usize, Vec<Mapping>>> = Mutex::new(BTreeMap::new());
+
+// frames outside of ram and the zero page are not tracked, nothing revokes or moves them
+fn tracked(paddr: PhysAddr) -> Option<usize> {
+    let pfn = paddr.ppn();
+    let info = frame_info::frame_info(pfn)?;
+    (!info.flags().contains(FrameFlags::ZERO_PAGE)).then_some(pfn)
+}
+
+pub(crate) fn add(paddr: PhysAddr, root: usize, vaddr: usize) {
+    let Some(pfn) = tracked(paddr) else {
+        return;
+    };
+    RMAP.lock().entry(pfn).or_default().push(Mapping { root, vaddr });
+    frame_info::frame_info(pfn).unwrap().mapcount.fetch_add(1, Ordering::AcqRel);
+}
+
+pub(crate) fn remove(paddr: PhysAddr, root: usize, vaddr: usize) {
+    let Some(pfn) = tracked(paddr) else {
+        return;
+    };
+    let mut rmap = RMAP.lock();
+    let Some(mappings) = rmap.get_mut(&pfn) else {
+        return;
+    };
+    let Some(index) = mappings.iter().position(|mapping| *mapping == Mapping { root, vaddr }) else {
+        return;
+    };
+    mappings.swap_remove(index);
+    if mappings.is_empty() {
+        rmap.remove(&pfn);
+    }
+    frame_info::frame_info(pfn).unwrap().mapcount.fetch_sub(1, Ordering::AcqRel);
+}
+
+// a snapshot, the mappings may change as soon as the lock is dropped
+pub fn mappings(pfn: usize) -> Vec<Mapping> {
+    RMAP.lock().get(&pfn).cloned().unwrap_or_default()
+}
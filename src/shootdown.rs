@@ -0,0 +1,76 @@
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::sync::IrqMutex;
+use crate::{hart, tlb};
+
+#[derive(Clone, Copy, Debug)]
+pub enum FlushRequest {
+    Page { vaddr: usize, asid: Option<usize> },
+    Asid(usize),
+    All,
+}
+
+impl FlushRequest {
+    pub fn flush_local(&self) {
+        match *self {
+            FlushRequest::Page { vaddr, asid: Some(asid) } => tlb::flush_page(vaddr, asid),
+            FlushRequest::Page { vaddr, asid: None } => tlb::flush_vaddr(vaddr),
+            FlushRequest::Asid(asid) => tlb::flush_asid(asid),
+            FlushRequest::All => tlb::flush_all(),
+        }
+    }
+}
+
+// the ipi handler reads the request, and vfree shoots down from inside the global allocator
+static SHOOTDOWN_LOCK: IrqMutex<()> = IrqMutex::new(());
+static REQUEST: IrqMutex<FlushRequest> = IrqMutex::new(FlushRequest::All);
+static PENDING_HARTS: AtomicUsize = AtomicUsize::new(0);
+static ONLINE_HARTS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn hart_online(hart_id: usize) {
+    ONLINE_HARTS.fetch_or(1 << hart_id, Ordering::AcqRel);
+}
+
+pub fn online_harts() -> usize {
+    ONLINE_HARTS.load(Ordering::Acquire)
+}
+
+// flush locally, then ask every other hart in hart_mask to flush and wait for their acks
+pub fn shootdown(hart_mask: usize, request: FlushRequest) {
+    request.flush_local();
+    shootdown_remote(hart_mask, request);
+}
+
+pub fn shootdown_remote(hart_mask: usize, request: FlushRequest) {
+    let targets = hart_mask & !(1 << hart::hart_id());
+    if targets == 0 {
+        return;
+    }
+    // another hart may be shooting down at us meanwhile, possibly with interrupts off on both,
+    // so both waits serve its request instead of just spinning
+    let _guard = loop {
+        if let Some(guard) = SHOOTDOWN_LOCK.try_lock() {
+            break guard;
+        }
+        handle_ipi();
+        spin_loop();
+    };
+    *REQUEST.lock() = request;
+    PENDING_HARTS.store(targets, Ordering::Release);
+    hart::send_ipi(targets);
+    while PENDING_HARTS.load(Ordering::Acquire) != 0 {
+        handle_ipi();
+        spin_loop();
+    }
+}
+
+// called by the ipi handler on the target hart
+pub fn handle_ipi() {
+    let hart_bit = 1 << hart::hart_id();
+    if PENDING_HARTS.load(Ordering::Acquire) & hart_bit == 0 {
+        return;
+    }
+    let request = *REQUEST.lock();
+    request.flush_local();
+    PENDING_HARTS.fetch_and(!hart_bit, Ordering::AcqRel);
+}
@@ -0,0 +1,71 @@
+use alloc::collections::BTreeMap;
+use crate::attributes::MapAttributes;
+use crate::sync::IrqMutex;
+
+// the hal only takes the permissions of a leaf and can not read a pte back beyond valid, leaf and ppn.
+// the full attributes of user leaves and the swap slots of empty entries live here instead,
+// keyed by the virtual address of the table and the index of the entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum SoftPte {
+    Leaf(MapAttributes),
+    Swapped(usize),
+}
+
+// vfree clears kernel entries from inside the global allocator
+static SOFT_PTES: IrqMutex<BTreeMap<(usize, usize), SoftPte>> = IrqMutex::new(BTreeMap::new());
+
+pub(crate) fn get(table: usize, index: usize) -> Option<SoftPte> {
+    SOFT_PTES.lock().get(&(table, index)).copied()
+}
+
+pub(crate) fn set(table: usize, index: usize, pte: SoftPte) {
+    SOFT_PTES.lock().insert((table, index), pte);
+}
+
+pub(crate) fn take(table: usize, index: usize) -> Option<SoftPte> {
+    SOFT_PTES.lock().remove(&(table, index))
+}
+
+// drops every entry of a table that is freed or handed in fresh
+pub(crate) fn forget_table(table: usize) {
+    let mut ptes = SOFT_PTES.lock();
+    while let Some((&key, _)) = ptes.range((table, 0)..(table + 1, 0)).next() {
+        ptes.remove(&key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the store is global, every test uses tables of its own
+
+    #[test]
+    fn swap_entry_round_trip() {
+        let table = 0x1000;
+        set(table, 3, SoftPte::Swapped(42));
+        assert_eq!(get(table, 3), Some(SoftPte::Swapped(42)));
+        assert_eq!(take(table, 3), Some(SoftPte::Swapped(42)));
+        assert_eq!(get(table, 3), None);
+    }
+
+    #[test]
+    fn leaf_replaces_swap_entry() {
+        let table = 0x2000;
+        set(table, 0, SoftPte::Swapped(7));
+        set(table, 0, SoftPte::Leaf(MapAttributes::READ | MapAttributes::COW));
+        assert_eq!(get(table, 0), Some(SoftPte::Leaf(MapAttributes::READ | MapAttributes::COW)));
+    }
+
+    #[test]
+    fn forget_table_keeps_other_tables() {
+        let (table, next) = (0x3000, 0x4000);
+        set(table, 0, SoftPte::Leaf(MapAttributes::READ));
+        set(table, 511, SoftPte::Swapped(1));
+        set(next, 0, SoftPte::Swapped(2));
+        forget_table(table);
+        assert_eq!(get(table, 0), None);
+        assert_eq!(get(table, 511), None);
+        assert_eq!(get(next, 0), Some(SoftPte::Swapped(2)));
+    }
+}
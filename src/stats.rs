@@ -0,0 +1,122 @@
+use alloc::vec::Vec;
+use mork_common::mork_kernel_log;
+use mork_common::types::ResultWithErr;
+use crate::error::MmError;
+use crate::frame::{self, Zone};
+use crate::memory::{self, MemoryRegion};
+use crate::{balloon, heap, page_table};
+
+// None means unlimited
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MemoryLimits {
+    pub max_resident_bytes: Option<usize>,
+    pub max_page_table_pages: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MemoryStats {
+    // bytes covered by the areas of the address space
+    pub virtual_bytes: usize,
+    // bytes mapped by leaf entries
+    pub resident_bytes: usize,
+    pub peak_resident_bytes: usize,
+    // resident bytes mapping a frame merged with identical pages
+    pub merged_bytes: usize,
+    // including the root
+    pub page_table_pages: usize,
+    pub limits: MemoryLimits,
+}
+
+impl MemoryStats {
+    pub(crate) fn check_resident(&self, size: usize) -> ResultWithErr<MmError> {
+        match self.limits.max_resident_bytes {
+            Some(max) if self.resident_bytes + size > max => {
+                mork_kernel_log!(warn, "resident quota exceeded, {:#x} + {:#x} > {:#x}", self.resident_bytes, size, max);
+                Err(MmError::QuotaExceeded)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn check_page_tables(&self, count: usize) -> ResultWithErr<MmError> {
+        match self.limits.max_page_table_pages {
+            Some(max) if self.page_table_pages + count > max => {
+                mork_kernel_log!(warn, "page table quota exceeded, {} + {} > {}", self.page_table_pages, count, max);
+                Err(MmError::QuotaExceeded)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn add_resident(&mut self, size: usize) {
+        self.resident_bytes += size;
+        self.peak_resident_bytes = self.peak_resident_bytes.max(self.resident_bytes);
+    }
+
+    pub(crate) fn sub_resident(&mut self, size: usize) {
+        self.resident_bytes = self.resident_bytes.saturating_sub(size);
+    }
+
+    pub(crate) fn add_merged(&mut self, size: usize) {
+        self.merged_bytes += size;
+    }
+
+    pub(crate) fn sub_merged(&mut self, size: usize) {
+        self.merged_bytes = self.merged_bytes.saturating_sub(size);
+    }
+
+    pub(crate) fn add_page_tables(&mut self, count: usize) {
+        self.page_table_pages += count;
+    }
+
+    pub(crate) fn sub_page_tables(&mut self, count: usize) {
+        self.page_table_pages = self.page_table_pages.saturating_sub(count);
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ZoneStats {
+    pub total_bytes: usize,
+    pub free_bytes: usize,
+    pub used_bytes: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct GlobalStats {
+    pub total_ram: usize,
+    pub heap_total: usize,
+    pub heap_used: usize,
+    pub heap_largest_free: usize,
+    pub free_frames: usize,
+    pub frames_in_use: usize,
+    pub page_table_frames: usize,
+    // taken out of the allocator for the host
+    pub balloon_frames: usize,
+    // heap memory per zone, frames and kernel objects alike
+    pub dma32: ZoneStats,
+    pub normal: ZoneStats,
+    pub regions: Vec<MemoryRegion>,
+}
+
+pub fn collect() -> GlobalStats {
+    let regions = memory::ram_regions();
+    let (heap_total, heap_used) = heap::usage();
+    GlobalStats {
+        total_ram: regions.iter().map(|region| region.end.as_usize() - region.start.as_usize()).sum(),
+        heap_total,
+        heap_used,
+        heap_largest_free: heap::largest_free_block(),
+        free_frames: frame::free_frames(),
+        frames_in_use: frame::frames_in_use(),
+        page_table_frames: page_table::page_table_frames(),
+        balloon_frames: balloon::frames(),
+        dma32: zone_stats(Zone::Dma32),
+        normal: zone_stats(Zone::Normal),
+        regions,
+    }
+}
+
+fn zone_stats(zone: Zone) -> ZoneStats {
+    let (total_bytes, used_bytes) = heap::zone_usage(zone);
+    ZoneStats { total_bytes, free_bytes: total_bytes - used_bytes, used_bytes }
+}
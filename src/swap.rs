@@ -0,0 +1,179 @@
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::mutex::Mutex;
+use mork_common::mork_kernel_log;
+use mork_common::types::ResultWithErr;
+use crate::error::MmError;
+
+// where evicted anonymous pages go, frames are kernel window addresses
+pub trait SwapBackend: Send + Sync {
+    fn write_page(&self, slot: usize, frame: usize) -> ResultWithErr<MmError>;
+
+    fn read_page(&self, slot: usize, frame: usize) -> ResultWithErr<MmError>;
+
+    // the slot content is no longer referenced
+    fn free_slot(&self, slot: usize);
+}
+
+// reference counted slots, a slot is shared by the clones of an address space
+pub struct SlotAllocator {
+    counts: Vec<u16>,
+    next: usize,
+    used: usize,
+}
+
+impl SlotAllocator {
+    pub fn new(slots: usize) -> Self {
+        Self { counts: vec![0; slots], next: 0, used: 0 }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.counts.len()
+    }
+
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    pub fn alloc(&mut self) -> Option<usize> {
+        let capacity = self.counts.len();
+        let slot = (0..capacity)
+            .map(|offset| (self.next + offset) % capacity)
+            .find(|slot| self.counts[*slot] == 0)?;
+        self.counts[slot] = 1;
+        self.next = (slot + 1) % capacity;
+        self.used += 1;
+        Some(slot)
+    }
+
+    // fails once the slot holds as many references as the counter takes
+    pub fn dup(&mut self, slot: usize) -> bool {
+        match self.counts[slot].checked_add(1) {
+            Some(count) => {
+                self.counts[slot] = count;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // returns true once the last reference is gone
+    pub fn free(&mut self, slot: usize) -> bool {
+        if self.counts[slot] == 0 {
+            mork_kernel_log!(warn, "swap slot {} is not in use", slot);
+            return false;
+        }
+        self.counts[slot] -= 1;
+        if self.counts[slot] == 0 {
+            self.used -= 1;
+            return true;
+        }
+        false
+    }
+}
+
+struct SwapDevice {
+    backend: Arc<dyn SwapBackend>,
+    slots: SlotAllocator,
+}
+
+static SWAP_DEVICE: Mutex<Option<SwapDevice>> = Mutex::new(None);
+
+pub fn set_backend(backend: Arc<dyn SwapBackend>, slots: usize) {
+    *SWAP_DEVICE.lock() = Some(SwapDevice { backend, slots: SlotAllocator::new(slots) });
+}
+
+pub fn is_enabled() -> bool {
+    SWAP_DEVICE.lock().is_some()
+}
+
+// (used, capacity) in slots
+pub fn usage() -> (usize, usize) {
+    SWAP_DEVICE.lock().as_ref().map_or((0, 0), |device| (device.slots.used(), device.slots.capacity()))
+}
+
+pub fn swap_out(frame: usize) -> Result<usize, MmError> {
+    let (backend, slot) = {
+        let mut device = SWAP_DEVICE.lock();
+        let device = device.as_mut().ok_or(MmError::NotMapped)?;
+        let slot = device.slots.alloc().ok_or_else(|| {
+            mork_kernel_log!(warn, "swap space is full");
+            MmError::OutOfMemory
+        })?;
+        (device.backend.clone(), slot)
+    };
+    if let Err(err) = backend.write_page(slot, frame) {
+        free_slot(slot);
+        return Err(err);
+    }
+    Ok(slot)
+}
+
+pub fn swap_in(slot: usize, frame: usize) -> ResultWithErr<MmError> {
+    let backend = SWAP_DEVICE.lock().as_ref().map(|device| device.backend.clone())
+        .ok_or(MmError::NotMapped)?;
+    backend.read_page(slot, frame)
+}
+
+pub fn dup_slot(slot: usize) -> ResultWithErr<MmError> {
+    if let Some(device) = SWAP_DEVICE.lock().as_mut() && !device.slots.dup(slot) {
+        mork_kernel_log!(warn, "swap slot {} has too many references", slot);
+        return Err(MmError::OutOfMemory);
+    }
+    Ok(())
+}
+
+pub fn free_slot(slot: usize) {
+    let backend = {
+        let mut device = SWAP_DEVICE.lock();
+        let Some(device) = device.as_mut() else {
+            return;
+        };
+        if !device.slots.free(slot) {
+            return;
+        }
+        device.backend.clone()
+    };
+    backend.free_slot(slot);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SlotAllocator;
+
+    #[test]
+    fn alloc_wraps_around_and_skips_used_slots() {
+        let mut slots = SlotAllocator::new(3);
+        assert_eq!(slots.alloc(), Some(0));
+        assert_eq!(slots.alloc(), Some(1));
+        assert!(slots.free(0));
+        assert_eq!(slots.alloc(), Some(2));
+        assert_eq!(slots.alloc(), Some(0));
+        assert_eq!(slots.alloc(), None);
+        assert_eq!(slots.used(), 3);
+    }
+
+    #[test]
+    fn shared_slot_goes_with_the_last_reference() {
+        let mut slots = SlotAllocator::new(1);
+        let slot = slots.alloc().unwrap();
+        assert!(slots.dup(slot));
+        assert!(!slots.free(slot));
+        assert_eq!(slots.used(), 1);
+        assert!(slots.free(slot));
+        assert_eq!(slots.used(), 0);
+        assert!(!slots.free(slot));
+    }
+
+    #[test]
+    fn dup_fails_once_the_count_saturates() {
+        let mut slots = SlotAllocator::new(1);
+        let slot = slots.alloc().unwrap();
+        for _ in 1..u16::MAX {
+            assert!(slots.dup(slot));
+        }
+        assert!(!slots.dup(slot));
+        assert!(!slots.free(slot));
+    }
+}
@@ -0,0 +1,89 @@
+#[cfg(target_arch = "riscv64")]
+use core::arch::asm;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use spin::mutex::{Mutex, MutexGuard};
+
+#[cfg(target_arch = "riscv64")]
+const SSTATUS_SIE: usize = 1 << 1;
+
+// keeps supervisor interrupts off while held, an interrupt handler allocating on the same hart
+// would otherwise spin forever on the lock its hart already holds
+pub struct IrqMutex<T> {
+    inner: Mutex<T>,
+}
+
+pub struct IrqMutexGuard<'a, T> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    was_enabled: bool,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self { inner: Mutex::new(value) }
+    }
+
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let was_enabled = disable_interrupts();
+        IrqMutexGuard { guard: ManuallyDrop::new(self.inner.lock()), was_enabled }
+    }
+
+    // gives up instead of spinning, interrupts are back as they were when it fails
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T>> {
+        let was_enabled = disable_interrupts();
+        let Some(guard) = self.inner.try_lock() else {
+            if was_enabled {
+                enable_interrupts();
+            }
+            return None;
+        };
+        Some(IrqMutexGuard { guard: ManuallyDrop::new(guard), was_enabled })
+    }
+}
+
+impl<T> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // the lock goes first, an interrupt right after the restore may take it again
+        unsafe {
+            ManuallyDrop::drop(&mut self.guard);
+        }
+        // nested locks leave interrupts to the outermost one
+        if self.was_enabled {
+            enable_interrupts();
+        }
+    }
+}
+
+// returns whether interrupts were enabled before
+fn disable_interrupts() -> bool {
+    #[cfg(target_arch = "riscv64")]
+    let enabled = unsafe {
+        let old: usize;
+        asm!("csrrc {}, sstatus, {}", out(reg) old, in(reg) SSTATUS_SIE);
+        old & SSTATUS_SIE != 0
+    };
+    #[cfg(not(target_arch = "riscv64"))]
+    let enabled = false;
+    enabled
+}
+
+fn enable_interrupts() {
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        asm!("csrs sstatus, {}", in(reg) SSTATUS_SIE);
+    }
+}
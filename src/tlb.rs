@@ -0,0 +1,133 @@
+#[cfg(target_arch = "riscv64")]
+use core::arch::asm;
+use crate::shootdown::{shootdown, shootdown_remote, FlushRequest};
+
+pub fn flush_all() {
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        asm!("sfence.vma");
+    }
+}
+
+pub fn flush_asid(asid: usize) {
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        asm!("sfence.vma zero, {}", in(reg) asid);
+    }
+    #[cfg(not(target_arch = "riscv64"))]
+    let _ = asid;
+}
+
+pub fn flush_page(vaddr: usize, asid: usize) {
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        asm!("sfence.vma {}, {}", in(reg) vaddr, in(reg) asid);
+    }
+    #[cfg(not(target_arch = "riscv64"))]
+    let _ = (vaddr, asid);
+}
+
+// flush vaddr in every address space
+pub fn flush_vaddr(vaddr: usize) {
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        asm!("sfence.vma {}, zero", in(reg) vaddr);
+    }
+    #[cfg(not(target_arch = "riscv64"))]
+    let _ = vaddr;
+}
+
+const TLB_BATCH_THRESHOLD: usize = 32;
+
+pub struct TlbBatch {
+    asid: Option<usize>,
+    harts: usize,
+    pages: [usize; TLB_BATCH_THRESHOLD],
+    count: usize,
+    overflow: bool,
+}
+
+impl TlbBatch {
+    pub fn new(asid: Option<usize>, harts: usize) -> Self {
+        Self {
+            asid,
+            harts,
+            pages: [0; TLB_BATCH_THRESHOLD],
+            count: 0,
+            overflow: false,
+        }
+    }
+
+    pub fn add_page(&mut self, vaddr: usize) {
+        if self.count < TLB_BATCH_THRESHOLD {
+            self.pages[self.count] = vaddr;
+            self.count += 1;
+        } else {
+            self.overflow = true;
+        }
+    }
+
+    pub fn add_all(&mut self) {
+        self.overflow = true;
+    }
+
+    pub fn flush(&mut self) {
+        if self.overflow {
+            match self.asid {
+                Some(asid) => shootdown(self.harts, FlushRequest::Asid(asid)),
+                None => shootdown(self.harts, FlushRequest::All),
+            }
+        } else if self.count == 1 {
+            shootdown(self.harts, FlushRequest::Page { vaddr: self.pages[0], asid: self.asid });
+        } else if self.count > 1 {
+            for vaddr in &self.pages[..self.count] {
+                FlushRequest::Page { vaddr: *vaddr, asid: self.asid }.flush_local();
+            }
+            // remote harts only get one request at a time, flush the whole space there
+            match self.asid {
+                Some(asid) => shootdown_remote(self.harts, FlushRequest::Asid(asid)),
+                None => shootdown_remote(self.harts, FlushRequest::All),
+            }
+        }
+        self.count = 0;
+        self.overflow = false;
+    }
+}
+
+impl Drop for TlbBatch {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // only the local hart, so flushing never waits on another one
+
+    #[test]
+    fn pages_past_the_threshold_flush_everything() {
+        let mut batch = TlbBatch::new(Some(1), 1);
+        for page in 0..TLB_BATCH_THRESHOLD {
+            batch.add_page(page * 4096);
+        }
+        assert_eq!(batch.count, TLB_BATCH_THRESHOLD);
+        assert!(!batch.overflow);
+        batch.add_page(TLB_BATCH_THRESHOLD * 4096);
+        assert!(batch.overflow);
+        assert_eq!(batch.count, TLB_BATCH_THRESHOLD);
+    }
+
+    #[test]
+    fn flush_starts_a_new_batch() {
+        let mut batch = TlbBatch::new(None, 1);
+        batch.add_page(0x1000);
+        batch.add_all();
+        batch.flush();
+        assert_eq!(batch.count, 0);
+        assert!(!batch.overflow);
+        batch.add_page(0x2000);
+        assert_eq!(batch.pages[0], 0x2000);
+    }
+}
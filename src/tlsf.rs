@@ -0,0 +1,366 @@
+use core::alloc::Layout;
+use core::ptr::{self, NonNull};
+use crate::heap::HeapBackend;
+
+// two level segregated fit. alloc and dealloc run in constant time whatever the heap size or
+// fragmentation: one mapping, two bitmap scans and a bounded number of list and split operations,
+// there is no loop over blocks or orders. the price is a 16 byte header per block and up to
+// 1/16 of a block lost to the rounding of the size classes
+
+const ALIGN: usize = 16;
+const HEADER: usize = size_of::<Header>();
+// a free block also holds its list links
+const MIN_BLOCK: usize = size_of::<FreeBlock>();
+const SL_LOG2: usize = 4;
+const SL_COUNT: usize = 1 << SL_LOG2;
+// blocks below 1 << FL_SHIFT bytes share the first list level, in ALIGN steps
+const FL_SHIFT: usize = SL_LOG2 + ALIGN.trailing_zeros() as usize;
+const FL_COUNT: usize = 32;
+// largest block a region is cut into
+const MAX_BLOCK: usize = 1 << (FL_COUNT + FL_SHIFT - 2);
+const FREE: usize = 1;
+
+#[repr(C)]
+struct Header {
+    // null for the first block of a region
+    prev_phys: *mut Header,
+    // size including the header, FREE in the low bit
+    size: usize,
+}
+
+#[repr(C)]
+struct FreeBlock {
+    header: Header,
+    next: *mut FreeBlock,
+    prev: *mut FreeBlock,
+}
+
+pub struct TlsfHeap {
+    fl_bitmap: usize,
+    sl_bitmap: [usize; FL_COUNT],
+    lists: [[*mut FreeBlock; SL_COUNT]; FL_COUNT],
+    user: usize,
+    allocated: usize,
+    total: usize,
+}
+
+unsafe impl Send for TlsfHeap {}
+
+impl TlsfHeap {
+    pub const fn empty() -> Self {
+        Self {
+            fl_bitmap: 0,
+            sl_bitmap: [0; FL_COUNT],
+            lists: [[ptr::null_mut(); SL_COUNT]; FL_COUNT],
+            user: 0,
+            allocated: 0,
+            total: 0,
+        }
+    }
+
+    // the first class whose blocks are all at least size bytes
+    fn find_suitable(&self, size: usize) -> Option<(usize, usize)> {
+        let size = if size < 1 << FL_SHIFT {
+            size
+        } else {
+            size.checked_add((1 << (log2(size) - SL_LOG2)) - 1)?
+        };
+        let (fl, sl) = mapping(size);
+        if fl >= FL_COUNT {
+            return None;
+        }
+        let sl_map = self.sl_bitmap[fl] & (usize::MAX << sl);
+        if sl_map != 0 {
+            return Some((fl, sl_map.trailing_zeros() as usize));
+        }
+        let fl_map = self.fl_bitmap & usize::MAX.checked_shl(fl as u32 + 1).unwrap_or(0);
+        if fl_map == 0 {
+            return None;
+        }
+        let fl = fl_map.trailing_zeros() as usize;
+        Some((fl, self.sl_bitmap[fl].trailing_zeros() as usize))
+    }
+
+    unsafe fn insert_free(&mut self, block: *mut FreeBlock) {
+        let size = unsafe { block_size(block) };
+        let (fl, sl) = mapping(size);
+        unsafe {
+            (*block).header.size = size | FREE;
+            let head = self.lists[fl][sl];
+            (*block).next = head;
+            (*block).prev = ptr::null_mut();
+            if !head.is_null() {
+                (*head).prev = block;
+            }
+            self.lists[fl][sl] = block;
+        }
+        self.fl_bitmap |= 1 << fl;
+        self.sl_bitmap[fl] |= 1 << sl;
+    }
+
+    unsafe fn remove_free(&mut self, block: *mut FreeBlock) {
+        unsafe {
+            let (fl, sl) = mapping(block_size(block));
+            let (next, prev) = ((*block).next, (*block).prev);
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+            if !prev.is_null() {
+                (*prev).next = next;
+            } else {
+                self.lists[fl][sl] = next;
+                if next.is_null() {
+                    self.sl_bitmap[fl] &= !(1 << sl);
+                    if self.sl_bitmap[fl] == 0 {
+                        self.fl_bitmap &= !(1 << fl);
+                    }
+                }
+            }
+            (*block).header.size &= !FREE;
+        }
+    }
+
+    fn lists(&self) -> impl Iterator<Item = *mut FreeBlock> + '_ {
+        self.lists.iter().flatten().copied().filter(|head| !head.is_null())
+    }
+}
+
+impl HeapBackend for TlsfHeap {
+    unsafe fn add_to_heap(&mut self, start: usize, end: usize) {
+        for (start, end) in chunks(start, end) {
+            let block = start as *mut FreeBlock;
+            unsafe {
+                (*block).header = Header { prev_phys: ptr::null_mut(), size: end - start - HEADER };
+                // a used block without size ends the region, so every block has a next one
+                let sentinel = (end - HEADER) as *mut Header;
+                sentinel.write(Header { prev_phys: block as *mut Header, size: 0 });
+                self.insert_free(block);
+            }
+            self.total += end - start;
+            self.allocated += HEADER;
+        }
+    }
+
+    // blocks never merge across chunks, so a free chunk is a single free block
+    unsafe fn remove_region(&mut self, start: usize, end: usize) -> bool {
+        let free = chunks(start, end).count() != 0 && chunks(start, end).all(|(start, end)| unsafe {
+            let block = start as *mut FreeBlock;
+            is_free(block) && block_size(block) == end - start - HEADER
+        });
+        if !free {
+            return false;
+        }
+        for (start, end) in chunks(start, end) {
+            unsafe {
+                self.remove_free(start as *mut FreeBlock);
+            }
+            self.total -= end - start;
+            self.allocated -= HEADER;
+        }
+        true
+    }
+
+    fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, ()> {
+        let size = (layout.size().checked_next_multiple_of(ALIGN).ok_or(())? + HEADER).max(MIN_BLOCK);
+        let align = layout.align().max(ALIGN);
+        // room to move the start forward by at least a whole free block
+        let search = if align > ALIGN { size + align + MIN_BLOCK } else { size };
+        let (fl, sl) = self.find_suitable(search).ok_or(())?;
+        unsafe {
+            let mut block = self.lists[fl][sl];
+            self.remove_free(block);
+            if align > ALIGN {
+                let start = block as usize;
+                let mut user = (start + HEADER).next_multiple_of(align);
+                if user - HEADER != start && user - HEADER - start < MIN_BLOCK {
+                    user += align;
+                }
+                if user - HEADER != start {
+                    let front = block;
+                    block = split(front, user - HEADER - start);
+                    self.insert_free(front);
+                }
+            }
+            // the neighbours of a free block are never free, so the rest needs no merging
+            if block_size(block) - size >= MIN_BLOCK {
+                let rest = split(block, size);
+                self.insert_free(rest);
+            }
+            self.user += layout.size();
+            self.allocated += block_size(block);
+            Ok(NonNull::new_unchecked((block as usize + HEADER) as *mut u8))
+        }
+    }
+
+    fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        unsafe {
+            let mut block = (ptr.as_ptr() as usize - HEADER) as *mut FreeBlock;
+            self.allocated -= block_size(block);
+            self.user -= layout.size();
+            let next = next_phys(block);
+            if is_free(next) {
+                self.remove_free(next);
+                (*block).header.size = block_size(block) + block_size(next);
+                (*next_phys(block)).header.prev_phys = block as *mut Header;
+            }
+            let prev = (*block).header.prev_phys as *mut FreeBlock;
+            if !prev.is_null() && is_free(prev) {
+                self.remove_free(prev);
+                (*prev).header.size = block_size(prev) + block_size(block);
+                block = prev;
+                (*next_phys(block)).header.prev_phys = block as *mut Header;
+            }
+            self.insert_free(block);
+        }
+    }
+
+    fn total_bytes(&self) -> usize {
+        self.total
+    }
+
+    // headers and region ends included
+    fn allocated_bytes(&self) -> usize {
+        self.allocated
+    }
+
+    fn requested_bytes(&self) -> usize {
+        self.user
+    }
+
+    fn largest_free_block(&self) -> usize {
+        if self.fl_bitmap == 0 {
+            return 0;
+        }
+        let fl = log2(self.fl_bitmap);
+        let mut block = self.lists[fl][log2(self.sl_bitmap[fl])];
+        let mut largest = 0;
+        while !block.is_null() {
+            unsafe {
+                largest = largest.max(block_size(block));
+                block = (*block).next;
+            }
+        }
+        largest
+    }
+
+    fn count_free_blocks(&self, counts: &mut [usize]) {
+        for mut block in self.lists() {
+            while !block.is_null() {
+                unsafe {
+                    counts[log2(block_size(block)).min(counts.len() - 1)] += 1;
+                    block = (*block).next;
+                }
+            }
+        }
+    }
+}
+
+// add_to_heap cuts a region into chunks of at most a maximal block and its end marker
+fn chunks(start: usize, end: usize) -> impl Iterator<Item = (usize, usize)> + Clone {
+    let mut start = start.next_multiple_of(ALIGN);
+    let end = end & !(ALIGN - 1);
+    core::iter::from_fn(move || {
+        if end <= start || end - start < MIN_BLOCK + HEADER {
+            return None;
+        }
+        let chunk_end = if end - start > MAX_BLOCK + HEADER { start + MAX_BLOCK + HEADER } else { end };
+        let chunk = (start, chunk_end);
+        start = chunk_end;
+        Some(chunk)
+    })
+}
+
+fn log2(value: usize) -> usize {
+    (usize::BITS - 1 - value.leading_zeros()) as usize
+}
+
+fn mapping(size: usize) -> (usize, usize) {
+    if size < 1 << FL_SHIFT {
+        (0, size / ALIGN)
+    } else {
+        let fl = log2(size);
+        (fl - FL_SHIFT + 1, (size >> (fl - SL_LOG2)) - SL_COUNT)
+    }
+}
+
+unsafe fn block_size(block: *mut FreeBlock) -> usize {
+    unsafe { (*block).header.size & !FREE }
+}
+
+unsafe fn is_free(block: *mut FreeBlock) -> bool {
+    unsafe { (*block).header.size & FREE != 0 }
+}
+
+unsafe fn next_phys(block: *mut FreeBlock) -> *mut FreeBlock {
+    unsafe { (block as usize + block_size(block)) as *mut FreeBlock }
+}
+
+// cuts block after front bytes and returns the back part, both are left marked used
+unsafe fn split(block: *mut FreeBlock, front: usize) -> *mut FreeBlock {
+    unsafe {
+        let back = (block as usize + front) as *mut FreeBlock;
+        (*back).header = Header { prev_phys: block as *mut Header, size: block_size(block) - front };
+        (*block).header.size = front;
+        (*next_phys(back)).header.prev_phys = back as *mut Header;
+        back
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use super::*;
+
+    const REGION: usize = 64 << 10;
+
+    fn heap(memory: &mut Vec<u128>) -> (TlsfHeap, usize) {
+        let start = memory.as_mut_ptr() as usize;
+        let mut heap = TlsfHeap::empty();
+        unsafe { heap.add_to_heap(start, start + REGION); }
+        (heap, start)
+    }
+
+    #[test]
+    fn freed_blocks_merge_back_into_one() {
+        let mut memory = vec![0u128; REGION / 16];
+        let (mut heap, _) = heap(&mut memory);
+        let whole = heap.largest_free_block();
+        assert_eq!(whole, REGION - HEADER);
+        let layouts = [Layout::from_size_align(24, 8).unwrap(), Layout::from_size_align(1000, 16).unwrap(),
+                       Layout::from_size_align(4096, 8).unwrap()];
+        let blocks: Vec<_> = layouts.iter().map(|layout| heap.alloc(*layout).unwrap()).collect();
+        assert_eq!(heap.requested_bytes(), 24 + 1000 + 4096);
+        assert!(heap.largest_free_block() < whole);
+        // freed in the middle first, so both neighbour merges run
+        for index in [1, 0, 2] {
+            heap.dealloc(blocks[index], layouts[index]);
+        }
+        assert_eq!(heap.largest_free_block(), whole);
+        assert_eq!(heap.requested_bytes(), 0);
+    }
+
+    #[test]
+    fn large_alignment_is_honoured() {
+        let mut memory = vec![0u128; REGION / 16];
+        let (mut heap, _) = heap(&mut memory);
+        let layout = Layout::from_size_align(100, 4096).unwrap();
+        let block = heap.alloc(layout).unwrap();
+        assert_eq!(block.as_ptr() as usize % 4096, 0);
+        heap.dealloc(block, layout);
+        assert_eq!(heap.largest_free_block(), REGION - HEADER);
+    }
+
+    #[test]
+    fn region_leaves_only_while_free() {
+        let mut memory = vec![0u128; REGION / 16];
+        let (mut heap, start) = heap(&mut memory);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let block = heap.alloc(layout).unwrap();
+        assert!(!unsafe { heap.remove_region(start, start + REGION) });
+        heap.dealloc(block, layout);
+        assert!(unsafe { heap.remove_region(start, start + REGION) });
+        assert_eq!(heap.total_bytes(), 0);
+        assert!(heap.alloc(layout).is_err());
+    }
+}
@@ -0,0 +1,21 @@
+use mork_common::mork_kernel_log;
+use mork_common::types::ResultWithErr;
+use crate::addr::PhysAddr;
+use crate::attributes::MapAttributes;
+use crate::error::MmError;
+use crate::kernel_layout::TRAMPOLINE_START;
+use crate::kernel_template;
+use crate::page_table::MutPageTableWrapper;
+use crate::shootdown::online_harts;
+
+// same address in the kernel and every user root, so the trap path survives the satp switch
+pub const TRAMPOLINE_VADDR: usize = TRAMPOLINE_START;
+
+// the region table is created at init and shared by all roots, so no root has to be updated.
+// the page has no user bit, user code can neither read nor run it
+pub fn map_trampoline(paddr: PhysAddr) -> ResultWithErr<MmError> {
+    let attrs = MapAttributes::READ | MapAttributes::EXECUTE | MapAttributes::ACCESSED;
+    MutPageTableWrapper::new(kernel_template::kernel_root()).with_harts(online_harts())
+        .map_kernel_phys(TRAMPOLINE_VADDR, paddr, attrs)
+        .inspect_err(|err| mork_kernel_log!(warn, "fail to map trampoline {:#x}, {}", paddr.as_usize(), err))
+}
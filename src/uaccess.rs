@@ -0,0 +1,205 @@
+#[cfg(target_arch = "riscv64")]
+use core::arch::asm;
+use alloc::string::String;
+use alloc::vec::Vec;
+use mork_common::mork_kernel_log;
+use mork_common::types::ResultWithErr;
+use crate::addr::{is_user_range, phys_to_virt};
+use crate::address_space::AddressSpace;
+use crate::error::MmError;
+use crate::fault::{self, AccessType};
+use crate::frame::FRAME_SIZE;
+use crate::memory::PhysSegment;
+
+const SSTATUS_SUM: usize = 1 << 18;
+
+// supervisor access to user pages is off by default, so a stray user pointer
+// dereference in the kernel faults instead of silently reading user memory
+pub struct UserAccessGuard {
+    was_enabled: bool,
+}
+
+impl UserAccessGuard {
+    pub fn new() -> Self {
+        #[cfg(target_arch = "riscv64")]
+        let old: usize = unsafe {
+            let old;
+            asm!("csrrs {}, sstatus, {}", out(reg) old, in(reg) SSTATUS_SUM);
+            old
+        };
+        #[cfg(not(target_arch = "riscv64"))]
+        let old = 0;
+        // nested guards leave SUM to the outermost one
+        Self { was_enabled: old & SSTATUS_SUM != 0 }
+    }
+}
+
+impl Default for UserAccessGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for UserAccessGuard {
+    fn drop(&mut self) {
+        #[cfg(target_arch = "riscv64")]
+        if !self.was_enabled {
+            unsafe {
+                asm!("csrc sstatus, {}", in(reg) SSTATUS_SUM);
+            }
+        }
+        #[cfg(not(target_arch = "riscv64"))]
+        let _ = self.was_enabled;
+    }
+}
+
+// the default state on every hart, user pages are reachable only through a guard
+pub(crate) fn disable_user_access() {
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        asm!("csrc sstatus, {}", in(reg) SSTATUS_SUM);
+    }
+}
+
+pub fn copy_from_user(address_space: &mut AddressSpace, dst: &mut [u8], src: usize) -> ResultWithErr<MmError> {
+    let segments = prepare(address_space, src, dst.len(), AccessType::Read)?;
+    if address_space.is_active() {
+        let _guard = UserAccessGuard::new();
+        unsafe {
+            core::ptr::copy_nonoverlapping(src as *const u8, dst.as_mut_ptr(), dst.len());
+        }
+        return Ok(());
+    }
+    // not our page tables, go through the direct map instead
+    let mut copied = 0;
+    for segment in segments {
+        let from = phys_to_virt(segment.paddr).as_usize() as *const u8;
+        unsafe {
+            core::ptr::copy_nonoverlapping(from, dst[copied..].as_mut_ptr(), segment.len);
+        }
+        copied += segment.len;
+    }
+    Ok(())
+}
+
+pub fn copy_to_user(address_space: &mut AddressSpace, dst: usize, src: &[u8]) -> ResultWithErr<MmError> {
+    let segments = prepare(address_space, dst, src.len(), AccessType::Write)?;
+    if address_space.is_active() {
+        let _guard = UserAccessGuard::new();
+        unsafe {
+            core::ptr::copy_nonoverlapping(src.as_ptr(), dst as *mut u8, src.len());
+        }
+        return Ok(());
+    }
+    let mut copied = 0;
+    for segment in segments {
+        let to = phys_to_virt(segment.paddr).as_usize() as *mut u8;
+        unsafe {
+            core::ptr::copy_nonoverlapping(src[copied..].as_ptr(), to, segment.len);
+        }
+        copied += segment.len;
+    }
+    Ok(())
+}
+
+// reads a nul terminated string of at most max_len bytes, the nul excluded.
+// pages past the terminator are never touched, so it may end right before a hole
+pub fn read_user_cstr(address_space: &mut AddressSpace, vaddr: usize, max_len: usize) -> Result<String, MmError> {
+    read_cstr(vaddr, max_len, |dst, src| copy_from_user(address_space, dst, src))
+}
+
+fn read_cstr(vaddr: usize, max_len: usize, mut copy: impl FnMut(&mut [u8], usize) -> ResultWithErr<MmError>)
+    -> Result<String, MmError> {
+    let mut bytes = Vec::new();
+    let mut current = vaddr;
+    let mut chunk = [0u8; 256];
+    loop {
+        // never cross a page boundary within one copy
+        let len = chunk.len().min(FRAME_SIZE - (current & (FRAME_SIZE - 1)));
+        let len = len.min(max_len.saturating_add(1) - bytes.len());
+        copy(&mut chunk[..len], current)?;
+        if let Some(end) = chunk[..len].iter().position(|byte| *byte == 0) {
+            bytes.extend_from_slice(&chunk[..end]);
+            break;
+        }
+        bytes.extend_from_slice(&chunk[..len]);
+        if bytes.len() > max_len {
+            mork_kernel_log!(warn, "user string at {:#x} is longer than {:#x}", vaddr, max_len);
+            return Err(MmError::InvalidParam);
+        }
+        current += len;
+    }
+    String::from_utf8(bytes).map_err(|_| {
+        mork_kernel_log!(warn, "user string at {:#x} is not utf-8", vaddr);
+        MmError::InvalidParam
+    })
+}
+
+// checks presence, permissions and the user bit of every page, faulting in pages
+// that are not there yet or still cow, the way a user access would
+fn prepare(address_space: &mut AddressSpace, vaddr: usize, len: usize, access: AccessType)
+    -> Result<Vec<PhysSegment>, MmError> {
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+    if !is_user_range(vaddr, len) {
+        return Err(MmError::InvalidAddress);
+    }
+    match address_space.lookup_segments(vaddr, len, access) {
+        Err(MmError::NotMapped | MmError::PermissionDenied) => {}
+        result => return result,
+    }
+    let start = vaddr & !(FRAME_SIZE - 1);
+    for page in (start..vaddr + len).step_by(FRAME_SIZE) {
+        fault::handle_page_fault(address_space, page, access).into_result()?;
+    }
+    address_space.lookup_segments(vaddr, len, access)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: usize = 0x10000;
+
+    // user memory from BASE on, reads past its end fault
+    fn read(memory: &[u8], max_len: usize) -> Result<String, MmError> {
+        read_cstr(BASE, max_len, |dst, src| {
+            let offset = src - BASE;
+            let bytes = memory.get(offset..offset + dst.len()).ok_or(MmError::NotMapped)?;
+            dst.copy_from_slice(bytes);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn string_up_to_max_len_is_read() {
+        assert_eq!(read(b"mork\0", 4).unwrap(), "mork");
+        assert_eq!(read(b"\0", 0).unwrap(), "");
+    }
+
+    #[test]
+    fn string_longer_than_max_len_fails() {
+        assert_eq!(read(b"mork\0", 3), Err(MmError::InvalidParam));
+    }
+
+    #[test]
+    fn page_after_the_nul_is_not_touched() {
+        let mut memory = alloc::vec![b'a'; FRAME_SIZE];
+        memory[FRAME_SIZE - 1] = 0;
+        assert_eq!(read(&memory, usize::MAX).unwrap().len(), FRAME_SIZE - 1);
+    }
+
+    #[test]
+    fn long_string_crosses_chunks_and_pages() {
+        let mut memory = alloc::vec![b'a'; FRAME_SIZE + 300];
+        memory.push(0);
+        assert_eq!(read(&memory, FRAME_SIZE + 300).unwrap().len(), FRAME_SIZE + 300);
+        assert_eq!(read(&memory, FRAME_SIZE + 299), Err(MmError::InvalidParam));
+    }
+
+    #[test]
+    fn missing_terminator_faults() {
+        assert_eq!(read(b"mork", 16), Err(MmError::NotMapped));
+    }
+}
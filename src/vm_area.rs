@@ -0,0 +1,278 @@
+use core::fmt;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use mork_common::mork_kernel_log;
+use mork_common::types::ResultWithErr;
+use crate::attributes::MapAttributes;
+use crate::backend::MappingBackend;
+use crate::error::MmError;
+use crate::frame::FRAME_SIZE;
+
+#[derive(Clone)]
+pub enum VmBacking {
+    // caller provided frames starting at paddr, mapped eagerly
+    Fixed { paddr: usize },
+    // zero-filled frames allocated on first touch
+    Anonymous,
+    // pages read from the backend on first touch, offset is where the area starts
+    File { backend: Arc<dyn MappingBackend>, offset: usize },
+}
+
+impl fmt::Debug for VmBacking {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmBacking::Fixed { paddr } => write!(f, "Fixed({:#x})", paddr),
+            VmBacking::Anonymous => f.write_str("Anonymous"),
+            VmBacking::File { offset, .. } => write!(f, "File({:#x})", offset),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VmKind {
+    Normal,
+    // faults up to gap bytes below start extend the area down to limit,
+    // the page right below limit stays unmapped as a guard
+    StackGrowsDown { limit: usize, gap: usize },
+    // intentionally left unmapped, nothing may be mapped over it
+    Guard,
+    // the program break area, moved by set_brk
+    Heap,
+}
+
+#[derive(Clone, Debug)]
+pub struct VmArea {
+    pub start: usize,
+    pub len: usize,
+    pub attrs: MapAttributes,
+    pub backing: VmBacking,
+    pub kind: VmKind,
+}
+
+impl VmArea {
+    pub fn new(start: usize, len: usize, attrs: MapAttributes, backing: VmBacking) -> Self {
+        Self { start, len, attrs, backing, kind: VmKind::Normal }
+    }
+
+    pub fn with_kind(mut self, kind: VmKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    // lowest address the area may ever occupy, including its guard page
+    pub fn reserved_start(&self) -> usize {
+        match self.kind {
+            VmKind::StackGrowsDown { limit, .. } => limit - FRAME_SIZE,
+            VmKind::Normal | VmKind::Guard | VmKind::Heap => self.start,
+        }
+    }
+
+    pub fn end(&self) -> usize {
+        self.start + self.len
+    }
+
+    pub fn contains(&self, vaddr: usize) -> bool {
+        vaddr >= self.start && vaddr < self.end()
+    }
+
+    pub fn overlaps(&self, start: usize, len: usize) -> bool {
+        start < self.end() && start + len > self.reserved_start()
+    }
+
+    // whether a fault at vaddr, below the area, may grow it
+    pub fn can_grow_to(&self, vaddr: usize) -> bool {
+        match self.kind {
+            VmKind::StackGrowsDown { limit, gap } => {
+                vaddr >= limit && vaddr < self.start && self.start - vaddr <= gap
+            }
+            VmKind::Normal | VmKind::Guard | VmKind::Heap => false,
+        }
+    }
+
+    // the part of this area inside [start, end), with the backing offset adjusted
+    fn slice(&self, start: usize, end: usize) -> Self {
+        let start = start.max(self.start);
+        let end = end.min(self.end());
+        let backing = match &self.backing {
+            VmBacking::Fixed { paddr } => VmBacking::Fixed { paddr: paddr + (start - self.start) },
+            VmBacking::Anonymous => VmBacking::Anonymous,
+            VmBacking::File { backend, offset } => VmBacking::File {
+                backend: backend.clone(),
+                offset: offset + (start - self.start),
+            },
+        };
+        Self { start, len: end - start, attrs: self.attrs, backing, kind: self.kind }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct VmAreaSet {
+    areas: BTreeMap<usize, VmArea>,
+}
+
+impl VmAreaSet {
+    pub fn new() -> Self {
+        Self { areas: BTreeMap::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.areas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.areas.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &VmArea> {
+        self.areas.values()
+    }
+
+    pub fn find_region(&self, vaddr: usize) -> Option<&VmArea> {
+        self.areas
+            .range(..=vaddr)
+            .next_back()
+            .map(|(_, area)| area)
+            .filter(|area| area.contains(vaddr))
+    }
+
+    pub fn overlaps(&self, start: usize, len: usize) -> bool {
+        self.find_overlap(start, len).is_some()
+    }
+
+    pub fn find_overlap(&self, start: usize, len: usize) -> Option<&VmArea> {
+        if len == 0 {
+            return None;
+        }
+        let end = start + len;
+        // reserved spans are disjoint, so only the neighbours around end can overlap
+        self.areas
+            .range(..end)
+            .next_back()
+            .map(|(_, area)| area)
+            .filter(|area| area.overlaps(start, len))
+            .or_else(|| {
+                self.areas
+                    .range(end..)
+                    .next()
+                    .map(|(_, area)| area)
+                    .filter(|area| area.overlaps(start, len))
+            })
+    }
+
+    // fails with GuardRegion if [start, start + len) touches a guard, AlreadyMapped for other areas
+    pub fn check_free(&self, start: usize, len: usize) -> ResultWithErr<MmError> {
+        match self.find_overlap(start, len) {
+            None => Ok(()),
+            Some(area) if area.kind == VmKind::Guard => {
+                mork_kernel_log!(warn, "region hits a guard area, {:#x}, {:#x}", start, len);
+                Err(MmError::GuardRegion)
+            }
+            Some(_) => {
+                mork_kernel_log!(warn, "region overlaps an existing area, {:#x}, {:#x}", start, len);
+                Err(MmError::AlreadyMapped)
+            }
+        }
+    }
+
+    // the growable area right above vaddr, if vaddr is within its reach
+    pub fn find_growable(&self, vaddr: usize) -> Option<&VmArea> {
+        self.areas
+            .range(vaddr..)
+            .next()
+            .map(|(_, area)| area)
+            .filter(|area| area.can_grow_to(vaddr))
+    }
+
+    pub fn grow_down(&mut self, area_start: usize, new_start: usize) -> ResultWithErr<MmError> {
+        let Some(area) = self.areas.get(&area_start).cloned() else {
+            return Err(MmError::NotMapped);
+        };
+        if !area.can_grow_to(new_start) {
+            mork_kernel_log!(warn, "area at {:#x} can not grow to {:#x}", area_start, new_start);
+            return Err(MmError::InvalidParam);
+        }
+        self.areas.remove(&area_start);
+        self.areas.insert(new_start, VmArea { start: new_start, len: area.end() - new_start, ..area });
+        Ok(())
+    }
+
+    pub fn insert(&mut self, area: VmArea) -> ResultWithErr<MmError> {
+        if area.len == 0 {
+            return Err(MmError::InvalidParam);
+        }
+        self.check_free(area.reserved_start(), area.end() - area.reserved_start())?;
+        self.areas.insert(area.start, area);
+        Ok(())
+    }
+
+    pub fn resize(&mut self, area_start: usize, new_len: usize) -> ResultWithErr<MmError> {
+        let Some(area) = self.areas.get(&area_start).cloned() else {
+            return Err(MmError::NotMapped);
+        };
+        if new_len == 0 {
+            return Err(MmError::InvalidParam);
+        }
+        if new_len > area.len {
+            self.check_free(area.end(), new_len - area.len)?;
+        }
+        self.areas.insert(area_start, VmArea { len: new_len, ..area });
+        Ok(())
+    }
+
+    // first fit search for an unused, aligned [start, start + len) inside [lower, upper),
+    // trying the hint first
+    pub fn find_free(&self, len: usize, align: usize, hint: usize, lower: usize, upper: usize)
+        -> Option<usize> {
+        let fits = |start: usize| {
+            start >= lower && start.checked_add(len).is_some_and(|end| end <= upper)
+                && !self.overlaps(start, len)
+        };
+        if let Some(start) = align_up(hint, align).filter(|start| hint != 0 && fits(*start)) {
+            return Some(start);
+        }
+        let mut candidate = align_up(lower, align)?;
+        for area in self.areas.values() {
+            if area.end() <= candidate {
+                continue;
+            }
+            if candidate.checked_add(len)? <= area.reserved_start() {
+                break;
+            }
+            candidate = align_up(area.end(), align)?;
+        }
+        (candidate.checked_add(len)? <= upper).then_some(candidate)
+    }
+
+    // removes [start, start + len) from the set, splitting partially covered areas,
+    // and returns the removed pieces
+    pub fn remove_range(&mut self, start: usize, len: usize) -> Vec<VmArea> {
+        let end = start + len;
+        let overlapped: Vec<usize> = self.areas
+            .range(..end)
+            .filter(|(_, area)| area.end() > start)
+            .map(|(area_start, _)| *area_start)
+            .collect();
+        let mut removed = Vec::new();
+        for area_start in overlapped {
+            let area = self.areas.remove(&area_start).unwrap();
+            // only the lowest remaining piece keeps the growth behaviour
+            if area.start < start {
+                self.areas.insert(area.start, area.slice(area.start, start));
+            }
+            if area.end() > end {
+                let mut upper = area.slice(end, area.end());
+                if area.start < start {
+                    upper.kind = VmKind::Normal;
+                }
+                self.areas.insert(end, upper);
+            }
+            removed.push(area.slice(start, end));
+        }
+        removed
+    }
+}
+
+fn align_up(vaddr: usize, align: usize) -> Option<usize> {
+    vaddr.checked_add(align - 1).map(|vaddr| vaddr & !(align - 1))
+}
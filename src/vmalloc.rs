@@ -0,0 +1,117 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use mork_common::mork_kernel_log;
+use crate::attributes::MapAttributes;
+use crate::frame::{self, FRAME_SIZE};
+use crate::kernel_layout::{RegionAllocator, REGION_SIZE, VMALLOC_START};
+use crate::kernel_template;
+use crate::page_table::MutPageTableWrapper;
+use crate::shootdown::online_harts;
+use crate::sync::IrqMutex;
+
+struct Vmalloc {
+    space: RegionAllocator,
+    // start -> frames backing the area
+    frames: BTreeMap<usize, Vec<usize>>,
+}
+
+// the global allocator takes it for large blocks, so interrupts stay off while it is held
+static VMALLOC: IrqMutex<Vmalloc> = IrqMutex::new(Vmalloc {
+    space: RegionAllocator::new(VMALLOC_START, VMALLOC_START + REGION_SIZE),
+    frames: BTreeMap::new(),
+});
+
+// virtually contiguous, zeroed, backed by frames from anywhere in ram
+pub fn vmalloc(len: usize) -> Option<usize> {
+    alloc_area(&mut VMALLOC.lock(), len)
+}
+
+// for the global allocator, gives up instead of spinning while the area is busy,
+// e.g. because vmalloc itself allocates on this hart
+pub(crate) fn try_vmalloc(len: usize) -> Option<usize> {
+    let mut vmalloc = VMALLOC.try_lock()?;
+    alloc_area(&mut vmalloc, len)
+}
+
+fn alloc_area(vmalloc: &mut Vmalloc, len: usize) -> Option<usize> {
+    if len == 0 {
+        return None;
+    }
+    let len = len.checked_next_multiple_of(FRAME_SIZE)?;
+    let Some(start) = vmalloc.space.alloc(len) else {
+        mork_kernel_log!(warn, "vmalloc area exhausted, {:#x} bytes requested", len);
+        return None;
+    };
+    let Some(frames) = map_frames(start, len) else {
+        vmalloc.space.free(start);
+        return None;
+    };
+    vmalloc.frames.insert(start, frames);
+    Some(start)
+}
+
+/// # Safety
+///
+/// `vaddr` must come from `vmalloc`, and nothing may use the area afterwards.
+pub unsafe fn vfree(vaddr: usize) {
+    let mut vmalloc = VMALLOC.lock();
+    let Some(frames) = vmalloc.frames.remove(&vaddr) else {
+        mork_kernel_log!(warn, "vfree of unknown area {:#x}", vaddr);
+        return;
+    };
+    unmap_frames(vaddr, &frames);
+    // the range is reused only once its pages are gone from every tlb
+    vmalloc.space.free(vaddr);
+}
+
+// mapped bytes of the area starting at vaddr, None while the area is busy
+pub(crate) fn try_area_len(vaddr: usize) -> Option<usize> {
+    VMALLOC.try_lock()?.frames.get(&vaddr).map(|frames| frames.len() * FRAME_SIZE)
+}
+
+pub fn is_vmalloc_addr(vaddr: usize) -> bool {
+    (VMALLOC_START..VMALLOC_START + REGION_SIZE).contains(&vaddr)
+}
+
+// backs [start, start + len) of kernel space with fresh zeroed frames, nothing stays mapped on failure
+pub(crate) fn map_frames(start: usize, len: usize) -> Option<Vec<usize>> {
+    let mut wrapper = MutPageTableWrapper::new(kernel_template::kernel_root()).with_harts(online_harts());
+    let mut frames = Vec::with_capacity(len / FRAME_SIZE);
+    let attrs = MapAttributes::READ | MapAttributes::WRITE | MapAttributes::ACCESSED | MapAttributes::DIRTY;
+    for vaddr in (start..start + len).step_by(FRAME_SIZE) {
+        let mapped = frame::alloc_frame().map(|paddr| {
+            let result = wrapper.map_kernel_page(vaddr, paddr, attrs);
+            if result.is_err() {
+                unsafe { frame::dealloc_frame(paddr); }
+            }
+            result.map(|_| paddr)
+        });
+        match mapped {
+            Some(Ok(paddr)) => frames.push(paddr),
+            _ => {
+                mork_kernel_log!(warn, "fail to back kernel area at {:#x}", vaddr);
+                release(&mut wrapper, start, &frames);
+                return None;
+            }
+        }
+    }
+    Some(frames)
+}
+
+pub(crate) fn unmap_frames(start: usize, frames: &[usize]) {
+    let mut wrapper = MutPageTableWrapper::new(kernel_template::kernel_root()).with_harts(online_harts());
+    release(&mut wrapper, start, frames);
+}
+
+// the frames go back only once the batched flush dropped them from every tlb
+fn release(wrapper: &mut MutPageTableWrapper, start: usize, frames: &[usize]) {
+    wrapper.begin_batch();
+    let unmapped: Vec<usize> = frames.iter().enumerate()
+        .filter(|(index, _)| wrapper.unmap_kernel_page(start + index * FRAME_SIZE).is_ok())
+        .map(|(_, paddr)| *paddr)
+        .collect();
+    wrapper.finish_batch();
+    for paddr in unmapped {
+        unsafe { frame::dealloc_frame(paddr); }
+    }
+}
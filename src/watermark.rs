@@ -0,0 +1,91 @@
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use crate::frame;
+use crate::sync::IrqMutex;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum MemoryPressure {
+    Normal,
+    Low,
+    Critical,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PressureEvent {
+    pub pressure: MemoryPressure,
+    pub free_frames: usize,
+}
+
+pub type PressureCallback = fn(PressureEvent);
+
+// events nobody polled are dropped oldest first
+const MAX_EVENTS: usize = 16;
+
+// in free frames, 0 disables the watermark
+static LOW_WATERMARK: AtomicUsize = AtomicUsize::new(0);
+static CRITICAL_WATERMARK: AtomicUsize = AtomicUsize::new(0);
+static PRESSURE: AtomicU8 = AtomicU8::new(MemoryPressure::Normal as u8);
+static CALLBACKS: IrqMutex<Vec<PressureCallback>> = IrqMutex::new(Vec::new());
+static EVENTS: IrqMutex<VecDeque<PressureEvent>> = IrqMutex::new(VecDeque::new());
+
+pub fn set_watermarks(low: usize, critical: usize) {
+    LOW_WATERMARK.store(low.max(critical), Ordering::Release);
+    CRITICAL_WATERMARK.store(critical, Ordering::Release);
+    check();
+}
+
+pub fn watermarks() -> (usize, usize) {
+    (LOW_WATERMARK.load(Ordering::Acquire), CRITICAL_WATERMARK.load(Ordering::Acquire))
+}
+
+pub fn register_callback(callback: PressureCallback) {
+    CALLBACKS.lock().push(callback);
+}
+
+pub fn poll_event() -> Option<PressureEvent> {
+    EVENTS.lock().pop_front()
+}
+
+pub fn pressure() -> MemoryPressure {
+    from_u8(PRESSURE.load(Ordering::Acquire))
+}
+
+// re-evaluates the pressure level, notifying on every crossing
+pub fn check() {
+    let free_frames = frame::free_frames();
+    let (low, critical) = watermarks();
+    let pressure = if free_frames < critical {
+        MemoryPressure::Critical
+    } else if free_frames < low {
+        MemoryPressure::Low
+    } else {
+        MemoryPressure::Normal
+    };
+    let old = PRESSURE.swap(pressure as u8, Ordering::AcqRel);
+    if old == pressure as u8 {
+        return;
+    }
+    let event = PressureEvent { pressure, free_frames };
+    {
+        let mut events = EVENTS.lock();
+        if events.len() == MAX_EVENTS {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+    // callbacks may allocate or register further callbacks, do not call them under the lock
+    let callbacks = CALLBACKS.lock().clone();
+    for callback in callbacks {
+        callback(event);
+    }
+}
+
+fn from_u8(value: u8) -> MemoryPressure {
+    match value {
+        0 => MemoryPressure::Normal,
+        1 => MemoryPressure::Low,
+        _ => MemoryPressure::Critical,
+    }
+}
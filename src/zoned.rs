@@ -0,0 +1,116 @@
+use core::alloc::Layout;
+use core::ptr::NonNull;
+use crate::addr::{phys_to_virt, PhysAddr};
+use crate::frame::Zone;
+use crate::heap::HeapBackend;
+
+// devices with 32 bit dma masks only reach memory below this
+pub const DMA32_LIMIT: usize = 1 << 32;
+
+// one backend per zone behind a single lock, so the hart caches work on top of it unchanged.
+// plain allocations prefer the normal zone and fall back to dma32, like linux does
+pub(crate) struct ZonedHeap<B> {
+    dma32: B,
+    normal: B,
+}
+
+impl<B: HeapBackend> ZonedHeap<B> {
+    pub const fn new(dma32: B, normal: B) -> Self {
+        Self { dma32, normal }
+    }
+
+    // only allocates from the zone itself and the zones below it
+    pub fn alloc_in(&mut self, zone: Zone, layout: Layout) -> Result<NonNull<u8>, ()> {
+        match zone {
+            Zone::Dma32 => self.dma32.alloc(layout),
+            Zone::Normal => self.normal.alloc(layout).or_else(|_| self.dma32.alloc(layout)),
+        }
+    }
+
+    // (total, allocated) bytes of the zone alone
+    pub fn zone_usage(&self, zone: Zone) -> (usize, usize) {
+        let backend = match zone {
+            Zone::Dma32 => &self.dma32,
+            Zone::Normal => &self.normal,
+        };
+        (backend.total_bytes(), backend.allocated_bytes())
+    }
+
+    fn zone_of(&mut self, addr: usize) -> &mut B {
+        if addr < dma32_end() { &mut self.dma32 } else { &mut self.normal }
+    }
+}
+
+// the kernel window address of DMA32_LIMIT, the heap only deals in window addresses
+fn dma32_end() -> usize {
+    phys_to_virt(PhysAddr::new(DMA32_LIMIT)).as_usize()
+}
+
+impl<B: HeapBackend> HeapBackend for ZonedHeap<B> {
+    unsafe fn add_to_heap(&mut self, start: usize, end: usize) {
+        let split = end.min(dma32_end()).max(start);
+        unsafe {
+            if split > start {
+                self.dma32.add_to_heap(start, split);
+            }
+            if end > split {
+                self.normal.add_to_heap(split, end);
+            }
+        }
+    }
+
+    unsafe fn remove_region(&mut self, start: usize, end: usize) -> bool {
+        let split = end.min(dma32_end()).max(start);
+        unsafe {
+            if split == start || split == end {
+                return self.zone_of(start).remove_region(start, end);
+            }
+            if !self.dma32.remove_region(start, split) {
+                return false;
+            }
+            if !self.normal.remove_region(split, end) {
+                self.dma32.add_to_heap(start, split);
+                return false;
+            }
+        }
+        true
+    }
+
+    fn set_max_block(&mut self, size: usize) {
+        self.dma32.set_max_block(size);
+        self.normal.set_max_block(size);
+    }
+
+    fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, ()> {
+        self.alloc_in(Zone::Normal, layout)
+    }
+
+    fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        self.zone_of(ptr.as_ptr() as usize).dealloc(ptr, layout);
+    }
+
+    fn grow_in_place(&mut self, ptr: NonNull<u8>, layout: Layout, new_size: usize) -> bool {
+        self.zone_of(ptr.as_ptr() as usize).grow_in_place(ptr, layout, new_size)
+    }
+
+    fn total_bytes(&self) -> usize {
+        self.dma32.total_bytes() + self.normal.total_bytes()
+    }
+
+    fn allocated_bytes(&self) -> usize {
+        self.dma32.allocated_bytes() + self.normal.allocated_bytes()
+    }
+
+    fn requested_bytes(&self) -> usize {
+        self.dma32.requested_bytes() + self.normal.requested_bytes()
+    }
+
+    fn largest_free_block(&self) -> usize {
+        self.dma32.largest_free_block().max(self.normal.largest_free_block())
+    }
+
+    fn count_free_blocks(&self, counts: &mut [usize]) {
+        self.dma32.count_free_blocks(counts);
+        self.normal.count_free_blocks(counts);
+    }
+}
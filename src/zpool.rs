@@ -0,0 +1,159 @@
+use core::slice;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::mutex::Mutex;
+use mork_common::mork_kernel_log;
+use mork_common::types::ResultWithErr;
+use crate::error::MmError;
+use crate::frame::FRAME_SIZE;
+use crate::swap::{self, SwapBackend};
+
+// token format: a control byte below 0x80 is followed by ctrl + 1 literal bytes,
+// otherwise it copies (ctrl & 0x7f) + MIN_MATCH bytes from a little endian u16 offset back
+const MIN_MATCH: usize = 4;
+const MAX_MATCH: usize = MIN_MATCH + 0x7f;
+const MAX_LITERALS: usize = 0x80;
+const HASH_BITS: usize = 10;
+
+// swap backend keeping compressed pages in kernel memory
+pub struct ZPool {
+    slots: Mutex<Vec<Option<Vec<u8>>>>,
+    stored_bytes: AtomicUsize,
+}
+
+impl ZPool {
+    pub fn new(slots: usize) -> Self {
+        Self { slots: Mutex::new(vec![None; slots]), stored_bytes: AtomicUsize::new(0) }
+    }
+
+    // compressed bytes currently held for all slots
+    pub fn stored_bytes(&self) -> usize {
+        self.stored_bytes.load(Ordering::Relaxed)
+    }
+}
+
+impl SwapBackend for ZPool {
+    fn write_page(&self, slot: usize, frame: usize) -> ResultWithErr<MmError> {
+        let page = unsafe { slice::from_raw_parts(frame as *const u8, FRAME_SIZE) };
+        let mut data = compress(page);
+        // incompressible pages are kept as is, recognizable by their full length
+        if data.len() >= FRAME_SIZE {
+            data = page.to_vec();
+        }
+        let len = data.len();
+        let mut slots = self.slots.lock();
+        let entry = slots.get_mut(slot).ok_or(MmError::InvalidParam)?;
+        if let Some(old) = entry.replace(data) {
+            self.stored_bytes.fetch_sub(old.len(), Ordering::Relaxed);
+        }
+        self.stored_bytes.fetch_add(len, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn read_page(&self, slot: usize, frame: usize) -> ResultWithErr<MmError> {
+        let page = unsafe { slice::from_raw_parts_mut(frame as *mut u8, FRAME_SIZE) };
+        let slots = self.slots.lock();
+        let Some(Some(data)) = slots.get(slot) else {
+            mork_kernel_log!(warn, "zpool slot {} is empty", slot);
+            return Err(MmError::NotMapped);
+        };
+        if data.len() == FRAME_SIZE {
+            page.copy_from_slice(data);
+            return Ok(());
+        }
+        decompress(data, page)
+    }
+
+    fn free_slot(&self, slot: usize) {
+        if let Some(Some(data)) = self.slots.lock().get_mut(slot).map(Option::take) {
+            self.stored_bytes.fetch_sub(data.len(), Ordering::Relaxed);
+        }
+    }
+}
+
+// installs a zpool of the given number of pages as the swap backend
+pub fn enable(slots: usize) -> Arc<ZPool> {
+    let pool = Arc::new(ZPool::new(slots));
+    swap::set_backend(pool.clone(), slots);
+    pool
+}
+
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2);
+    let mut table = [usize::MAX; 1 << HASH_BITS];
+    let mut literal_start = 0;
+    let mut pos = 0;
+    while pos + MIN_MATCH <= input.len() {
+        let hash = hash4(&input[pos..pos + MIN_MATCH]);
+        let candidate = table[hash];
+        table[hash] = pos;
+        if candidate != usize::MAX && pos - candidate <= u16::MAX as usize
+            && input[candidate..candidate + MIN_MATCH] == input[pos..pos + MIN_MATCH] {
+            let mut len = MIN_MATCH;
+            while len < MAX_MATCH && pos + len < input.len() && input[candidate + len] == input[pos + len] {
+                len += 1;
+            }
+            emit_literals(&mut out, &input[literal_start..pos]);
+            out.push(0x80 | (len - MIN_MATCH) as u8);
+            out.extend_from_slice(&((pos - candidate) as u16).to_le_bytes());
+            pos += len;
+            literal_start = pos;
+        } else {
+            pos += 1;
+        }
+    }
+    emit_literals(&mut out, &input[literal_start..]);
+    out
+}
+
+pub fn decompress(input: &[u8], output: &mut [u8]) -> ResultWithErr<MmError> {
+    let mut ip = 0;
+    let mut op = 0;
+    while ip < input.len() {
+        let ctrl = input[ip] as usize;
+        ip += 1;
+        if ctrl & 0x80 == 0 {
+            let len = ctrl + 1;
+            if ip + len > input.len() || op + len > output.len() {
+                return Err(MmError::InvalidParam);
+            }
+            output[op..op + len].copy_from_slice(&input[ip..ip + len]);
+            ip += len;
+            op += len;
+        } else {
+            let len = (ctrl & 0x7f) + MIN_MATCH;
+            if ip + 2 > input.len() {
+                return Err(MmError::InvalidParam);
+            }
+            let offset = u16::from_le_bytes([input[ip], input[ip + 1]]) as usize;
+            ip += 2;
+            if offset == 0 || offset > op || op + len > output.len() {
+                return Err(MmError::InvalidParam);
+            }
+            // byte by byte, matches may overlap their own output
+            for i in op..op + len {
+                output[i] = output[i - offset];
+            }
+            op += len;
+        }
+    }
+    if op != output.len() {
+        mork_kernel_log!(warn, "zpool page decompressed to {} bytes", op);
+        return Err(MmError::InvalidParam);
+    }
+    Ok(())
+}
+
+fn emit_literals(out: &mut Vec<u8>, literals: &[u8]) {
+    for chunk in literals.chunks(MAX_LITERALS) {
+        out.push((chunk.len() - 1) as u8);
+        out.extend_from_slice(chunk);
+    }
+}
+
+fn hash4(bytes: &[u8]) -> usize {
+    let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    (value.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}